@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use crate::runtime_error::{Error, Result};
+
+/// アカウント作成時に指定がない場合のログインシェル
+pub const DEFAULT_SHELL: &str = "/bin/bash";
+/// 一般ユーザーのアカウントのUIDの最小値(これ未満はシステムのアカウント)
+pub const MIN_USER_UID: u32 = 1000;
+/// 一般のグループのGIDの最小値(これ未満はシステムのグループ)
+pub const MIN_USER_GID: u32 = 1000;
+
+/// authorized_keys のうちsacanaが管理する範囲の最初の行
+const MANAGED_BEGIN: &str = "# BEGIN sacana managed keys";
+/// authorized_keys のうちsacanaが管理する範囲の最後の行
+const MANAGED_END: &str = "# END sacana managed keys";
+
+/// 公開鍵の更新で authorized_keys をどのように書き換えるか
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyMode {
+    /// ユーザーが追加した行を残し、sacanaが管理する範囲のみを書き換える
+    Merge,
+    /// ファイル全体を書き換える
+    Replace,
+}
+
+impl KeyMode {
+    /// `merge` または `replace` を KeyMode として読み込む
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "merge" => Some(KeyMode::Merge),
+            "replace" => Some(KeyMode::Replace),
+            _ => None,
+        }
+    }
+
+    /// 設定ファイルの key_mode を読み込む(記述が無い場合は replace)
+    pub fn from_settings(v: &serde_json::Value) -> Result<Self> {
+        match v.as_str() {
+            None => Ok(KeyMode::Replace),
+            Some(x) => KeyMode::from_name(x).ok_or_else(|| Error::UnknownKeyMode(x.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyMode::Merge => "merge",
+            KeyMode::Replace => "replace",
+        })
+    }
+}
+
+/// existing の authorized_keys のsacanaが管理する範囲を keys で置き換えた内容
+/// 管理する範囲の外の行は残すが、 keys に含まれる鍵と同じ行は重複しないよう取り除く
+pub fn merge_authorized_keys(existing: &str, keys: &str) -> String {
+    let keys = keys
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    let mut lines = Vec::new();
+    let mut managed = false;
+    for line in existing.lines() {
+        match line.trim() {
+            MANAGED_BEGIN => managed = true,
+            MANAGED_END => managed = false,
+            x if !managed && !keys.contains(&x) => lines.push(line),
+            _ => {}
+        }
+    }
+    lines.push(MANAGED_BEGIN);
+    lines.extend(keys);
+    lines.push(MANAGED_END);
+    lines.join("\n") + "\n"
+}
+
+/// アカウントの情報
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub home: String,
+    pub shell: String,
+    /// 所属している補助グループ
+    pub groups: Vec<String>,
+    /// lock されているかどうか
+    pub locked: bool,
+}
+
+/// アカウントを管理する方法
+/// 各メソッドのエラーはそのままSlackに返答される
+pub trait AccountBackend {
+    /// ログインシェルを shell として、 keys の公開鍵でログインできるアカウントを作成
+    fn create(&self, user_name: &str, shell: &str, keys: &str) -> Result<()>;
+    /// アカウントの authorized_keys を mode に従って keys で書き換える
+    fn update_keys(&self, user_name: &str, keys: &str, mode: KeyMode) -> Result<()>;
+    /// アカウントをグループに追加
+    fn join_group(&self, user_name: &str, group_name: &str) -> Result<()>;
+    /// アカウントをグループから削除
+    fn leave_group(&self, user_name: &str, group_name: &str) -> Result<()>;
+    /// アカウントのホームディレクトリをアーカイブし、アーカイブのパスを返す
+    fn archive_home(&self, user_name: &str) -> Result<String>;
+    /// アカウントをホームディレクトリとともに削除
+    fn delete(&self, user_name: &str) -> Result<()>;
+    /// アカウントを期限切れにして公開鍵を退避し、実行中のセッションを終了させる
+    /// ホームディレクトリなどのデータはそのまま残す
+    fn lock(&self, user_name: &str) -> Result<()>;
+    /// lock したアカウントに再びログインできるようにする
+    fn unlock(&self, user_name: &str) -> Result<()>;
+    /// アカウントの情報を取得(存在しない場合は None)
+    fn query(&self, user_name: &str) -> Result<Option<Account>>;
+    /// 参加できるグループ名の一覧
+    fn group_names(&self) -> Result<Vec<String>>;
+    /// グループのGIDを取得(存在しない場合は None)
+    fn group_id(&self, group_name: &str) -> Result<Option<u32>>;
+    /// 選択できるログインシェルの一覧
+    fn login_shells(&self) -> Result<Vec<String>>;
+    /// dry run で実行しなかった操作を取り出す(実際に操作するバックエンドでは空)
+    fn take_planned_actions(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+/// 管理者の承認が必要な操作
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestedAction {
+    /// ログインシェルを shell としてアカウントを作成し、 groups に参加する
+    Create { shell: String, groups: Vec<String> },
+    /// group に参加する
+    Join { group: String },
+}
+
+/// 承認待ちの申請
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingRequest {
+    /// 申請したユーザーのID
+    pub requester: String,
+    /// 操作の対象のアカウント名
+    pub user_name: String,
+    pub action: RequestedAction,
+    /// 申請者に結果を返答するチャンネル
+    pub channel: String,
+    /// 申請者に結果を返答するスレッド(無い場合はチャンネルに投稿する)
+    pub thread_ts: Option<String>,
+}
+
+impl PendingRequest {
+    /// host での申請の内容を表すテキスト
+    pub fn describe(&self, host: &str) -> String {
+        match &self.action {
+            RequestedAction::Create { groups, .. } if groups.is_empty() => {
+                format!("create an account `{}` on {}", self.user_name, host)
+            }
+            RequestedAction::Create { groups, .. } => format!(
+                "create an account `{}` on {} and join {}",
+                self.user_name,
+                host,
+                groups.join(", ")
+            ),
+            RequestedAction::Join { group } => {
+                format!("join `{}` group on {} as `{}`", group, host, self.user_name)
+            }
+        }
+    }
+}
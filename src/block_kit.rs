@@ -0,0 +1,165 @@
+use serde_json::json;
+
+/// Block Kit の一覧に表示できる選択肢の最大数
+pub const MAX_OPTIONS: usize = 100;
+
+/// mrkdwn のテキストオブジェクト
+pub fn mrkdwn(text: &str) -> serde_json::Value {
+    json!({ "type": "mrkdwn", "text": text })
+}
+
+/// plain_text のテキストオブジェクト
+pub fn plain_text(text: &str) -> serde_json::Value {
+    json!({ "type": "plain_text", "text": text, "emoji": true })
+}
+
+/// mrkdwn のテキストを表示する section ブロック
+pub fn section(text: &str) -> serde_json::Value {
+    json!({ "type": "section", "text": mrkdwn(text) })
+}
+
+/// 補足の mrkdwn のテキストを表示する context ブロック
+pub fn context(text: &str) -> serde_json::Value {
+    json!({ "type": "context", "elements": [mrkdwn(text)] })
+}
+
+/// divider ブロック
+pub fn divider() -> serde_json::Value {
+    json!({ "type": "divider" })
+}
+
+/// elements を並べる actions ブロック
+pub fn actions(block_id: &str, elements: Vec<serde_json::Value>) -> serde_json::Value {
+    json!({ "type": "actions", "block_id": block_id, "elements": elements })
+}
+
+/// ボタン
+pub fn button(action_id: &str, text: &str, value: &str) -> serde_json::Value {
+    json!({
+        "type": "button",
+        "action_id": action_id,
+        "text": plain_text(text),
+        "value": value,
+    })
+}
+
+/// 選択肢オブジェクト
+pub fn option(text: &str) -> serde_json::Value {
+    json!({ "text": plain_text(text), "value": text })
+}
+
+/// values から1つを選ぶセレクトメニュー
+pub fn static_select(action_id: &str, placeholder: &str, values: &[String]) -> serde_json::Value {
+    json!({
+        "type": "static_select",
+        "action_id": action_id,
+        "placeholder": plain_text(placeholder),
+        "options": values
+            .iter()
+            .take(MAX_OPTIONS)
+            .map(|x| option(x))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// 入力された文字列で絞り込んだ選択肢を block_suggestion で取得するセレクトメニュー
+/// 選択肢が MAX_OPTIONS を超えても全て選べるよう、 static_select の代わりに使う
+pub fn external_select(action_id: &str, placeholder: &str) -> serde_json::Value {
+    json!({
+        "type": "external_select",
+        "action_id": action_id,
+        "placeholder": plain_text(placeholder),
+        "min_query_length": 0,
+    })
+}
+
+/// 入力された文字列で絞り込んだ選択肢から複数を選ぶセレクトメニュー
+pub fn multi_external_select(action_id: &str, placeholder: &str) -> serde_json::Value {
+    let mut select = external_select(action_id, placeholder);
+    select["type"] = "multi_external_select".into();
+    select
+}
+
+/// block_suggestion への応答として、 query を含む values を MAX_OPTIONS まで返す
+pub fn suggested_options(values: &[String], query: &str) -> serde_json::Value {
+    json!({
+        "options": values
+            .iter()
+            .filter(|x| x.contains(query))
+            .take(MAX_OPTIONS)
+            .map(|x| option(x))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// 1行のテキスト入力欄
+pub fn plain_text_input(action_id: &str, initial_value: &str) -> serde_json::Value {
+    json!({
+        "type": "plain_text_input",
+        "action_id": action_id,
+        "initial_value": initial_value,
+    })
+}
+
+/// モーダルの入力欄を表示する input ブロック
+pub fn input(
+    block_id: &str,
+    label: &str,
+    element: serde_json::Value,
+    optional: bool,
+) -> serde_json::Value {
+    json!({
+        "type": "input",
+        "block_id": block_id,
+        "label": plain_text(label),
+        "element": element,
+        "optional": optional,
+    })
+}
+
+/// モーダルの view
+pub fn modal(
+    callback_id: &str,
+    title: &str,
+    submit: &str,
+    blocks: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    json!({
+        "type": "modal",
+        "callback_id": callback_id,
+        "title": plain_text(title),
+        "submit": plain_text(submit),
+        "close": plain_text("Cancel"),
+        "blocks": blocks,
+    })
+}
+
+/// ペイロードの state から、セレクトメニューで選ばれた値を取得
+pub fn selected_value<'a>(
+    state: &'a serde_json::Value,
+    block_id: &str,
+    action_id: &str,
+) -> Option<&'a str> {
+    state["values"][block_id][action_id]["selected_option"]["value"].as_str()
+}
+
+/// ペイロードの state から、複数選択のセレクトメニューで選ばれた値を取得
+pub fn selected_values<'a>(
+    state: &'a serde_json::Value,
+    block_id: &str,
+    action_id: &str,
+) -> Vec<&'a str> {
+    state["values"][block_id][action_id]["selected_options"]
+        .as_array()
+        .map(|options| options.iter().filter_map(|x| x["value"].as_str()).collect())
+        .unwrap_or_default()
+}
+
+/// ペイロードの state から、テキスト入力欄に入力された値を取得
+pub fn input_value<'a>(
+    state: &'a serde_json::Value,
+    block_id: &str,
+    action_id: &str,
+) -> Option<&'a str> {
+    state["values"][block_id][action_id]["value"].as_str()
+}
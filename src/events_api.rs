@@ -0,0 +1,257 @@
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+
+use crate::runtime_error::{as_str, Error, Result};
+#[derive(thiserror::Error, Debug)]
+pub enum EventsApiError {
+    #[error("can't listen on {0}: {1}")]
+    Bind(String, String),
+    #[error("request header `{0}` is missing")]
+    MissingHeader(&'static str),
+    #[error("request timestamp {0} is too old")]
+    StaleTimestamp(String),
+    #[error("request signature mismatch")]
+    InvalidSignature,
+    #[error("invalid form data: {0}")]
+    InvalidForm(String),
+    #[error("invalid response header")]
+    InvalidHeader,
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(u64),
+}
+
+/// リクエストのタイムスタンプと現在時刻の差の許容範囲(秒)
+const TIMESTAMP_TOLERANCE: i64 = 60 * 5;
+/// 受け付けるリクエストの本文の最大サイズ(バイト)
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// 再送されたイベントを判別するために記録する event_id の数
+const MAX_SEEN_EVENTS: usize = 1000;
+
+/// 16進数文字列をバイト列に変換
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// X-Slack-Request-Timestamp が現在時刻から TIMESTAMP_TOLERANCE 以内か確認
+fn check_timestamp(timestamp: &str) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    match timestamp.parse::<i64>() {
+        Ok(t) if (now - t).abs() <= TIMESTAMP_TOLERANCE => Ok(()),
+        _ => Err(EventsApiError::StaleTimestamp(timestamp.to_string()).into()),
+    }
+}
+
+/// signing_secret を用いて X-Slack-Signature を検証
+pub fn verify_signature(
+    signing_secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> Result<()> {
+    check_timestamp(timestamp)?;
+    let expected = signature
+        .strip_prefix("v0=")
+        .and_then(decode_hex)
+        .ok_or(EventsApiError::InvalidSignature)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes())
+        .map_err(|_| EventsApiError::InvalidSignature)?;
+    mac.update(b"v0:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b":");
+    mac.update(body);
+    Ok(mac
+        .verify_slice(&expected)
+        .map_err(|_| EventsApiError::InvalidSignature)?)
+}
+
+/// request から name のヘッダーの値を取得
+fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Result<&'a str> {
+    Ok(request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+        .ok_or(EventsApiError::MissingHeader(name))?)
+}
+
+/// request の本文をJSONとして取得
+/// slash command などのフォームデータはフィールド名をキーとするオブジェクトに変換する
+fn parse_body(request: &tiny_http::Request, body: &[u8]) -> Result<serde_json::Value> {
+    if header(request, "Content-Type")
+        .is_ok_and(|x| x.starts_with("application/x-www-form-urlencoded"))
+    {
+        let form: HashMap<String, String> = serde_urlencoded::from_bytes(body)
+            .map_err(|e| EventsApiError::InvalidForm(e.to_string()))?;
+        Ok(json!(form))
+    } else {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// Slackから受け取ったリクエスト
+pub enum Callback {
+    /// Events API のイベント
+    Event(serde_json::Value),
+    /// slash command のペイロード
+    SlashCommand(serde_json::Value),
+    /// ボタンなどの操作のペイロード
+    Interaction(serde_json::Value),
+    /// セレクトメニューの選択肢を求める block_suggestion のペイロード
+    /// 選択肢は respond_json で request への応答として返す
+    Suggestion {
+        payload: serde_json::Value,
+        request: tiny_http::Request,
+    },
+}
+
+/// request に value をJSONの本文として応答する
+pub fn respond_json(request: tiny_http::Request, value: &serde_json::Value) -> Result<()> {
+    let content_type = "Content-Type: application/json"
+        .parse::<tiny_http::Header>()
+        .map_err(|()| EventsApiError::InvalidHeader)?;
+    Ok(request
+        .respond(tiny_http::Response::from_string(value.to_string()).with_header(content_type))?)
+}
+
+/// 最近受け取ったイベントのIDの記録
+/// Slack は応答が遅れたイベントを再送するため、同じIDのイベントを二度処理しないよう使う
+#[derive(Default)]
+pub struct SeenEvents {
+    /// 受け取ったID (古い順)
+    ids: VecDeque<String>,
+}
+
+impl SeenEvents {
+    /// id を記録し、初めて受け取った場合は true を返す
+    /// 記録する数が MAX_SEEN_EVENTS を超えると古いものから忘れる
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.ids.iter().any(|x| x == id) {
+            return false;
+        }
+        if self.ids.len() >= MAX_SEEN_EVENTS {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(id.to_string());
+        true
+    }
+}
+
+/// Events API のリクエストを受け付けるHTTPリスナー
+pub struct Receiver {
+    server: tiny_http::Server,
+    signing_secret: String,
+    /// 最近受け取ったイベントの event_id
+    seen_events: SeenEvents,
+}
+
+impl Receiver {
+    /// listen_address でHTTPリクエストの受け付けを開始する
+    pub fn bind(listen_address: &str, signing_secret: String) -> Result<Self> {
+        let server = tiny_http::Server::http(listen_address)
+            .map_err(|e| EventsApiError::Bind(listen_address.to_string(), e.to_string()))?;
+        Ok(Receiver {
+            server,
+            signing_secret,
+            seen_events: SeenEvents::default(),
+        })
+    }
+
+    /// 署名を検証して request の本文を取得
+    /// 署名の無いリクエストでメモリを使い切らないよう、本文はタイムスタンプを確認してから MAX_BODY_SIZE まで読む
+    fn verified_body(&self, request: &mut tiny_http::Request) -> Result<Vec<u8>> {
+        let timestamp = header(request, "X-Slack-Request-Timestamp")?.to_string();
+        let signature = header(request, "X-Slack-Signature")?.to_string();
+        check_timestamp(&timestamp)?;
+        if request
+            .body_length()
+            .is_some_and(|x| x as u64 > MAX_BODY_SIZE)
+        {
+            return Err(EventsApiError::BodyTooLarge(MAX_BODY_SIZE).into());
+        }
+        let mut body = Vec::new();
+        Read::take(request.as_reader(), MAX_BODY_SIZE + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > MAX_BODY_SIZE {
+            return Err(EventsApiError::BodyTooLarge(MAX_BODY_SIZE).into());
+        }
+        verify_signature(&self.signing_secret, &timestamp, &body, &signature)?;
+        Ok(body)
+    }
+
+    /// timeout まで次のリクエストを待ち、Slackのイベントや slash command であればそれを返す
+    /// url_verification など処理の不要なリクエストにはここで応答する
+    pub fn recv(&mut self, timeout: std::time::Duration) -> Result<Option<Callback>> {
+        let mut request = match self.server.recv_timeout(timeout)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        debug!("{} {}", request.method(), request.url());
+        if request.method() != &tiny_http::Method::Post {
+            request.respond(tiny_http::Response::empty(405))?;
+            return Ok(None);
+        }
+        let body = match self.verified_body(&mut request) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("reject request from {:?}: {}", request.remote_addr(), e);
+                let status = match e {
+                    Error::EventsApi(EventsApiError::BodyTooLarge(_)) => 413,
+                    _ => 401,
+                };
+                request.respond(tiny_http::Response::empty(status))?;
+                return Ok(None);
+            }
+        };
+        let v = match parse_body(&request, &body) {
+            Ok(x) => x,
+            Err(e) => {
+                request.respond(tiny_http::Response::empty(400))?;
+                return Err(e);
+            }
+        };
+        if v["command"].is_string() {
+            // Slack は3秒以内に応答が無いとエラーを表示するため、処理の前に応答する
+            request.respond(tiny_http::Response::empty(200))?;
+            return Ok(Some(Callback::SlashCommand(v)));
+        }
+        if let Some(payload) = v["payload"].as_str() {
+            let payload: serde_json::Value = serde_json::from_str(payload)?;
+            if payload["type"] == "block_suggestion" {
+                return Ok(Some(Callback::Suggestion { payload, request }));
+            }
+            request.respond(tiny_http::Response::empty(200))?;
+            return Ok(Some(Callback::Interaction(payload)));
+        }
+        match v["type"].as_str() {
+            Some("url_verification") => {
+                request.respond(tiny_http::Response::from_string(as_str(&v["challenge"])?))?;
+                Ok(None)
+            }
+            Some("event_callback") => {
+                // Slack は3秒以内に応答が無いと再送するため、処理の前に応答する
+                request.respond(tiny_http::Response::empty(200))?;
+                // 受け取り済みのイベントの再送は無視する(受け取れなかったイベントの再送は処理する)
+                let event_id = as_str(&v["event_id"])?;
+                if !self.seen_events.insert(event_id) {
+                    debug!("ignore retry of event {}", event_id);
+                    return Ok(None);
+                }
+                Ok(Some(Callback::Event(v["event"].clone())))
+            }
+            _ => {
+                debug!("unknown request: {}", v);
+                request.respond(tiny_http::Response::empty(200))?;
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Deserialize;
+
+use crate::policy::glob_match;
+use crate::runtime_error::Result;
+use crate::ssh_key::{PublicKey, SshKeyError};
+
+/// 設定ファイルの key_policy
+/// 記述が無い項目は既定値(ed25519, ECDSA, 3072ビット以上のRSA, セキュリティキーの鍵を許可)を使う
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct KeyPolicy {
+    /// 許可する鍵の種類のパターン(`*` と `?` を使える)
+    allowed_types: Vec<String>,
+    /// RSAの鍵の最小のビット数
+    min_rsa_bits: usize,
+    /// 漏洩などで使用を禁止する鍵の SHA256 のフィンガープリント
+    blocked_fingerprints: Vec<String>,
+    /// blocked_fingerprints に加える、フィンガープリントを列挙したファイル
+    blocked_fingerprints_file: Option<String>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        KeyPolicy {
+            allowed_types: ["ssh-ed25519", "ecdsa-sha2-*", "ssh-rsa", "sk-*"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            min_rsa_bits: 3072,
+            blocked_fingerprints: Vec::new(),
+            blocked_fingerprints_file: None,
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// 設定ファイルの key_policy を読み込む
+    /// blocked_fingerprints_file は各行の `SHA256:` で始まる語をフィンガープリントとして読み込む
+    pub fn from_settings(v: &serde_json::Value) -> Result<Self> {
+        if v.is_null() {
+            return Ok(KeyPolicy::default());
+        }
+        let mut policy = KeyPolicy::deserialize(v)?;
+        if let Some(path) = &policy.blocked_fingerprints_file {
+            let text = std::fs::read_to_string(path)?;
+            let fingerprints = text
+                .lines()
+                .filter(|x| !x.trim_start().starts_with('#'))
+                .flat_map(str::split_whitespace)
+                .filter(|x| x.starts_with("SHA256:"))
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            policy.blocked_fingerprints.extend(fingerprints);
+        }
+        Ok(policy)
+    }
+
+    /// key を authorized_keys に書き込んでよいかどうか
+    /// DSAの鍵は設定によらず許可しない
+    pub fn check(&self, key: &PublicKey) -> std::result::Result<(), SshKeyError> {
+        if key.key_type == "ssh-dss" {
+            return Err(SshKeyError::Dsa);
+        }
+        if !self
+            .allowed_types
+            .iter()
+            .any(|x| glob_match(x, &key.key_type))
+        {
+            return Err(SshKeyError::DisallowedType(key.key_type.clone()));
+        }
+        if let Some(bits) = key.rsa_bits() {
+            if bits < self.min_rsa_bits {
+                return Err(SshKeyError::WeakRsa(bits, self.min_rsa_bits));
+            }
+        }
+        if self.blocked_fingerprints.contains(&key.fingerprint()) {
+            return Err(SshKeyError::Blocked);
+        }
+        Ok(())
+    }
+}
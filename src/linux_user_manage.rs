@@ -1,16 +1,23 @@
-use std::io::{BufRead, Write};
+use log::info;
+use std::cell::RefCell;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 
-use crate::runtime_error::{path_join, Result};
+use crate::account_backend::{
+    merge_authorized_keys, Account, AccountBackend, KeyMode, MIN_USER_UID,
+};
+use crate::runtime_error::{path_join, Error, Result};
+use crate::user_name;
 #[derive(thiserror::Error, Debug)]
 pub enum LinuxError {
-    #[error("can't access {0}: {1}")]
-    HeadPublicKey(String, String),
-    #[error("get public key from {0} failed: {1}")]
-    GetPublicKey(String, String),
     #[error("`{0}` failed. status code: {1}")]
     Command(&'static str, i32),
     #[error("`{0}` is killed by signal")]
     CommandKilled(&'static str),
+    #[error("`{0}` isn't a regular file. it's left unchanged")]
+    NotRegularFile(String),
+    #[error("`{0}` isn't a directory. it's left unchanged")]
+    NotDirectory(String),
 }
 
 fn from_command_status(command: &'static str, es: std::process::ExitStatus) -> Result<()> {
@@ -23,26 +30,10 @@ fn from_command_status(command: &'static str, es: std::process::ExitStatus) -> R
     }
 }
 
-/// uri_format が指すuriに user_id のpublic keyが存在するかどうかを判定
-fn public_keys_exist(uri_format: &str, user_id: &str) -> Result<()> {
-    let uri = uri_format.replace("{}", user_id);
-    let response = reqwest::blocking::Client::new().head(&uri).send()?;
-    if response.status().is_success() {
-        Ok(())
-    } else {
-        Err(LinuxError::HeadPublicKey(uri, response.text()?).into())
-    }
-}
-
-/// uri_format が指すuriから user_id のpublic keyを取得
-fn get_public_keys(uri_format: &str, user_id: &str) -> Result<String> {
-    let uri = uri_format.replace("{}", user_id);
-    let response = reqwest::blocking::get(&uri)?;
-    if response.status().is_success() {
-        Ok(response.text()?)
-    } else {
-        Err(LinuxError::GetPublicKey(uri, response.text()?).into())
-    }
+/// command を args で実行
+fn run(command: &'static str, args: &[&str]) -> Result<()> {
+    let output = std::process::Command::new(command).args(args).output()?;
+    from_command_status(command, output.status)
 }
 
 /// /etc/passwdから user_id の行を抜き出す
@@ -59,95 +50,450 @@ fn etc_passwd(user_id: &str) -> Result<Option<String>> {
     Ok(None)
 }
 
-/// etc_passwdの結果からユーザーのホームディレクトリを取得
-fn home_directory(passwd_line: String) -> String {
-    passwd_line.split(':').nth(5).unwrap().to_string()
-}
-
-/// ユーザーを作成
-fn add_user(user_name: &str, local_host_name: &str) -> Result<()> {
-    if etc_passwd(user_name)?.is_some() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Your account already exists on {}", local_host_name),
-        )
-        .into());
-    }
-    let useradd = std::process::Command::new("useradd")
-        .arg("-m")
-        .arg("-s")
-        .arg("/bin/bash")
-        .arg("-p")
-        .arg("")
-        .arg(user_name)
-        .output()?;
-    from_command_status("useradd", useradd.status)
-}
-
-/// user_name の $HOME に .ssh を作成し、そのパスを取得
-/// ディレクトリが既に存在した場合は特に何もせずにパスを返す
-fn create_ssh_directory(user_name: &str, local_host_name: &str) -> Result<String> {
-    let ep = etc_passwd(user_name)?.ok_or_else(|| {
-        std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Your account doesn't exist on {}", local_host_name),
-        )
-    })?;
-    let hd = home_directory(ep);
-    let ssh_dir = path_join(&[&hd, ".ssh"])?;
-    std::fs::create_dir_all(&ssh_dir)?;
-    Ok(ssh_dir)
-}
-
-/// ssh_dir/authorized_keysに uri_format で指定したURIから取得した公開鍵を上書き
-fn overwrite_ssh_public_key(ssh_dir: &str, user_name: &str, uri_format: &str) -> Result<()> {
-    let path = path_join(&[ssh_dir, "authorized_keys"])?;
-    let keys = get_public_keys(uri_format, user_name)?;
-    std::io::BufWriter::new(std::fs::File::create(path)?).write_all(&keys.into_bytes())?;
-    Ok(())
-}
-
-/// ssh_dir 以下のファイルのパーミッションを700に、所有者を user_name に変更
-fn set_owner_and_permission(ssh_dir: &str, user_name: &str) -> Result<()> {
-    let chmod = std::process::Command::new("chmod")
-        .arg("700")
-        .arg(ssh_dir)
-        .output()?;
-    from_command_status("chmod", chmod.status)?;
-    let chown = std::process::Command::new("chown")
-        .arg("-R")
-        .arg(format!("{0}:{0}", user_name))
-        .arg(ssh_dir)
-        .output()?;
-    from_command_status("chown", chown.status)
-}
-
-/// ユーザーのauthorized_keysを更新
-pub fn update_account(user_name: &str, local_host_name: &str, uri_format: &str) -> Result<()> {
-    let ssh_dir = create_ssh_directory(user_name, local_host_name)?;
-    overwrite_ssh_public_key(&ssh_dir, user_name, uri_format)?;
-    set_owner_and_permission(&ssh_dir, user_name)
-}
-
-/// アカウントを作成
-pub fn create_account(user_name: &str, local_host_name: &str, uri_format: &str) -> Result<()> {
-    public_keys_exist(uri_format, user_name)?;
-    add_user(user_name, local_host_name)?;
-    update_account(user_name, local_host_name, uri_format)
-}
-
-/// ユーザーをグループに追加
-pub fn join_group(user_name: &str, group_name: &str, local_host_name: &str) -> Result<()> {
-    etc_passwd(user_name)?.ok_or_else(|| {
-        std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Your account doesn't exist on {}", local_host_name),
-        )
-    })?;
-    let usermod = std::process::Command::new("usermod")
-        .arg("-aG")
-        .arg(group_name)
-        .arg(user_name)
-        .output()?;
-    from_command_status("usermod", usermod.status)
+/// /etc/passwdから user_id の所属するグループのGIDを取得
+fn primary_gid(user_id: &str) -> Result<Option<u32>> {
+    // name:password:UID:GID:GECOS:directory:shell
+    Ok(etc_passwd(user_id)?.and_then(|x| x.split(':').nth(3).and_then(|x| x.parse().ok())))
+}
+
+/// /etc/shadowから user_id の行を抜き出す
+fn etc_shadow(user_id: &str) -> Result<Option<String>> {
+    let file = std::fs::File::open("/etc/shadow")?;
+    for line in std::io::BufReader::new(&file).lines() {
+        let l = line?;
+        if l.split(':').next() == Some(user_id) {
+            return Ok(Some(l));
+        }
+    }
+    Ok(None)
+}
+
+/// /etc/shadow の行が、パスワードがロックされ(`!` で始まり)有効期限が today 以前のアカウントかどうか
+/// today と有効期限は1970年1月1日からの日数
+pub fn shadow_locked(line: &str, today: i64) -> bool {
+    // name:password:lastchg:min:max:warn:inactive:expire:reserved
+    let fields = line.split(':').collect::<Vec<_>>();
+    let password_locked = fields.get(1).is_some_and(|x| x.starts_with('!'));
+    let expired = fields
+        .get(7)
+        .and_then(|x| x.parse::<i64>().ok())
+        .is_some_and(|x| x <= today);
+    password_locked && expired
+}
+
+/// /etc/groupから user_id が補助グループとして所属しているグループ名の一覧を取得
+fn supplementary_groups(user_id: &str) -> Result<Vec<String>> {
+    let file = std::fs::File::open("/etc/group")?;
+    let mut names = Vec::new();
+    for line in std::io::BufReader::new(&file).lines() {
+        let l = line?;
+        let fields = l.split(':').collect::<Vec<_>>();
+        if let (Some(g), Some(members)) = (fields.first(), fields.get(3)) {
+            if members.split(',').any(|x| x == user_id) {
+                names.push(g.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// /etc/groupに記載されているグループ名の一覧を取得
+fn group_names() -> Result<Vec<String>> {
+    let file = std::fs::File::open("/etc/group")?;
+    let mut names = Vec::new();
+    for line in std::io::BufReader::new(&file).lines() {
+        if let Some(g) = line?.split(':').next() {
+            names.push(g.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// /etc/groupに記載されている group_name のGIDを取得
+fn group_id(group_name: &str) -> Result<Option<u32>> {
+    let file = std::fs::File::open("/etc/group")?;
+    for line in std::io::BufReader::new(&file).lines() {
+        // name:password:GID:members
+        let line = line?;
+        let fields = line.split(':').collect::<Vec<_>>();
+        if fields.first() == Some(&group_name) {
+            return Ok(fields.get(2).and_then(|x| x.parse().ok()));
+        }
+    }
+    Ok(None)
+}
+
+/// /etc/shellsに記載されているログインシェルの一覧を取得
+fn login_shells() -> Result<Vec<String>> {
+    let file = std::fs::File::open("/etc/shells")?;
+    let mut shells = Vec::new();
+    for line in std::io::BufReader::new(&file).lines() {
+        let l = line?;
+        let shell = l.trim();
+        if !shell.is_empty() && !shell.starts_with('#') {
+            shells.push(shell.to_string());
+        }
+    }
+    Ok(shells)
+}
+
+/// dry run で実行しなかった操作
+#[derive(Default)]
+struct DryRun {
+    /// 実行しなかったコマンドやファイルの書き込み
+    actions: Vec<String>,
+    /// 作成したことにしたアカウント
+    created_users: Vec<String>,
+}
+
+/// lock したアカウントの authorized_keys の退避先のファイル名
+const LOCKED_KEYS_FILE: &str = "authorized_keys.locked";
+
+/// home の .ssh 以下の file のパス
+fn ssh_file(home: &str, file: &str) -> Result<String> {
+    path_join(&[home, ".ssh", file])
+}
+
+/// ユーザーが書き換えられる path の通常のファイルを、シンボリックリンクをたどらずに読む
+/// 存在しない場合は空
+pub(crate) fn read_user_file(path: &str) -> Result<String> {
+    // FIFO を開いたまま止まらないよう O_NONBLOCK を指定する
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(String::new()),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(LinuxError::NotRegularFile(path.to_string()).into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !file.metadata()?.is_file() {
+        return Err(LinuxError::NotRegularFile(path.to_string()).into());
+    }
+    let mut content = String::new();
+    (&file).read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// ユーザーが書き換えられるディレクトリ dir の path に、シンボリックリンクをたどらずに content を書き込む
+/// dir に一時ファイルを uid と gid の所有で作成してから置き換える
+pub(crate) fn write_user_file(
+    dir: &str,
+    path: &str,
+    content: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<()> {
+    let tmp = format!("{}.sacana-tmp", path);
+    // 前回の書き込みの途中で残ったものは、シンボリックリンクであってもリンク自体を消す
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let write = || -> Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&tmp)?;
+        std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+        std::io::BufWriter::new(&file).write_all(content.as_bytes())?;
+        file.sync_all()?;
+        // 書き込みの間に dir が置き換えられていないことを確かめる
+        if !std::fs::symlink_metadata(dir)?.is_dir() {
+            return Err(LinuxError::NotDirectory(dir.to_string()).into());
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+/// ホームディレクトリのアーカイブを保存するディレクトリの既定値
+pub const DEFAULT_ARCHIVE_DIRECTORY: &str = "/var/backups/sacana";
+
+/// useradd などのコマンドでローカルのアカウントを管理する
+pub struct LinuxBackend {
+    local_host_name: String,
+    /// ホームディレクトリのアーカイブを保存するディレクトリ
+    archive_directory: String,
+    /// dry run の場合、コマンドやファイルの書き込みを実行せずに記録する
+    dry_run: Option<RefCell<DryRun>>,
+}
+
+impl LinuxBackend {
+    pub fn new(local_host_name: String, archive_directory: String) -> Self {
+        LinuxBackend {
+            local_host_name,
+            archive_directory,
+            dry_run: None,
+        }
+    }
+
+    /// アカウントの情報の参照以外の操作を実行しないバックエンド
+    pub fn dry_run(local_host_name: String, archive_directory: String) -> Self {
+        LinuxBackend {
+            local_host_name,
+            archive_directory,
+            dry_run: Some(RefCell::default()),
+        }
+    }
+
+    /// dry run の場合は action を記録して true を返す
+    fn plan(&self, action: impl FnOnce() -> String) -> bool {
+        if let Some(dry_run) = &self.dry_run {
+            let action = action();
+            info!("dry run: {}", action);
+            dry_run.borrow_mut().actions.push(action);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// command を args で実行
+    fn execute(&self, command: &'static str, args: &[&str]) -> Result<()> {
+        let planned = self.plan(|| {
+            let args = args
+                .iter()
+                .map(|x| if x.is_empty() { "''" } else { x })
+                .collect::<Vec<_>>();
+            format!("{} {}", command, args.join(" "))
+        });
+        if planned {
+            return Ok(());
+        }
+        run(command, args)
+    }
+
+    /// from を to に移動する
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        if !self.plan(|| format!("mv {} {}", from, to)) {
+            std::fs::rename(from, to)?;
+        }
+        Ok(())
+    }
+
+    /// account のホームディレクトリ以下の .ssh/authorized_keys を mode に従って keys で書き換え、パーミッションと所有者を設定
+    /// .ssh 以下はユーザーが書き換えられるため、シンボリックリンクや通常のファイル以外は読み書きしない
+    fn install_keys(&self, account: &Account, keys: &str, mode: KeyMode) -> Result<()> {
+        let user_name = account.name.as_str();
+        let ssh_dir = path_join(&[&account.home, ".ssh"])?;
+        let path = ssh_file(&account.home, "authorized_keys")?;
+        match std::fs::symlink_metadata(&ssh_dir) {
+            Ok(x) if !x.is_dir() => return Err(LinuxError::NotDirectory(ssh_dir).into()),
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let existing = match mode {
+            KeyMode::Merge => read_user_file(&path)?,
+            KeyMode::Replace => String::new(),
+        };
+        let content = merge_authorized_keys(&existing, keys);
+        let planned = self.plan(|| {
+            let key_count = keys.lines().filter(|x| !x.trim().is_empty()).count();
+            // 管理する範囲の最初と最後の行を除いた、ユーザーが追加した行の数
+            let kept = content.lines().count() - key_count - 2;
+            format!(
+                "write {} line(s) to {}{}",
+                key_count,
+                path,
+                if kept == 0 {
+                    String::new()
+                } else {
+                    format!(" keeping {} other line(s)", kept)
+                }
+            )
+        });
+        if !planned {
+            let gid = primary_gid(user_name)?.unwrap_or(account.uid);
+            // ディレクトリが既に存在した場合は特に何もしない
+            if let Err(e) = std::fs::DirBuilder::new().mode(0o700).create(&ssh_dir) {
+                if e.kind() != std::io::ErrorKind::AlreadyExists {
+                    return Err(e.into());
+                }
+            }
+            write_user_file(&ssh_dir, &path, &content, account.uid, gid)?;
+        }
+        // ssh_dir 以下のファイルのパーミッションを700に、所有者を user_name に変更
+        self.execute("chmod", &["700", &ssh_dir])?;
+        self.execute("chown", &["-R", &format!("{0}:{0}", user_name), &ssh_dir])
+    }
+
+    /// user_name のアカウントの情報を取得し、存在しない場合はエラーを返す
+    fn existing_account(&self, user_name: &str) -> Result<Account> {
+        Ok(self.query(user_name)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Your account doesn't exist on {}", self.local_host_name),
+            )
+        })?)
+    }
+}
+
+impl AccountBackend for LinuxBackend {
+    fn create(&self, user_name: &str, shell: &str, keys: &str) -> Result<()> {
+        // 不正な名前は useradd に渡す前に拒否する(`-` で始まる名前はオプションとして解釈されうる)
+        user_name::validate(user_name)?;
+        if self.query(user_name)?.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Your account already exists on {}", self.local_host_name),
+            )
+            .into());
+        }
+        self.execute("useradd", &["-m", "-s", shell, "-p", "", user_name])?;
+        if let Some(dry_run) = &self.dry_run {
+            dry_run
+                .borrow_mut()
+                .created_users
+                .push(user_name.to_string());
+        }
+        self.update_keys(user_name, keys, KeyMode::Replace)
+    }
+
+    fn update_keys(&self, user_name: &str, keys: &str, mode: KeyMode) -> Result<()> {
+        let account = self.existing_account(user_name)?;
+        if account.locked {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Your account is locked on {}", self.local_host_name),
+            )
+            .into());
+        }
+        self.install_keys(&account, keys, mode)
+    }
+
+    fn join_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        self.existing_account(user_name)?;
+        self.execute("usermod", &["-aG", group_name, user_name])
+    }
+
+    fn leave_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        self.existing_account(user_name)?;
+        self.execute("gpasswd", &["-d", user_name, group_name])
+    }
+
+    fn archive_home(&self, user_name: &str) -> Result<String> {
+        let account = self.existing_account(user_name)?;
+        let path = path_join(&[
+            &self.archive_directory,
+            &format!(
+                "{}-{}-{}.tar.gz",
+                user_name,
+                self.local_host_name,
+                chrono::Local::now().format("%Y%m%d%H%M%S")
+            ),
+        ])?;
+        // アーカイブには秘密鍵なども含まれうるため、所有者のみが読めるようにする
+        if !self.plan(|| format!("mkdir -p {}", self.archive_directory)) {
+            std::fs::create_dir_all(&self.archive_directory)?;
+        }
+        self.execute("chmod", &["700", &self.archive_directory])?;
+        self.execute("tar", &["-czf", &path, "-C", &account.home, "."])?;
+        self.execute("chmod", &["600", &path])?;
+        Ok(path)
+    }
+
+    fn delete(&self, user_name: &str) -> Result<()> {
+        self.existing_account(user_name)?;
+        self.execute("userdel", &["-r", user_name])
+    }
+
+    fn lock(&self, user_name: &str) -> Result<()> {
+        let account = self.existing_account(user_name)?;
+        // パスワードを無効にし、有効期限を過去にすることで公開鍵以外のログインも防ぐ
+        self.execute("usermod", &["-L", "-e", "1", user_name])?;
+        let keys = ssh_file(&account.home, "authorized_keys")?;
+        if !account.locked && std::path::Path::new(&keys).exists() {
+            self.rename(&keys, &ssh_file(&account.home, LOCKED_KEYS_FILE)?)?;
+        }
+        // 実行中のプロセスが無い場合 pkill は1を返す
+        match self.execute("pkill", &["-KILL", "-u", user_name]) {
+            Err(Error::Linux(LinuxError::Command(_, 1))) => Ok(()),
+            x => x,
+        }
+    }
+
+    fn unlock(&self, user_name: &str) -> Result<()> {
+        let account = self.existing_account(user_name)?;
+        self.execute("usermod", &["-U", "-e", "", user_name])?;
+        // lock の前に authorized_keys が無かった場合は退避したファイルも無い
+        let locked_keys = ssh_file(&account.home, LOCKED_KEYS_FILE)?;
+        if account.locked && std::path::Path::new(&locked_keys).exists() {
+            self.rename(&locked_keys, &ssh_file(&account.home, "authorized_keys")?)?;
+        }
+        Ok(())
+    }
+
+    fn query(&self, user_name: &str) -> Result<Option<Account>> {
+        let line = match etc_passwd(user_name)? {
+            Some(x) => x,
+            None => {
+                // dry run で作成したことにしたアカウントは useradd の既定値で存在するものとする
+                let created = self
+                    .dry_run
+                    .as_ref()
+                    .is_some_and(|x| x.borrow().created_users.iter().any(|u| u == user_name));
+                return Ok(created.then(|| Account {
+                    name: user_name.to_string(),
+                    uid: MIN_USER_UID,
+                    home: format!("/home/{}", user_name),
+                    shell: String::new(),
+                    groups: Vec::new(),
+                    locked: false,
+                }));
+            }
+        };
+        // name:password:UID:GID:GECOS:directory:shell
+        let fields = line.split(':').collect::<Vec<_>>();
+        let home = fields.get(5).unwrap_or(&"").to_string();
+        // lock したかどうかはユーザーが書き換えられるホームディレクトリではなく /etc/shadow で判断する
+        // root 以外で dry run を試す場合は /etc/shadow を読めないため、 lock されていないものとする
+        let shadow = match etc_shadow(user_name) {
+            Err(Error::Io(e))
+                if self.dry_run.is_some() && e.kind() == std::io::ErrorKind::PermissionDenied =>
+            {
+                None
+            }
+            x => x?,
+        };
+        let today = chrono::Utc::now().timestamp() / (60 * 60 * 24);
+        Ok(Some(Account {
+            locked: shadow.is_some_and(|x| shadow_locked(&x, today)),
+            name: user_name.to_string(),
+            uid: fields
+                .get(2)
+                .and_then(|x| x.parse().ok())
+                .unwrap_or_default(),
+            home,
+            shell: fields.get(6).unwrap_or(&"").to_string(),
+            groups: supplementary_groups(user_name)?,
+        }))
+    }
+
+    fn group_names(&self) -> Result<Vec<String>> {
+        group_names()
+    }
+
+    fn group_id(&self, group_name: &str) -> Result<Option<u32>> {
+        group_id(group_name)
+    }
+
+    fn login_shells(&self) -> Result<Vec<String>> {
+        login_shells()
+    }
+
+    fn take_planned_actions(&self) -> Vec<String> {
+        self.dry_run
+            .as_ref()
+            .map(|x| std::mem::take(&mut x.borrow_mut().actions))
+            .unwrap_or_default()
+    }
 }
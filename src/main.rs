@@ -1,20 +1,59 @@
 use log::{debug, error, info, trace};
 use serde_json::json;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Read;
 
 mod runtime_error;
-use crate::runtime_error::{as_str, Error, Result};
+use crate::runtime_error::{as_array, as_str, Error, Result};
 
 mod slack;
 use crate::slack::{
-    add_reaction, channel_type, conversations_history, post_ephemeral_attachments, post_message,
-    post_message_to_thread, rtm_connect, try_connect_to_slack_com, users_list,
-    users_public_channel_list, ChannelType,
+    min_intervals_from_settings, ChannelType, SlackClient, DEFAULT_API_BASE_URL, DEFAULT_FILES_URL,
 };
 
+mod events_api;
+use crate::events_api::{respond_json, Callback, Receiver, SeenEvents};
+
+mod socket_mode;
+use crate::socket_mode::{acknowledge, Envelope};
+
+mod account_backend;
+use crate::account_backend::{AccountBackend, KeyMode, DEFAULT_SHELL, MIN_USER_UID};
+
 mod linux_user_manage;
-use crate::linux_user_manage::{create_account, join_group, update_account};
+use crate::linux_user_manage::{LinuxBackend, DEFAULT_ARCHIVE_DIRECTORY};
+
+mod public_keys;
+use crate::public_keys::{KeyOwner, KeySources, PublicKeyError};
+
+mod ssh_key;
+use crate::ssh_key::{KeySet, PublicKey};
+
+mod key_policy;
+use crate::key_policy::KeyPolicy;
+
+mod user_name;
+use crate::user_name::UserNameError;
+
+mod policy;
+use crate::policy::{glob_match, GroupAccess, GroupPolicy};
+
+mod roles;
+use crate::roles::{Role, Roles};
+
+mod store;
+use crate::store::{prepare_directory, Store, DEFAULT_STATE_DIRECTORY};
+
+mod approval;
+use crate::approval::{PendingRequest, RequestedAction};
+
+mod block_kit;
+use crate::block_kit::{
+    actions, button, context, divider, external_select, input, input_value, modal,
+    multi_external_select, option, plain_text_input, section, selected_value, selected_values,
+    static_select, suggested_options,
+};
 
 fn to_naive_date_time(timestamp_string: &str) -> Result<chrono::NaiveDateTime> {
     Ok(chrono::NaiveDateTime::parse_from_str(
@@ -66,7 +105,7 @@ fn get_hosts(uri: &str, certificate_file: Option<&str>) -> Result<Vec<String>> {
         .collect())
 }
 
-/// mes_json が channels で指定されたチャンネルでのメッセージかつ
+/// mes_json が channels で指定されたチャンネルでのメッセージ(またはメンション)かつ
 /// my_id で指定されたユーザー宛のメッセージかつ
 /// my_id で指定されたユーザーからのメッセージでない場合
 /// そのチャンネルIDを返す
@@ -81,7 +120,7 @@ fn channel_of_message_to_me_at_channels<'a>(
         mes_json["user"].as_str(),
         mes_json["text"].as_str(),
     ) {
-        if mes_type.trim_matches('"') == "message"
+        if matches!(mes_type.trim_matches('"'), "message" | "app_mention")
             && mes_user.trim_matches('"') != my_id
             && mes_text
                 .trim_matches('"')
@@ -95,20 +134,63 @@ fn channel_of_message_to_me_at_channels<'a>(
     None
 }
 
+/// `<@U0123>` や `<@U0123|name>` の形式のメンションからユーザーのIDを取得
+fn mentioned_user_id(text: &str) -> Option<&str> {
+    text.strip_prefix("<@")?
+        .strip_suffix('>')?
+        .split('|')
+        .next()
+}
+
+/// Slackが整形したメッセージの `<mailto:...|...>` などのリンクや `&lt;` などの文字参照を元に戻す
+fn unescape_slack_text(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = text;
+    while let (Some(start), Some(end)) = (rest.find('<'), rest.find('>')) {
+        if end < start {
+            break;
+        }
+        unescaped += &rest[..start];
+        let link = rest[start + 1..end].rsplit('|').next().unwrap_or_default();
+        unescaped += link.strip_prefix("mailto:").unwrap_or(link);
+        rest = &rest[end + 1..];
+    }
+    unescaped += rest;
+    unescaped
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// text の先頭から n 語を取り除いた残り(改行はそのまま残す)
+fn skip_words(text: &str, n: usize) -> &str {
+    let mut rest = text.trim_start();
+    for _ in 0..n {
+        rest = rest
+            .find(char::is_whitespace)
+            .map_or("", |i| rest[i..].trim_start());
+    }
+    rest
+}
+
 /// mes_json がDirect Message上でのメッセージかつ
 /// my_id で指定されたユーザーからのメッセージでない場合
 /// trueを返す
-fn is_message_at_dm(mes_json: &serde_json::Value, api_token: &str, my_id: &str) -> bool {
+fn is_message_at_dm(mes_json: &serde_json::Value, slack: &SlackClient, my_id: &str) -> bool {
     if let (Some(mes_type), Some(mes_channel), Some(mes_user)) = (
         mes_json["type"].as_str(),
         mes_json["channel"].as_str(),
         mes_json["user"].as_str(),
     ) {
-        match channel_type(api_token, mes_channel.trim_matches('"')) {
+        match slack.channel_type(mes_channel.trim_matches('"')) {
             Ok(ChannelType::DirectMessage) => {
                 mes_type.trim_matches('"') == "message" && mes_user.trim_matches('"') != my_id
             }
-            _ => false,
+            Ok(_) => false,
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
         }
     } else {
         false
@@ -118,10 +200,10 @@ fn is_message_at_dm(mes_json: &serde_json::Value, api_token: &str, my_id: &str)
 type WebSocket =
     tungstenite::protocol::WebSocket<tungstenite::stream::MaybeTlsStream<std::net::TcpStream>>;
 /// RTMのセットアップ
-fn rtm_setup(api_token: &str) -> Result<(String, WebSocket)> {
-    try_connect_to_slack_com()?;
+fn rtm_setup(slack: &SlackClient) -> Result<(String, WebSocket)> {
+    slack.try_connect_to_slack_com()?;
     // RTM の URL を取得
-    let v = rtm_connect(api_token)?;
+    let v = slack.rtm_connect()?;
     debug!("{}", serde_json::to_string_pretty(&v)?);
     debug!("{}", v["url"]);
 
@@ -130,15 +212,108 @@ fn rtm_setup(api_token: &str) -> Result<(String, WebSocket)> {
     debug!("My ID is {}.", my_id);
 
     // 先ほど取得したURLでRTMのクライアントを起動
-    let (client, response) = tungstenite::client::connect(as_str(&v["url"])?)?;
+    let (client, response) = tungstenite::client::connect(slack.websocket_url(as_str(&v["url"])?))?;
+    debug!("{response:?}");
+    Ok((my_id, client))
+}
+
+/// auth.test で自分のIDを取得
+fn get_my_id(slack: &SlackClient) -> Result<String> {
+    let v = slack.auth_test()?;
+    debug!("{}", serde_json::to_string_pretty(&v)?);
+    let my_id = as_str(&v["user_id"])?.to_string();
+    debug!("My ID is {}.", my_id);
+    Ok(my_id)
+}
+
+/// Socket Modeのセットアップ
+fn socket_mode_setup(slack: &SlackClient, app_token: &str) -> Result<(String, WebSocket)> {
+    slack.try_connect_to_slack_com()?;
+    // 自分のIDを取得
+    let my_id = get_my_id(slack)?;
+
+    // Socket Mode の URL を取得
+    let v = slack.apps_connections_open(app_token)?;
+    debug!("{}", serde_json::to_string_pretty(&v)?);
+
+    // 先ほど取得したURLでSocket Modeのクライアントを起動
+    let (client, response) = tungstenite::client::connect(slack.websocket_url(as_str(&v["url"])?))?;
     debug!("{response:?}");
     Ok((my_id, client))
 }
 
+/// WebSocketによるSlackとの接続方式
+enum WebSocketMode {
+    /// Real Time Messaging API
+    Rtm,
+    /// Socket Mode (app_token はアプリレベルトークン)
+    SocketMode { app_token: String },
+}
+
+impl WebSocketMode {
+    /// Slackに接続し、自分のIDとWebSocketのクライアントを取得
+    fn connect(&self, slack: &SlackClient) -> Result<(String, WebSocket)> {
+        match self {
+            WebSocketMode::Rtm => rtm_setup(slack),
+            WebSocketMode::SocketMode { app_token } => socket_mode_setup(slack, app_token),
+        }
+    }
+}
+
+/// Slackからイベントを受け取る方式
+enum ConnectionMode {
+    /// RTM または Socket Mode の WebSocket
+    WebSocket(WebSocketMode),
+    /// Events API のHTTPリクエスト
+    EventsApi {
+        listen_address: String,
+        signing_secret: String,
+    },
+}
+
+impl ConnectionMode {
+    /// 設定ファイルから接続方式を取得
+    fn from_settings(settings: &serde_json::Value) -> Result<Self> {
+        let required = |key: &'static str, mode: &'static str| {
+            settings[key]
+                .as_str()
+                .map(str::to_string)
+                .ok_or(Error::MissingSetting(key, mode))
+        };
+        match settings["connection_mode"].as_str() {
+            None | Some("rtm") => Ok(ConnectionMode::WebSocket(WebSocketMode::Rtm)),
+            Some("socket_mode") => Ok(ConnectionMode::WebSocket(WebSocketMode::SocketMode {
+                app_token: required("SLACK_APP_TOKEN", "socket_mode")?,
+            })),
+            Some("events_api") => Ok(ConnectionMode::EventsApi {
+                listen_address: settings["listen_address"]
+                    .as_str()
+                    .unwrap_or("0.0.0.0:3000")
+                    .to_string(),
+                signing_secret: required("SLACK_SIGNING_SECRET", "events_api")?,
+            }),
+            Some(x) => Err(Error::UnknownConnectionMode(x.to_string())),
+        }
+    }
+
+    /// local_host_name のbotが help や不正なコマンドに応答すべきかどうか
+    /// RTM では全てのホストのbotが同じbotユーザーへのメッセージを受け取るため、 hosts の先頭のホストだけが応答する
+    /// Socket Mode と Events API ではホストごとのSlackアプリに宛てたメッセージだけが届くため、常に応答する
+    fn pic_of_response(&self, hosts: &[String], local_host_name: &str) -> bool {
+        match self {
+            ConnectionMode::WebSocket(WebSocketMode::Rtm) => {
+                hosts.first().is_some_and(|x| x == local_host_name)
+            }
+            _ => true,
+        }
+    }
+}
+
 /// `channel_names` で与えられたチャンネルが、公開チャンネルとして存在するか確認する
-fn check_channels(api_token: &str, channel_names: &[String]) -> Result<Vec<String>> {
+fn check_channels(slack: &SlackClient, channel_names: &[String]) -> Result<Vec<String>> {
     // 公開チャンネルの一覧を取得
-    let public_channels = users_public_channel_list(api_token, None)?
+    let public_channels = slack
+        .users_public_channel_list(None)?
         .into_iter()
         .map(|v| (v.name, v.id))
         .collect::<HashMap<_, _>>();
@@ -154,8 +329,9 @@ fn check_channels(api_token: &str, channel_names: &[String]) -> Result<Vec<Strin
 }
 
 /// ユーザー一覧の取得
-fn get_users(api_token: &str) -> Result<HashMap<String, String>> {
-    users_list(api_token)?
+fn get_users(slack: &SlackClient) -> Result<HashMap<String, String>> {
+    slack
+        .users_list()?
         .into_iter()
         .map(|v| {
             Ok((
@@ -166,115 +342,28 @@ fn get_users(api_token: &str) -> Result<HashMap<String, String>> {
         .collect()
 }
 
-const ATTRIBUTE_NUMS: usize = 6usize;
-fn make_hostname_field(what: &str) -> serde_json::Map<String, serde_json::Value> {
-    vec![
-        ("title".to_string(), "_HOSTNAME_".into()),
-        (
-            "value".to_string(),
-            format!(
-                "The host name on which you want to {} (see below _HOSTNAME_ list)",
-                what
-            )
-            .into(),
-        ),
-    ]
-    .into_iter()
-    .collect()
-}
-
-fn make_available_channel_field(
-    channel_name: String,
-) -> serde_json::Map<String, serde_json::Value> {
-    vec![
-        ("title".to_string(), "available on".into()),
-        ("value".to_string(), channel_name.into()),
-    ]
-    .into_iter()
-    .collect()
-}
-
-fn format_fields(
-    fields: &[serde_json::Map<String, serde_json::Value>],
-) -> Vec<serde_json::Map<String, serde_json::Value>> {
-    let size = if fields.len() % 2 == 0 {
-        fields.len()
-    } else {
-        fields.len() - 1
-    };
-    let mut fs = fields.to_owned();
-    for x in fs.iter_mut().take(size) {
-        x.insert("short".to_string(), "true".into());
-    }
-    fs
-}
-
-fn make_head_lower(text: &str) -> String {
-    text.chars()
-        .by_ref()
-        .take(1)
-        .map(|x| x.to_ascii_lowercase())
-        .chain(text.chars().by_ref().skip(1))
-        .collect()
-}
-
-enum DescriptionOrList<'a> {
-    Description(&'a str),
-    List(&'a [String]),
-}
-
-type AttributeEntry<'a> = (
-    &'a str,
-    &'a DescriptionOrList<'a>,
-    Option<&'a [serde_json::Map<String, serde_json::Value>]>,
-);
+const SELECT_BLOCK_ID: &str = "sacana_select";
+const GROUP_ACTION_ID: &str = "sacana_group";
+const COMMAND_BLOCK_ID: &str = "sacana_command";
+const CREATE_ACTION_ID: &str = "sacana_create";
+const UPDATE_ACTION_ID: &str = "sacana_update";
+const JOIN_ACTION_ID: &str = "sacana_join";
+const LEAVE_ACTION_ID: &str = "sacana_leave";
 
-fn make_attributes(data: [AttributeEntry; ATTRIBUTE_NUMS]) -> serde_json::Value {
-    const COLORS: [&str; ATTRIBUTE_NUMS] = [
-        "#007dc6", "#ed1b23", "#fdb811", "#71bf44", "#00a650", "#6c6e71",
-    ];
-    data.iter()
-        .zip(COLORS.iter())
-        .map(|(&(text, desc_or_list, fields), &c)| {
-            let mut attribute = serde_json::Map::new();
-            attribute.insert("color".to_string(), c.to_string().into());
-            match desc_or_list {
-                DescriptionOrList::Description(d) => {
-                    attribute.insert("text".to_string(), [text, d].join("\n").into());
-                    attribute.insert(
-                        "fallback".to_string(),
-                        [text.to_string(), make_head_lower(d)].join(": ").into(),
-                    );
-                }
-                DescriptionOrList::List(l) => {
-                    attribute.insert(
-                        "text".to_string(),
-                        [text.to_string(), l.join("\n    ")].join("\n    ").into(),
-                    );
-                    attribute.insert(
-                        "fallback".to_string(),
-                        [text.to_string(), l.join(", ")].join(": ").into(),
-                    );
-                }
-            }
-            if let Some(f) = fields {
-                attribute.insert("fields".to_string(), format_fields(f).into());
-                attribute.insert("mrkdwn_in".to_string(), json!(["text", "fields"]));
-            } else {
-                attribute.insert("mrkdwn_in".to_string(), json!(["text"]));
-            }
-            attribute.into()
-        })
-        .collect::<Vec<serde_json::Value>>()
-        .into()
-}
+/// helpメッセージを表示できない通知などで使われるテキスト
+const HELP_FALLBACK: &str = "sacana help";
 
 /// helpメッセージの生成
+/// interactive が true の場合はグループを選んで local_host_name でコマンドを実行するボタンを含める
+/// ボタンの操作を受け取れるのはメッセージを投稿したbotだけなので、対象は local_host_name に限る
 fn make_help_message(
     my_id: &str,
     channels: &[String],
-    uri: &str,
+    key_sources: &str,
     hosts: &[String],
+    local_host_name: &str,
+    has_groups: bool,
+    interactive: bool,
 ) -> serde_json::Value {
     let channels_names = channels
         .iter()
@@ -284,58 +373,240 @@ fn make_help_message(
     let dm = format!("DM(<@{}>)", my_id);
     let channels_and_dm =
         channels_names.clone() + if channels_names.is_empty() { "" } else { ", " } + &dm;
-    make_attributes([
-        (
-            &format!("*<@{}> create _HOSTNAME_*", my_id),
-            &DescriptionOrList::Description("Creates you an account on _HOSTNAME_"),
-            Some(&[
-                make_hostname_field("create your account"),
-                make_available_channel_field(channels_names.clone()),
-            ]),
-        ),
-        (
-            &format!("*<@{}> update _HOSTNAME_*", my_id),
-            &DescriptionOrList::Description(&format!("Retrieves all public keys from `{}` and add them to `$HOME/.ssh/authorized_keys` (this command *WILL OVERWRITE* your `$HOME/.ssh/authorized_keys`)", uri)),
-            Some(&[
-                make_hostname_field("update your `authorized_keys`"),
-                make_available_channel_field(channels_names.clone())
-            ])
-        ),
-        (
-            &format!("*<@{}> join _GROUPNAME_ _HOSTNAME_*", my_id),
-            &DescriptionOrList::Description("Join _GROUPNAME_ group on _HOSTNAME_"),
-            Some(&[
-                vec![("title".to_string(), "_GROUPNAME_".into()),
-                     ("value".to_string(), "The group name which you want to join on _HOSTNAME_ . You can check the available groups on _HOSTNAME_ using `cat /etc/groups` .".into())]
-                    .into_iter()
-                    .collect(),
-                make_hostname_field("join the group"),
-                make_available_channel_field(channels_names)
-            ])
-        ),
-        (
-            &format!("*<@{}> ping*", my_id),
-            &DescriptionOrList::Description("Get pongs from alive bots"),
-            Some(&[make_available_channel_field(channels_and_dm)]),
-        ),
-        (
-            &format!("*<@{}> help*", my_id),
-            &DescriptionOrList::Description("Shows this message"),
-            Some(&[make_available_channel_field(dm)]),
+    let available_on = |x: &str| context(&format!("available on {}", x));
+    let mut blocks = vec![
+        section(&format!(
+            "*<@{}> create _HOSTNAME_*\nCreates you an account on _HOSTNAME_",
+            my_id
+        )),
+        available_on(&channels_names),
+        section(&format!("*<@{}> update _HOSTNAME_*\nRetrieves all public keys from {} and add them to `$HOME/.ssh/authorized_keys` (in `replace` mode this command *WILL OVERWRITE* your `$HOME/.ssh/authorized_keys`)", my_id, key_sources)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> keymode merge|replace _HOSTNAME_*\nChooses whether `update` keeps the lines you added to `$HOME/.ssh/authorized_keys` (`merge`) or overwrites the whole file (`replace`)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> join _GROUPNAME_ _HOSTNAME_*\nJoin _GROUPNAME_ group on _HOSTNAME_ . You can check the available groups on _HOSTNAME_ using `cat /etc/groups` .", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> leave _GROUPNAME_ _HOSTNAME_*\nLeave _GROUPNAME_ group on _HOSTNAME_", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> delete _HOSTNAME_*\nDeletes your account on _HOSTNAME_ . Your home directory is archived before deletion.", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> delete @USER _HOSTNAME_*\nDeletes the account of @USER on _HOSTNAME_ (admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> lock @USER _HOSTNAME_*\nSuspends the account of @USER on _HOSTNAME_ keeping its data, and kills running sessions (operators and admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> unlock @USER _HOSTNAME_*\nResumes the account of @USER on _HOSTNAME_ (operators and admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> mapping [@USER] _HOSTNAME_*\nShows which login name each Slack user (or @USER) is bound to on _HOSTNAME_ (admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> map @USER _LOGIN_ _HOSTNAME_*\nBinds @USER to the account _LOGIN_ on _HOSTNAME_ (admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> unmap @USER _HOSTNAME_*\nRemoves the binding of @USER on _HOSTNAME_ (admins only)", my_id)),
+        available_on(&channels_names),
+        section(&format!("*<@{}> addkey _HOSTNAME_ _PUBLIC KEY_*\nAdds _PUBLIC KEY_ (or the `.pub` files attached to the message) to your `$HOME/.ssh/authorized_keys` on _HOSTNAME_ . The key is kept on _HOSTNAME_ and installed again by `create` and `update` .", my_id)),
+        available_on(&dm),
+        section(&format!("*<@{}> ping*\nGet pongs from alive bots", my_id)),
+        available_on(&channels_and_dm),
+        section(&format!("*<@{}> help*\nShows this message", my_id)),
+        available_on(&dm),
+        divider(),
+        section(&["*_HOSTNAME_ list*".to_string(), hosts.join("\n    ")].join("\n    ")),
+    ];
+    if interactive {
+        blocks.extend([
+            divider(),
+            section(&format!(
+                "*Run a command on {}*\nSelect _GROUPNAME_ to join or leave and push a button.",
+                local_host_name
+            )),
+        ]);
+        if has_groups {
+            blocks.push(actions(
+                SELECT_BLOCK_ID,
+                vec![external_select(GROUP_ACTION_ID, "Select a group")],
+            ));
+        }
+        blocks.extend([actions(
+            COMMAND_BLOCK_ID,
+            vec![
+                button(CREATE_ACTION_ID, "Create account", "create"),
+                button(UPDATE_ACTION_ID, "Update keys", "update"),
+                button(JOIN_ACTION_ID, "Join group", "join"),
+                button(LEAVE_ACTION_ID, "Leave group", "leave"),
+                button(REQUEST_ACTION_ID, "Open request form", "request"),
+            ],
+        )]);
+    }
+    blocks.into()
+}
+
+const REQUEST_ACTION_ID: &str = "sacana_request";
+const REQUEST_FORM_CALLBACK_ID: &str = "sacana_request_form";
+const REQUEST_LOGIN_ID: &str = "sacana_login";
+const REQUEST_SHELL_ID: &str = "sacana_shell";
+const REQUEST_GROUPS_ID: &str = "sacana_groups";
+
+/// host へのアカウント申請フォームの生成
+/// 送信を受け取れるのはフォームを開いたbotだけなので、申請先は host に限る
+fn make_request_form(
+    host: &str,
+    has_groups: bool,
+    shells: &[String],
+    user_name: &str,
+) -> serde_json::Value {
+    let mut shell_select = static_select(REQUEST_SHELL_ID, "Select a login shell", shells);
+    if shells.iter().any(|x| x == DEFAULT_SHELL) {
+        shell_select["initial_option"] = option(DEFAULT_SHELL);
+    }
+    let mut blocks = vec![
+        section(&format!("*Host*\n{}", host)),
+        input(
+            REQUEST_LOGIN_ID,
+            "Login name",
+            plain_text_input(REQUEST_LOGIN_ID, user_name),
+            false,
         ),
-        ("*_HOSTNAME_ list*", &DescriptionOrList::List(hosts), None)
-    ])
+        input(REQUEST_SHELL_ID, "Login shell", shell_select, true),
+    ];
+    if has_groups {
+        blocks.push(input(
+            REQUEST_GROUPS_ID,
+            "Groups to join",
+            multi_external_select(REQUEST_GROUPS_ID, "Select groups"),
+            true,
+        ));
+    }
+    let mut form = modal(
+        REQUEST_FORM_CALLBACK_ID,
+        "Request an account",
+        "Request",
+        blocks,
+    );
+    form["private_metadata"] = host.into();
+    form
+}
+
+const APPROVAL_BLOCK_ID: &str = "sacana_approval";
+const APPROVE_ACTION_ID: &str = "sacana_approve";
+const DENY_ACTION_ID: &str = "sacana_deny";
+/// 承認の申請を承認するリアクション
+const APPROVE_REACTION: &str = "white_check_mark";
+/// 承認の申請を却下するリアクション
+const DENY_REACTION: &str = "x";
+/// 承認待ちの申請を保存するファイル名
+const PENDING_REQUESTS_FILE: &str = "pending_requests.json";
+/// Slackユーザーとアカウント名の対応付けを保存するファイル名
+const LOGINS_FILE: &str = "logins.json";
+/// アカウントごとの公開鍵の更新方法を保存するファイル名
+const KEY_MODES_FILE: &str = "key_modes.json";
+/// Slackユーザーごとの、 addkey でSlackから登録された公開鍵を保存するファイル名
+const SLACK_KEYS_FILE: &str = "slack_keys.json";
+/// 無効化されたSlackユーザーごとの、自動的に lock したアカウント名を保存するファイル名
+const DEACTIVATION_LOCKS_FILE: &str = "deactivation_locks.json";
+/// addkey でSlackから登録された公開鍵の取得元の表記
+const SLACK_KEY_SOURCE: &str = "Slack";
+/// addkey で受け付ける公開鍵のファイルの最大のサイズ(バイト)
+const MAX_KEY_FILE_SIZE: u64 = 16 * 1024;
+
+/// 承認の申請のメッセージの生成
+/// interactive が false の場合はリアクションでのみ承認できる
+fn make_approval_message(text: &str, interactive: bool) -> serde_json::Value {
+    let mut blocks = vec![
+        section(text),
+        context(&format!(
+            "Admins can approve with :{}: or deny with :{}: reaction.",
+            APPROVE_REACTION, DENY_REACTION
+        )),
+    ];
+    if interactive {
+        blocks.push(actions(
+            APPROVAL_BLOCK_ID,
+            vec![
+                button(APPROVE_ACTION_ID, "Approve", "approve"),
+                button(DENY_ACTION_ID, "Deny", "deny"),
+            ],
+        ));
+    }
+    blocks.into()
+}
+
+/// コマンドへの返答先
+#[derive(Clone, Copy, Debug)]
+enum Reply<'a> {
+    /// チャンネルやDMに投稿されたメッセージ(投稿とリアクションで返答する)
+    Message {
+        channel: &'a str,
+        timestamp: &'a str,
+    },
+    /// slash command の response_url (ephemeralな投稿で返答する)
+    ResponseUrl { url: &'a str },
+}
+
+/// イベントの受信を待つ最大の時間
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// 無効化されたユーザーを users.list と照合する間隔
+const RECONCILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Slackユーザーが無効化されたか、ゲストになったかどうか
+fn is_deactivated(user: &serde_json::Value) -> bool {
+    ["deleted", "is_restricted", "is_ultra_restricted"]
+        .iter()
+        .any(|x| user[*x].as_bool() == Some(true))
+}
+
+/// dry run で実行しなかった操作を返答に付け加えるテキスト
+fn planned_actions_text(actions: &[String]) -> String {
+    if actions.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\nDry run: nothing was changed. Planned actions:\n```\n{}\n```",
+            actions.join("\n")
+        )
+    }
 }
 
 struct CommandHandler {
     pic_of_response: bool,
-    api_token: String,
+    /// ボタンなどの操作を受け取れるかどうか(RTMでは受け取れない)
+    interactive: bool,
+    slack: SlackClient,
+    backend: Box<dyn AccountBackend>,
     local_host_name: String,
     hosts: Vec<String>,
     channels: Vec<String>,
     users: HashMap<String, String>,
+    /// ユーザーごとのコマンドを実行できる権限
+    roles: Roles,
+    /// グループへの参加の可否
+    group_policy: GroupPolicy,
+    /// アカウントの作成に管理者の承認が必要かどうか
+    create_requires_approval: bool,
+    /// SlackユーザーのIDごとの、対応付けられたアカウント名
+    logins: RefCell<Store<HashMap<String, String>>>,
+    /// keymode で選択されていないアカウントの公開鍵の更新方法
+    default_key_mode: KeyMode,
+    /// SlackユーザーのIDごとの、 addkey でSlackから登録された公開鍵の行
+    slack_keys: RefCell<Store<HashMap<String, Vec<String>>>>,
+    /// アカウント名ごとの、 keymode で選択された公開鍵の更新方法
+    key_modes: RefCell<Store<HashMap<String, KeyMode>>>,
+    /// 無効化されたSlackユーザーのIDごとの、自動的に lock したアカウント名
+    /// 管理者が unlock した後に照合で再び lock しないよう、ユーザーが再び有効になるまで記録する
+    deactivation_locks: RefCell<Store<HashMap<String, String>>>,
+    /// 承認待ちの申請(キーは admin_channel に投稿した申請の ts)
+    pending_requests: RefCell<Store<HashMap<String, PendingRequest>>>,
+    /// アカウントの自動的な lock などを通知するチャンネルのID
+    admin_channel: Option<String>,
+    /// 最後に users.list と照合した時刻
+    last_reconciled: Option<std::time::Instant>,
     my_id: String,
-    uri_format: String,
+    /// 公開鍵の取得元
+    key_sources: KeySources,
+    /// key_sources の `{}` に入れる名前の取得方法
+    key_owner: KeyOwner,
+    /// 書き込む公開鍵の種類や強度の条件
+    key_policy: KeyPolicy,
+    /// 最近受け取った Socket Mode の envelope_id, event_id とメッセージの client_msg_id
+    seen_events: SeenEvents,
     last_timestamp: Option<chrono::NaiveDateTime>,
 }
 
@@ -343,8 +614,7 @@ impl CommandHandler {
     /// Slackに起動報告をする
     fn report_startup(&self) -> Result<()> {
         for channel in &self.channels {
-            post_message(
-                &self.api_token,
+            self.slack.post_message(
                 channel,
                 &format!("Hello, this is sacana@{}.", self.local_host_name),
             )?;
@@ -352,21 +622,41 @@ impl CommandHandler {
         Ok(())
     }
 
+    /// 不正なコマンドなどに応答すべきかどうか
+    /// slash command は受け取ったbotのみに届くため、常に応答する
+    fn responsible(&self, reply: Reply) -> bool {
+        self.pic_of_response || matches!(reply, Reply::ResponseUrl { .. })
+    }
+
+    /// user_id 宛に text を返答する
+    fn reply(&self, user_id: &str, reply: Reply, text: &str) -> Result<()> {
+        match reply {
+            Reply::Message { channel, .. } => self
+                .slack
+                .post_message(channel, &format!("<@{}> {}", user_id, text)),
+            Reply::ResponseUrl { url, .. } => self.slack.post_to_response_url(
+                url,
+                json!({ "response_type": "ephemeral", "replace_original": false, "text": text }),
+            ),
+        }
+    }
+
+    /// 返答先のメッセージに reaction を付加する
+    fn react(&self, reply: Reply, reaction: &str) -> Result<()> {
+        match reply {
+            Reply::Message { channel, timestamp } => {
+                self.slack.add_reaction(channel, timestamp, reaction)
+            }
+            Reply::ResponseUrl { .. } => Ok(()),
+        }
+    }
+
     /// コマンド列が不正
-    fn invalid_command_sequence(
-        &self,
-        user_id: &str,
-        channel: &str,
-        timestamp: &str,
-    ) -> Result<()> {
-        if self.pic_of_response {
-            post_message(
-                &self.api_token,
-                channel,
-                &format!("<@{}> Invalid command sequence.", user_id),
-            )?;
-            add_reaction(&self.api_token, channel, timestamp, "x")?;
-            self.help(user_id, channel, timestamp, false)?;
+    fn invalid_command_sequence(&self, user_id: &str, reply: Reply) -> Result<()> {
+        if self.responsible(reply) {
+            self.reply(user_id, reply, "Invalid command sequence.")?;
+            self.react(reply, "x")?;
+            self.help(user_id, reply, false)?;
         }
         Ok(())
     }
@@ -374,123 +664,603 @@ impl CommandHandler {
     fn handle_command_result(
         &self,
         user_id: &str,
-        channel: &str,
-        timestamp: &str,
+        reply: Reply,
         result: Result<()>,
         info_message: &str,
         slack_message: &str,
     ) -> Result<()> {
+        let planned = planned_actions_text(&self.backend.take_planned_actions());
         if let Err(e) = result {
-            post_message(&self.api_token, channel, &format!("<@{}> {}", user_id, e))?;
-            add_reaction(&self.api_token, channel, timestamp, "x")
+            self.reply(user_id, reply, &(e.to_string() + &planned))?;
+            self.react(reply, "x")
         } else {
             info!("{}", info_message);
-            post_message(
-                &self.api_token,
-                channel,
-                &format!("<@{}> {}", user_id, slack_message),
-            )?;
-            add_reaction(&self.api_token, channel, timestamp, "o")
+            self.reply(user_id, reply, &(slack_message.to_string() + &planned))?;
+            self.react(reply, "o")
         }
     }
 
     /// help
-    fn help(&self, user_id: &str, channel: &str, timestamp: &str, check: bool) -> Result<()> {
-        if self.pic_of_response {
-            post_ephemeral_attachments(
-                &self.api_token,
-                channel,
-                user_id,
-                make_help_message(
-                    &self.my_id,
-                    &self.channels,
-                    &self.uri_format.replace("{}", &self.users[user_id]),
-                    &self.hosts,
-                ),
-            )?;
+    fn help(&self, user_id: &str, reply: Reply, check: bool) -> Result<()> {
+        if self.responsible(reply) {
+            let blocks = make_help_message(
+                &self.my_id,
+                &self.channels,
+                &self
+                    .key_owner
+                    .name(&self.slack, user_id, &self.users[user_id])
+                    .map_or_else(
+                        |_| self.key_sources.describe("{}"),
+                        |x| self.key_sources.describe(&x),
+                    ),
+                &self.hosts,
+                &self.local_host_name,
+                !self.joinable_groups().is_empty(),
+                self.interactive,
+            );
+            match reply {
+                Reply::Message { channel, .. } => {
+                    self.slack
+                        .post_ephemeral_blocks(channel, user_id, HELP_FALLBACK, blocks)?
+                }
+                Reply::ResponseUrl { url, .. } => self.slack.post_to_response_url(
+                    url,
+                    json!({
+                        "response_type": "ephemeral",
+                        "replace_original": false,
+                        "text": HELP_FALLBACK,
+                        "blocks": blocks,
+                    }),
+                )?,
+            }
             if check {
-                add_reaction(&self.api_token, channel, timestamp, "ballot_box_with_check")?
+                self.react(reply, "ballot_box_with_check")?
             }
         }
         Ok(())
     }
     /// ping
-    fn ping(&self, channel: &str, timestamp: &str) -> Result<()> {
-        post_message_to_thread(
-            &self.api_token,
-            channel,
-            timestamp,
-            &format!("pong@{}", self.local_host_name),
-        )
+    fn ping(&self, user_id: &str, reply: Reply) -> Result<()> {
+        let text = format!("pong@{}", self.local_host_name);
+        match reply {
+            Reply::Message { channel, timestamp } => {
+                self.slack.post_message_to_thread(channel, timestamp, &text)
+            }
+            Reply::ResponseUrl { .. } => self.reply(user_id, reply, &text),
+        }
+    }
+    /// user_id のユーザーの公開鍵を取得し、そのユーザーがSlackから登録した公開鍵を加える
+    fn public_keys(&self, user_id: &str) -> Result<KeySet> {
+        let slack_keys = self
+            .slack_keys
+            .borrow()
+            .get()
+            .get(user_id)
+            .map(|x| x.join("\n"))
+            .unwrap_or_default();
+        let mut slack_keys = KeySet::parse(&slack_keys, &self.key_policy, SLACK_KEY_SOURCE);
+        let fetched = self
+            .key_owner
+            .name(&self.slack, user_id, &self.users[user_id])
+            .and_then(|x| self.key_sources.fetch(&x, &self.key_policy));
+        match fetched {
+            Ok(mut keys) => {
+                keys.append(slack_keys);
+                Ok(keys)
+            }
+            // Slackから登録された公開鍵があれば、他の取得元から取得できなくてもその鍵を使う
+            // 返答が長くならないよう、エラーは1行目のみを残す
+            Err(e) if !slack_keys.accepted.is_empty() => {
+                let e = e.to_string();
+                slack_keys
+                    .failed
+                    .push(e.lines().next().unwrap_or_default().to_string());
+                Ok(slack_keys)
+            }
+            Err(e) => Err(e),
+        }
     }
     /// create
-    fn create(&self, user_id: &str, user_name: &str, channel: &str, timestamp: &str) -> Result<()> {
+    fn create(&self, user_id: &str, user_name: &str, reply: Reply) -> Result<()> {
+        if self.create_requires_approval {
+            let action = RequestedAction::Create {
+                shell: DEFAULT_SHELL.to_string(),
+                groups: Vec::new(),
+            };
+            return self.ask_approval(user_id, user_name, reply, action);
+        }
+        let result = self.public_keys(user_id).and_then(|keys| {
+            self.backend
+                .create(user_name, DEFAULT_SHELL, &keys.authorized_keys())?;
+            self.bind_login(user_id, user_name)?;
+            Ok(keys.report())
+        });
+        let report = result.as_deref().unwrap_or_default().to_string();
         self.handle_command_result(
             user_id,
-            channel,
-            timestamp,
-            create_account(user_name, &self.local_host_name, &self.uri_format),
+            reply,
+            result.map(|_| ()),
             &format!("{} create account", user_name),
-            "creating account is succeeded.",
+            &format!("creating account is succeeded.{}", report),
         )
     }
+    /// user_name のアカウントの authorized_keys を keymode に従って更新し、返答に含める鍵の一覧を返す
+    fn update_keys(&self, user_id: &str, user_name: &str) -> Result<String> {
+        let mode = self
+            .key_modes
+            .borrow()
+            .get()
+            .get(user_name)
+            .copied()
+            .unwrap_or(self.default_key_mode);
+        let keys = self.public_keys(user_id)?;
+        self.backend
+            .update_keys(user_name, &keys.authorized_keys(), mode)?;
+        Ok(keys.report())
+    }
     /// update
-    fn update(&self, user_id: &str, user_name: &str, channel: &str, timestamp: &str) -> Result<()> {
+    fn update(&self, user_id: &str, user_name: &str, reply: Reply) -> Result<()> {
+        let result = self.update_keys(user_id, user_name);
+        let report = result.as_deref().unwrap_or_default().to_string();
         self.handle_command_result(
             user_id,
-            channel,
-            timestamp,
-            update_account(user_name, &self.local_host_name, &self.uri_format),
+            reply,
+            result.map(|_| ()),
             &format!("{} update keys", user_name),
-            "updating key is succeeded.",
+            &format!("updating key is succeeded.{}", report),
         )
     }
-    /// join
-    fn join(
+    /// addkey で送られた text と files の内容
+    fn added_key_text(&self, text: &str, files: &[serde_json::Value]) -> Result<String> {
+        let mut added = unescape_slack_text(text);
+        for file in files {
+            let name = file["name"].as_str().unwrap_or_default();
+            if file["size"].as_u64().unwrap_or_default() > MAX_KEY_FILE_SIZE {
+                return Err(PublicKeyError::FileTooLarge(name.to_string()).into());
+            }
+            let url =
+                as_str(&file["url_private_download"]).or_else(|_| as_str(&file["url_private"]))?;
+            added += "\n";
+            added += &self.slack.download_file(url, MAX_KEY_FILE_SIZE)?;
+        }
+        Ok(added)
+    }
+    /// addkey
+    /// text と files の公開鍵を user_id のユーザーがSlackから登録した鍵として保存し、
+    /// アカウントがあれば authorized_keys を更新する
+    fn add_key(
         &self,
         user_id: &str,
         user_name: &str,
-        channel: &str,
-        timestamp: &str,
-        group_name: &str,
+        reply: Reply,
+        text: &str,
+        files: &[serde_json::Value],
     ) -> Result<()> {
+        let result = self.added_key_text(text, files).and_then(|text| {
+            let keys = KeySet::parse(&text, &self.key_policy, SLACK_KEY_SOURCE);
+            if keys.accepted.is_empty() {
+                let details = keys.rejection_text();
+                let details = if details.is_empty() {
+                    details
+                } else {
+                    format!("\n```\n{}\n```", details)
+                };
+                return Err(
+                    PublicKeyError::NoValidKeys("your message".to_string(), details).into(),
+                );
+            }
+            self.slack_keys.borrow_mut().update(|x| {
+                let saved = x.entry(user_id.to_string()).or_default();
+                for key in &keys.accepted {
+                    let blobs = saved
+                        .iter()
+                        .filter_map(|y| PublicKey::parse(y).ok())
+                        .map(|y| y.blob)
+                        .collect::<Vec<_>>();
+                    if !blobs.contains(&key.blob) {
+                        saved.push(key.to_string());
+                    }
+                }
+            })?;
+            let mut text = format!("adding key is succeeded.{}", keys.report());
+            if self.backend.query(user_name)?.is_some() {
+                text += &format!(
+                    "\nupdating key is succeeded.{}",
+                    self.update_keys(user_id, user_name)?
+                );
+            } else {
+                text += &format!(
+                    "\nThe key(s) will be installed when you `create` your account on {}.",
+                    self.local_host_name
+                );
+            }
+            Ok(text)
+        });
+        let text = result.as_deref().unwrap_or_default().to_string();
         self.handle_command_result(
             user_id,
-            channel,
-            timestamp,
-            join_group(user_name, group_name, &self.local_host_name),
-            &format!("{} joined {} group.", user_name, group_name),
-            &format!("joined {} group.", group_name),
+            reply,
+            result.map(|_| ()),
+            &format!("{} add keys", user_name),
+            &text,
+        )
+    }
+    /// keymode
+    fn set_key_mode(&self, user_id: &str, user_name: &str, reply: Reply, mode: &str) -> Result<()> {
+        let mode = match KeyMode::from_name(mode) {
+            Some(x) => x,
+            None => return self.invalid_command_sequence(user_id, reply),
+        };
+        self.handle_command_result(
+            user_id,
+            reply,
+            self.key_modes
+                .borrow_mut()
+                .update(|x| x.insert(user_name.to_string(), mode))
+                .map(|_| ()),
+            &format!("{} set key mode to {}", user_name, mode),
+            &format!("`update` will {} your authorized_keys from now on.", mode),
+        )
+    }
+    /// group_policy で参加が禁止されている group_name に参加しようとした場合の返答
+    fn forbidden_group_text(&self, group_name: &str) -> String {
+        format!(
+            "`{}` group can't be joined on {}.",
+            group_name, self.local_host_name
+        )
+    }
+    /// group_policy での group_name への参加の可否
+    /// GIDを取得できない場合はシステムのグループとして扱う
+    fn group_access(&self, group_name: &str) -> GroupAccess {
+        let gid = self.backend.group_id(group_name).unwrap_or_else(|e| {
+            error!("{}", e);
+            Some(0)
+        });
+        self.group_policy
+            .access(&self.local_host_name, group_name, gid)
+    }
+    /// group_policy で参加が禁止されていないグループ名の一覧
+    fn joinable_groups(&self) -> Vec<String> {
+        let mut groups = self.backend.group_names().unwrap_or_default();
+        groups.retain(|x| self.group_access(x) != GroupAccess::Forbidden);
+        groups
+    }
+    /// join
+    fn join(&self, user_id: &str, user_name: &str, reply: Reply, group_name: &str) -> Result<()> {
+        match self.group_access(group_name) {
+            GroupAccess::Free => self.handle_command_result(
+                user_id,
+                reply,
+                self.backend.join_group(user_name, group_name),
+                &format!("{} joined {} group.", user_name, group_name),
+                &format!("joined {} group.", group_name),
+            ),
+            GroupAccess::Approval => {
+                let action = RequestedAction::Join {
+                    group: group_name.to_string(),
+                };
+                self.ask_approval(user_id, user_name, reply, action)
+            }
+            GroupAccess::Forbidden => {
+                self.reply(user_id, reply, &self.forbidden_group_text(group_name))?;
+                self.react(reply, "x")
+            }
+        }
+    }
+    /// leave
+    fn leave(&self, user_id: &str, user_name: &str, reply: Reply, group_name: &str) -> Result<()> {
+        self.handle_command_result(
+            user_id,
+            reply,
+            self.backend.leave_group(user_name, group_name),
+            &format!("{} left {} group.", user_name, group_name),
+            &format!("left {} group.", group_name),
+        )
+    }
+    /// delete
+    /// owner_id のユーザーがSlackから登録した公開鍵も削除する
+    fn delete(&self, user_id: &str, owner_id: &str, user_name: &str, reply: Reply) -> Result<()> {
+        let (result, slack_message) = match self.backend.archive_home(user_name) {
+            Ok(path) => (
+                self.backend.delete(user_name).and_then(|()| {
+                    self.slack_keys
+                        .borrow_mut()
+                        .update(|x| x.remove(owner_id))?;
+                    Ok(())
+                }),
+                format!(
+                    "deleting account `{}` is succeeded. the home directory is archived to `{}`.",
+                    user_name, path
+                ),
+            ),
+            Err(e) => (Err(e), String::new()),
+        };
+        self.handle_command_result(
+            user_id,
+            reply,
+            result,
+            &format!("{} deleted by {}", user_name, self.users[user_id]),
+            &slack_message,
+        )
+    }
+    /// lock
+    fn lock(&self, user_id: &str, user_name: &str, reply: Reply) -> Result<()> {
+        self.handle_command_result(
+            user_id,
+            reply,
+            self.backend.lock(user_name),
+            &format!("{} locked by {}", user_name, self.users[user_id]),
+            &format!("locking account `{}` is succeeded.", user_name),
+        )
+    }
+    /// unlock
+    fn unlock(&self, user_id: &str, user_name: &str, reply: Reply) -> Result<()> {
+        self.handle_command_result(
+            user_id,
+            reply,
+            self.backend.unlock(user_name),
+            &format!("{} unlocked by {}", user_name, self.users[user_id]),
+            &format!("unlocking account `{}` is succeeded.", user_name),
+        )
+    }
+    /// user_id が role 以上のロールを持つかどうか
+    fn has_role(&self, user_id: &str, role: Role) -> bool {
+        self.roles.role(user_id) >= Some(role)
+    }
+    /// user_id が role 以上のロールを持つかどうかを返し、持たない場合は返答する
+    fn permitted(&self, user_id: &str, reply: Reply, role: Role) -> Result<bool> {
+        if self.has_role(user_id, role) {
+            return Ok(true);
+        }
+        self.reply(
+            user_id,
+            reply,
+            &format!("This command requires the {} role.", role),
+        )?;
+        self.react(reply, "x")?;
+        Ok(false)
+    }
+    /// 他のユーザーを対象とするコマンドの対象のユーザーのIDを取得
+    /// target が不明な場合は返答して None を返す
+    fn target_user_id<'a>(
+        &self,
+        user_id: &str,
+        reply: Reply,
+        target: &'a str,
+    ) -> Result<Option<&'a str>> {
+        match mentioned_user_id(target).filter(|x| self.users.contains_key(*x)) {
+            Some(x) => Ok(Some(x)),
+            None => {
+                self.reply(user_id, reply, &format!("Unknown user '{}'.", target))?;
+                self.react(reply, "x")?;
+                Ok(None)
+            }
+        }
+    }
+    /// 他のユーザーを対象とするコマンドの対象のアカウント名を取得
+    /// target が不明な場合などは返答して None を返す
+    fn target_user(&self, user_id: &str, reply: Reply, target: &str) -> Result<Option<String>> {
+        match self.target_user_id(user_id, reply, target)? {
+            Some(x) => self.login_or_reply(user_id, reply, x),
+            None => Ok(None),
+        }
+    }
+    /// コマンドを実行したユーザーのアカウント名を取得
+    /// 取得できない場合は返答して None を返す
+    fn own_login(&self, user_id: &str, reply: Reply) -> Result<Option<String>> {
+        self.login_or_reply(user_id, reply, user_id)
+    }
+    /// user_id のユーザーのアカウント名を取得し、取得できないか lock されている場合は返答して None を返す
+    /// lock されたアカウントは調査のためにデータを残すよう、本人による削除やグループの変更を受け付けない
+    fn own_unlocked_login(&self, user_id: &str, reply: Reply) -> Result<Option<String>> {
+        let user_name = match self.own_login(user_id, reply)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let result = match self.backend.query(&user_name) {
+            Ok(Some(account)) if account.locked => Err(Error::AccountLocked(
+                user_name.clone(),
+                self.local_host_name.clone(),
+            )),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => Ok(Some(user_name)),
+            Err(e) => {
+                self.reply(user_id, reply, &e.to_string())?;
+                self.react(reply, "x")?;
+                Ok(None)
+            }
+        }
+    }
+    /// target_id のユーザーのアカウント名を取得し、取得できない場合は user_id に返答して None を返す
+    fn login_or_reply(
+        &self,
+        user_id: &str,
+        reply: Reply,
+        target_id: &str,
+    ) -> Result<Option<String>> {
+        match self.login_name(target_id) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) => {
+                self.reply(user_id, reply, &e.to_string())?;
+                self.react(reply, "x")?;
+                Ok(None)
+            }
+        }
+    }
+    /// user_id のSlackユーザーに対応付けられたアカウント名
+    fn bound_login(&self, user_id: &str) -> Option<String> {
+        self.logins.borrow().get().get(user_id).cloned()
+    }
+    /// login_name のアカウントに対応付けられたSlackユーザーのID
+    fn login_owner(&self, login_name: &str) -> Option<String> {
+        self.logins
+            .borrow()
+            .get()
+            .iter()
+            .find(|(_, x)| *x == login_name)
+            .map(|(id, _)| id.clone())
+    }
+    /// user_id のSlackユーザーが login_name のアカウントを使えるか確認する
+    fn check_login(&self, user_id: &str, login_name: &str) -> Result<()> {
+        match (self.bound_login(user_id), self.login_owner(login_name)) {
+            (Some(bound), _) if bound != login_name => Err(Error::LoginMismatch(bound)),
+            (None, Some(_)) => Err(Error::LoginBound(login_name.to_string())),
+            _ => Ok(()),
+        }
+    }
+    /// user_id のSlackユーザーを login_name のアカウントに対応付ける
+    fn bind_login(&self, user_id: &str, login_name: &str) -> Result<()> {
+        self.check_login(user_id, login_name)?;
+        if self.bound_login(user_id).is_none() {
+            info!("{} is bound to {}", user_id, login_name);
+            self.logins
+                .borrow_mut()
+                .update(|x| x.insert(user_id.to_string(), login_name.to_string()))?;
+        }
+        Ok(())
+    }
+    /// name が一般ユーザーのアカウント名として使えるか確認する
+    /// 規則を満たしていても、既存のシステムのアカウントの名前は使えない
+    fn check_user_name(&self, name: &str) -> Result<()> {
+        user_name::validate(name)?;
+        match self.backend.query(name)? {
+            Some(account) if account.uid < MIN_USER_UID => {
+                Err(UserNameError::Reserved(name.to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// user_id のSlackユーザーのアカウント名
+    /// 対応付けが無い場合は表示名を使うが、そのアカウントが既に存在する場合は管理者の map が必要
+    fn login_name(&self, user_id: &str) -> Result<String> {
+        if let Some(x) = self.bound_login(user_id) {
+            self.check_user_name(&x)?;
+            return Ok(x);
+        }
+        let name = self.users[user_id].clone();
+        self.check_user_name(&name)?;
+        self.check_login(user_id, &name)?;
+        // 表示名を変更して他のユーザーのアカウントを操作できないよう、自動的には対応付けない
+        if self.backend.query(&name)?.is_some() {
+            return Err(Error::LoginUnbound(name));
+        }
+        Ok(name)
+    }
+    /// 対応付けを導入する前に作成されたアカウントを、表示名が一致するSlackユーザーに対応付ける
+    /// 対応付けを保存するファイルが無い初回の起動時にのみ実行する
+    /// 表示名が重複しているユーザーは対応付けず、管理者の map に任せる
+    fn seed_logins(&self) -> Result<()> {
+        let mut seeded = HashMap::new();
+        for (user_id, name) in &self.users {
+            if self.users.values().filter(|x| *x == name).count() > 1
+                || self.check_user_name(name).is_err()
+                || self.backend.query(name)?.is_none()
+            {
+                continue;
+            }
+            info!("{} is bound to {} on migration", user_id, name);
+            seeded.insert(user_id.clone(), name.clone());
+        }
+        self.logins.borrow_mut().seed(|x| x.extend(seeded))
+    }
+    /// mapping: target_id のユーザー(None の場合は全てのユーザー)の対応付けを返答する
+    fn show_mappings(&self, user_id: &str, reply: Reply, target_id: Option<&str>) -> Result<()> {
+        let logins = self.logins.borrow();
+        let mut lines = logins
+            .get()
+            .iter()
+            .filter(|(id, _)| target_id.is_none_or(|x| x == *id))
+            .map(|(id, login)| format!("<@{}> → `{}`", id, login))
+            .collect::<Vec<_>>();
+        lines.sort();
+        let text = if lines.is_empty() {
+            format!("no mappings on {}.", self.local_host_name)
+        } else {
+            format!(
+                "mappings on {}:\n{}",
+                self.local_host_name,
+                lines.join("\n")
+            )
+        };
+        self.reply(user_id, reply, &text)
+    }
+    /// map: target_id のユーザーを login_name のアカウントに対応付け直す
+    fn map_login(
+        &self,
+        user_id: &str,
+        reply: Reply,
+        target_id: &str,
+        login_name: &str,
+    ) -> Result<()> {
+        let result =
+            self.check_user_name(login_name)
+                .and_then(|()| match self.login_owner(login_name) {
+                    Some(owner) if owner != target_id => {
+                        Err(Error::LoginBound(login_name.to_string()))
+                    }
+                    _ => self
+                        .logins
+                        .borrow_mut()
+                        .update(|x| x.insert(target_id.to_string(), login_name.to_string()))
+                        .map(|_| ()),
+                });
+        self.handle_command_result(
+            user_id,
+            reply,
+            result,
+            &format!("{} is bound to {} by {}", target_id, login_name, user_id),
+            &format!("<@{}> is bound to `{}`.", target_id, login_name),
+        )
+    }
+    /// unmap: target_id のユーザーの対応付けを削除する
+    /// Slackから登録された公開鍵はSlackのユーザーに紐づくため、アカウントを delete するまで残す
+    fn unmap_login(&self, user_id: &str, reply: Reply, target_id: &str) -> Result<()> {
+        let result = self
+            .logins
+            .borrow_mut()
+            .update(|x| x.remove(target_id))
+            .map(|_| ());
+        self.handle_command_result(
+            user_id,
+            reply,
+            result,
+            &format!("{} is unbound by {}", target_id, user_id),
+            &format!("<@{}> is unbound.", target_id),
         )
     }
     /// ホスト名チェック
     fn check_host_name(
         &self,
         user_id: &str,
-        channel: &str,
-        timestamp: &str,
+        reply: Reply,
         hostname: Option<&&str>,
     ) -> Result<bool> {
-        if self.pic_of_response {
+        if self.responsible(reply) {
             match hostname {
                 None => {
-                    post_message(
-                        &self.api_token,
-                        channel,
-                        &format!("<@{}> Invalid hostname.", user_id),
-                    )?;
-                    add_reaction(&self.api_token, channel, timestamp, "x")?;
-                    self.help(user_id, channel, timestamp, false)?;
+                    self.reply(user_id, reply, "Invalid hostname.")?;
+                    self.react(reply, "x")?;
+                    self.help(user_id, reply, false)?;
                 }
                 Some(name) => {
                     if !self.hosts.iter().any(|x| x == name) {
-                        post_message(
-                            &self.api_token,
-                            channel,
-                            &format!("<@{}> Invalid hostname '{}'.", user_id, name),
+                        self.reply(user_id, reply, &format!("Invalid hostname '{}'.", name))?;
+                        self.react(reply, "x")?;
+                        self.help(user_id, reply, false)?;
+                    } else if matches!(reply, Reply::ResponseUrl { .. })
+                        && name != &self.local_host_name
+                    {
+                        self.reply(
+                            user_id,
+                            reply,
+                            &format!(
+                                "sacana@{} can't handle commands for '{}'.",
+                                self.local_host_name, name
+                            ),
                         )?;
-                        add_reaction(&self.api_token, channel, timestamp, "x")?;
-                        self.help(user_id, channel, timestamp, false)?;
                     }
                 }
             }
@@ -498,38 +1268,569 @@ impl CommandHandler {
         Ok(hostname.is_some() && hostname.unwrap() == &self.local_host_name)
     }
 
-    fn dm(&self, mes_json: serde_json::Value) -> Result<Option<chrono::NaiveDateTime>> {
-        debug!("{}", serde_json::to_string_pretty(&mes_json)?);
-        let raw_message = as_str(&mes_json["text"])?;
-        debug!("Raw message:\n{}", raw_message);
-        let channel = as_str(&mes_json["channel"])?;
-        let user_id = as_str(&mes_json["user"])?;
-        let timestamp = as_str(&mes_json["ts"])?;
-        let splitted_messages: Vec<&str> = if raw_message
-            .trim_matches('"')
-            .starts_with(&format!("<@{}>", self.my_id))
-        {
-            //DMの場合かつリプライの場合、「@<bot名>」以後の入力を受け取る
-            raw_message.split_whitespace().skip(1).collect()
-        } else {
-            //DMの場合かつリプライではない場合、入力を全て受け取る
-            raw_message.split_whitespace().collect()
-        };
+    /// DMで受け付けるコマンドを実行し、応答したかどうかを返す
+    /// files はメッセージに添付されたファイル
+    fn dm_command(
+        &self,
+        user_id: &str,
+        reply: Reply,
+        text: &str,
+        files: &[serde_json::Value],
+    ) -> Result<bool> {
+        let splitted_messages: Vec<&str> = text.split_whitespace().collect();
         match (
             splitted_messages.first(),
             splitted_messages.len(),
-            self.pic_of_response,
+            self.responsible(reply),
         ) {
-            (Some(&"help"), 1, true) => self.help(user_id, channel, timestamp, true)?,
-            (Some(&"ping"), 1, _) => self.ping(channel, timestamp)?,
-            (_, _, true) => self.invalid_command_sequence(user_id, channel, timestamp)?,
-            (_, _, false) => return Ok(None),
+            (Some(&"help"), 1, true) => self.help(user_id, reply, true)?,
+            (Some(&"ping"), 1, _) => self.ping(user_id, reply)?,
+            (Some(&"addkey"), n, _) if n >= 3 || (n == 2 && !files.is_empty()) => {
+                if self.check_host_name(user_id, reply, splitted_messages.get(1))?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_login(user_id, reply)? {
+                        self.add_key(user_id, &x, reply, skip_words(text, 2), files)?
+                    }
+                }
+            }
+            (_, _, true) => self.invalid_command_sequence(user_id, reply)?,
+            (_, _, false) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// channels で受け付けるコマンドを実行
+    fn channel_command(
+        &self,
+        user_id: &str,
+        reply: Reply,
+        splitted_messages: &[&str],
+    ) -> Result<()> {
+        match (splitted_messages.first(), splitted_messages.len()) {
+            (Some(&"ping"), 1) => self.ping(user_id, reply)?,
+            (Some(&"create"), 2) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_login(user_id, reply)? {
+                        self.create(user_id, &x, reply)?
+                    }
+                }
+            }
+            (Some(&"update"), 2) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_login(user_id, reply)? {
+                        self.update(user_id, &x, reply)?
+                    }
+                }
+            }
+            (Some(&"keymode"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_login(user_id, reply)? {
+                        self.set_key_mode(user_id, &x, reply, splitted_messages[1])?
+                    }
+                }
+            }
+            (Some(&"join"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_unlocked_login(user_id, reply)? {
+                        self.join(user_id, &x, reply, splitted_messages[1])?
+                    }
+                }
+            }
+            (Some(&"leave"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_unlocked_login(user_id, reply)? {
+                        self.leave(user_id, &x, reply, splitted_messages[1])?
+                    }
+                }
+            }
+            (Some(&"delete"), 2) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::User)?
+                {
+                    if let Some(x) = self.own_unlocked_login(user_id, reply)? {
+                        self.delete(user_id, user_id, &x, reply)?
+                    }
+                }
+            }
+            (Some(&"delete"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Admin)?
+                {
+                    if let Some(target_id) =
+                        self.target_user_id(user_id, reply, splitted_messages[1])?
+                    {
+                        if let Some(x) = self.login_or_reply(user_id, reply, target_id)? {
+                            self.delete(user_id, target_id, &x, reply)?
+                        }
+                    }
+                }
+            }
+            (Some(&"lock"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Operator)?
+                {
+                    if let Some(x) = self.target_user(user_id, reply, splitted_messages[1])? {
+                        self.lock(user_id, &x, reply)?
+                    }
+                }
+            }
+            (Some(&"unlock"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Operator)?
+                {
+                    if let Some(x) = self.target_user(user_id, reply, splitted_messages[1])? {
+                        self.unlock(user_id, &x, reply)?
+                    }
+                }
+            }
+            (Some(&"mapping"), 2) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Admin)?
+                {
+                    self.show_mappings(user_id, reply, None)?
+                }
+            }
+            (Some(&"mapping"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Admin)?
+                {
+                    if let Some(x) = self.target_user_id(user_id, reply, splitted_messages[1])? {
+                        self.show_mappings(user_id, reply, Some(x))?
+                    }
+                }
+            }
+            (Some(&"map"), 4) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Admin)?
+                {
+                    if let Some(x) = self.target_user_id(user_id, reply, splitted_messages[1])? {
+                        self.map_login(user_id, reply, x, splitted_messages[2])?
+                    }
+                }
+            }
+            (Some(&"unmap"), 3) => {
+                if self.check_host_name(user_id, reply, splitted_messages.last())?
+                    && self.permitted(user_id, reply, Role::Admin)?
+                {
+                    if let Some(x) = self.target_user_id(user_id, reply, splitted_messages[1])? {
+                        self.unmap_login(user_id, reply, x)?
+                    }
+                }
+            }
+            _ => self.invalid_command_sequence(user_id, reply)?,
+        }
+        Ok(())
+    }
+
+    /// helpメッセージのボタンの操作を処理
+    fn block_actions(&self, payload: &serde_json::Value) -> Result<()> {
+        let user_id = as_str(&payload["user"]["id"])?;
+        let reply = Reply::ResponseUrl {
+            url: as_str(&payload["response_url"])?,
+        };
+        for action in as_array(&payload["actions"])? {
+            let action_id = as_str(&action["action_id"])?;
+            let command = match action_id {
+                CREATE_ACTION_ID => "create",
+                UPDATE_ACTION_ID => "update",
+                JOIN_ACTION_ID => "join",
+                LEAVE_ACTION_ID => "leave",
+                REQUEST_ACTION_ID => {
+                    self.open_request_form(user_id, as_str(&payload["trigger_id"])?)?;
+                    continue;
+                }
+                APPROVE_ACTION_ID | DENY_ACTION_ID => {
+                    if self.has_role(user_id, Role::Admin) {
+                        let ts = as_str(&payload["container"]["message_ts"])?;
+                        self.decide(user_id, ts, action_id == APPROVE_ACTION_ID)?;
+                    } else {
+                        self.reply(user_id, reply, "Only admins can approve or deny requests.")?;
+                    }
+                    continue;
+                }
+                // セレクトメニューの選択はボタンが押された時に state から取得する
+                _ => continue,
+            };
+            let host = self.local_host_name.as_str();
+            let group = selected_value(&payload["state"], SELECT_BLOCK_ID, GROUP_ACTION_ID);
+            let splitted_messages = match (command, group) {
+                ("join" | "leave", None) => {
+                    self.reply(user_id, reply, "Please select _GROUPNAME_.")?;
+                    continue;
+                }
+                ("join" | "leave", Some(g)) => vec![command, g, host],
+                (_, _) => vec![command, host],
+            };
+            self.channel_command(user_id, reply, &splitted_messages)?;
+        }
+        Ok(())
+    }
+
+    /// アカウント申請フォームを開く
+    fn open_request_form(&self, user_id: &str, trigger_id: &str) -> Result<()> {
+        let shells = self
+            .backend
+            .login_shells()
+            .unwrap_or_else(|_| vec![DEFAULT_SHELL.to_string()]);
+        self.slack.views_open(
+            trigger_id,
+            make_request_form(
+                &self.local_host_name,
+                !self.joinable_groups().is_empty(),
+                &shells,
+                &self
+                    .bound_login(user_id)
+                    .unwrap_or_else(|| self.users[user_id].clone()),
+            ),
+        )
+    }
+
+    /// key_owner_id のユーザーの公開鍵でアカウントを作成してグループに追加し、結果のメッセージを返す
+    /// アカウントの作成に失敗した場合は Err で結果のメッセージを返す
+    fn create_account(
+        &self,
+        key_owner_id: &str,
+        user_name: &str,
+        shell: &str,
+        groups: &[String],
+    ) -> std::result::Result<String, String> {
+        let mut results = Vec::new();
+        match self.public_keys(key_owner_id).and_then(|keys| {
+            self.backend
+                .create(user_name, shell, &keys.authorized_keys())?;
+            self.bind_login(key_owner_id, user_name)?;
+            Ok(keys.report())
+        }) {
+            Ok(report) => {
+                info!("{} create account", user_name);
+                results.push(format!(
+                    ":o: creating account `{}` is succeeded.{}",
+                    user_name, report
+                ));
+            }
+            Err(e) => {
+                return Err(format!(":x: {}", e)
+                    + &planned_actions_text(&self.backend.take_planned_actions()))
+            }
+        }
+        for group_name in groups {
+            results.push(self.join_group(user_name, group_name));
+        }
+        Ok(results.join("\n") + &planned_actions_text(&self.backend.take_planned_actions()))
+    }
+
+    /// user_name のアカウントをグループに追加し、結果のメッセージを返す
+    fn join_group(&self, user_name: &str, group_name: &str) -> String {
+        match self.backend.join_group(user_name, group_name) {
+            Ok(()) => {
+                info!("{} joined {} group.", user_name, group_name);
+                format!(":o: joined {} group.", group_name)
+            }
+            Err(e) => format!(":x: {}", e),
+        }
+    }
+
+    /// 申請されたアカウントの作成とグループへの追加を行い、結果のメッセージを返す
+    /// 管理者の承認が必要なものは承認を申請し、結果は channel の ts のスレッドに返答する
+    fn fulfil_request(
+        &self,
+        user_id: &str,
+        user_name: &str,
+        shell: &str,
+        groups: &[&str],
+        channel: &str,
+        ts: &str,
+    ) -> String {
+        if let Err(e) = self
+            .check_user_name(user_name)
+            .and_then(|()| self.check_login(user_id, user_name))
+        {
+            return format!(":x: {}", e);
+        }
+        let mut free_groups = Vec::new();
+        let mut approval_groups = Vec::new();
+        let mut forbidden = Vec::new();
+        for group_name in groups {
+            match self.group_access(group_name) {
+                GroupAccess::Free => free_groups.push(group_name.to_string()),
+                GroupAccess::Approval => approval_groups.push(group_name.to_string()),
+                GroupAccess::Forbidden => {
+                    forbidden.push(format!(":x: {}", self.forbidden_group_text(group_name)))
+                }
+            }
+        }
+        let request_approval = |action| {
+            let request = PendingRequest {
+                requester: user_id.to_string(),
+                user_name: user_name.to_string(),
+                action,
+                channel: channel.to_string(),
+                thread_ts: Some(ts.to_string()),
+            };
+            match self.request_approval(request) {
+                Ok(text) => format!(":hourglass_flowing_sand: {}", text),
+                Err(e) => format!(":x: {}", e),
+            }
+        };
+        let mut results = if self.create_requires_approval {
+            // アカウントの作成の承認で、承認が必要なグループへの参加もまとめて承認する
+            free_groups.append(&mut approval_groups);
+            vec![request_approval(RequestedAction::Create {
+                shell: shell.to_string(),
+                groups: free_groups,
+            })]
+        } else {
+            match self.create_account(user_id, user_name, shell, &free_groups) {
+                Ok(text) => std::iter::once(text)
+                    .chain(
+                        approval_groups
+                            .into_iter()
+                            .map(|group| request_approval(RequestedAction::Join { group })),
+                    )
+                    .collect(),
+                // アカウントの作成に失敗した場合はグループへの参加を申請しない
+                Err(text) => vec![text],
+            }
+        };
+        results.extend(forbidden);
+        results.join("\n")
+    }
+
+    /// 承認の申請を admin_channel に投稿して保存し、申請者への返答を返す
+    fn request_approval(&self, request: PendingRequest) -> Result<String> {
+        let channel = self.admin_channel.as_ref().ok_or(Error::NoAdminChannel)?;
+        let description = request.describe(&self.local_host_name);
+        let text = format!("<@{}> requests to {}.", request.requester, description);
+        let ts = self.slack.post_blocks_for_thread(
+            channel,
+            &text,
+            make_approval_message(&text, self.interactive),
+        )?;
+        info!("{} requested to {}", request.requester, description);
+        self.pending_requests
+            .borrow_mut()
+            .update(|x| x.insert(ts, request))?;
+        Ok(format!(
+            "{} requires approval by an admin. the request has been sent to the admins.",
+            description
+        ))
+    }
+
+    /// 管理者の承認が必要な action を申請し、申請したことを reply に返答する
+    fn ask_approval(
+        &self,
+        user_id: &str,
+        user_name: &str,
+        reply: Reply,
+        action: RequestedAction,
+    ) -> Result<()> {
+        let (channel, thread_ts) = match reply {
+            Reply::Message { channel, timestamp } => (channel, Some(timestamp.to_string())),
+            // response_url は時間が経つと使えなくなるため、結果は申請者へのDMで返答する
+            Reply::ResponseUrl { .. } => (user_id, None),
+        };
+        let request = PendingRequest {
+            requester: user_id.to_string(),
+            user_name: user_name.to_string(),
+            action,
+            channel: channel.to_string(),
+            thread_ts,
+        };
+        match self.request_approval(request) {
+            Ok(text) => {
+                self.reply(user_id, reply, &text)?;
+                self.react(reply, "hourglass_flowing_sand")
+            }
+            Err(e) => {
+                self.reply(user_id, reply, &e.to_string())?;
+                self.react(reply, "x")
+            }
+        }
+    }
+
+    /// 承認された申請を実行し、結果のメッセージを返す
+    fn perform(&self, request: &PendingRequest) -> String {
+        match &request.action {
+            RequestedAction::Create { shell, groups } => {
+                match self.create_account(&request.requester, &request.user_name, shell, groups) {
+                    Ok(text) | Err(text) => text,
+                }
+            }
+            RequestedAction::Join { group } => {
+                self.join_group(&request.user_name, group)
+                    + &planned_actions_text(&self.backend.take_planned_actions())
+            }
+        }
+    }
+
+    /// admin_channel の ts の申請を approver が承認または却下し、申請者に結果を返答する
+    fn decide(&self, approver: &str, ts: &str, approved: bool) -> Result<()> {
+        // 他のホストのbotが投稿した申請や、既に処理した申請は無視する
+        if !self.pending_requests.borrow().get().contains_key(ts) {
+            return Ok(());
+        }
+        let request = match self
+            .pending_requests
+            .borrow_mut()
+            .update(|x| x.remove(ts))?
+        {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let description = request.describe(&self.local_host_name);
+        let (decision, result) = if approved {
+            info!("{} approved the request to {}", approver, description);
+            ("approved", "\n".to_string() + &self.perform(&request))
+        } else {
+            info!("{} denied the request to {}", approver, description);
+            ("denied", String::new())
+        };
+        if let Some(channel) = &self.admin_channel {
+            self.slack.update_message(
+                channel,
+                ts,
+                &format!(
+                    "<@{}> requested to {}: {} by <@{}>.{}",
+                    request.requester, description, decision, approver, result
+                ),
+            )?;
+        }
+        let text = format!(
+            "<@{}> your request to {} was {} by <@{}>.{}",
+            request.requester, description, decision, approver, result
+        );
+        match &request.thread_ts {
+            Some(thread_ts) => {
+                self.slack
+                    .post_message_to_thread(&request.channel, thread_ts, &text)
+            }
+            None => self.slack.post_message(&request.channel, &text),
+        }
+    }
+
+    /// admin_channel の申請へのリアクションによる承認や却下を処理する
+    fn reaction_added(&self, event: &serde_json::Value) -> Result<()> {
+        let item = &event["item"];
+        match (&self.admin_channel, item["channel"].as_str()) {
+            (Some(admin_channel), Some(channel)) if admin_channel == channel => {}
+            _ => return Ok(()),
+        }
+        let approved = match event["reaction"].as_str() {
+            Some(APPROVE_REACTION) => true,
+            Some(DENY_REACTION) => false,
+            _ => return Ok(()),
+        };
+        let user_id = as_str(&event["user"])?;
+        // 管理者以外のリアクションは無視する
+        if self.has_role(user_id, Role::Admin) {
+            self.decide(user_id, as_str(&item["ts"])?, approved)?;
+        }
+        Ok(())
+    }
+
+    /// アカウント申請フォームの送信を処理し、結果をスレッドに投稿
+    fn request_form_submitted(&self, payload: &serde_json::Value) -> Result<()> {
+        let user_id = as_str(&payload["user"]["id"])?;
+        let host = as_str(&payload["view"]["private_metadata"])?;
+        let state = &payload["view"]["state"];
+        let bound_login = self.bound_login(user_id);
+        let user_name = input_value(state, REQUEST_LOGIN_ID, REQUEST_LOGIN_ID)
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .or(bound_login.as_deref())
+            .unwrap_or(&self.users[user_id]);
+        let shell =
+            selected_value(state, REQUEST_SHELL_ID, REQUEST_SHELL_ID).unwrap_or(DEFAULT_SHELL);
+        let groups = selected_values(state, REQUEST_GROUPS_ID, REQUEST_GROUPS_ID);
+        // 結果は監視しているチャンネルの先頭に投稿する(無い場合は申請者へのDM)
+        let channel = self.channels.first().map_or(user_id, String::as_str);
+        let ts = self.slack.post_message_for_thread(
+            channel,
+            &format!(
+                "<@{}> requested an account `{}` on {}.",
+                user_id, user_name, host
+            ),
+        )?;
+        let result = if host != self.local_host_name {
+            format!(
+                ":x: sacana@{} can't handle requests for '{}'.",
+                self.local_host_name, host
+            )
+        } else if !self.has_role(user_id, Role::User) {
+            format!(":x: This command requires the {} role.", Role::User)
+        } else {
+            self.fulfil_request(user_id, user_name, shell, &groups, channel, &ts)
+        };
+        self.slack
+            .post_message_to_thread(channel, &ts, &format!("*{}*\n{}", host, result))
+    }
+
+    /// block_suggestion で求められたセレクトメニューの、入力された文字列を含む選択肢
+    fn suggestion(&self, payload: &serde_json::Value) -> serde_json::Value {
+        let values = match payload["action_id"].as_str() {
+            Some(GROUP_ACTION_ID | REQUEST_GROUPS_ID) => self.joinable_groups(),
+            _ => Vec::new(),
+        };
+        suggested_options(&values, payload["value"].as_str().unwrap_or_default())
+    }
+
+    /// ボタンなどの操作を処理
+    fn interaction(&self, payload: &serde_json::Value) -> Result<()> {
+        debug!("{}", serde_json::to_string_pretty(payload)?);
+        match (
+            payload["type"].as_str(),
+            payload["callback_id"].as_str(),
+            payload["view"]["callback_id"].as_str(),
+        ) {
+            (Some("block_actions"), _, _) => self.block_actions(payload),
+            (Some("shortcut"), Some(REQUEST_ACTION_ID), _) => self.open_request_form(
+                as_str(&payload["user"]["id"])?,
+                as_str(&payload["trigger_id"])?,
+            ),
+            (Some("view_submission"), _, Some(REQUEST_FORM_CALLBACK_ID)) => {
+                self.request_form_submitted(payload)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn dm(&self, mes_json: serde_json::Value) -> Result<Option<chrono::NaiveDateTime>> {
+        debug!("{}", serde_json::to_string_pretty(&mes_json)?);
+        let raw_message = as_str(&mes_json["text"])?;
+        debug!("Raw message:\n{}", raw_message);
+        let channel = as_str(&mes_json["channel"])?;
+        let user_id = as_str(&mes_json["user"])?;
+        let timestamp = as_str(&mes_json["ts"])?;
+        let text = if raw_message
+            .trim_matches('"')
+            .starts_with(&format!("<@{}>", self.my_id))
+        {
+            //DMの場合かつリプライの場合、「@<bot名>」以後の入力を受け取る
+            skip_words(raw_message, 1)
+        } else {
+            //DMの場合かつリプライではない場合、入力を全て受け取る
+            raw_message
+        };
+        let files = mes_json["files"].as_array().cloned().unwrap_or_default();
+        if !self.dm_command(user_id, Reply::Message { channel, timestamp }, text, &files)? {
+            return Ok(None);
         }
         Ok(Some(to_naive_date_time(timestamp)?))
     }
 
     fn message(&self, mes_json: serde_json::Value) -> Result<Option<chrono::NaiveDateTime>> {
-        if is_message_at_dm(&mes_json, &self.api_token, &self.my_id) {
+        if is_message_at_dm(&mes_json, &self.slack, &self.my_id) {
             return self.dm(mes_json);
         }
         let channel = if let Some(x) =
@@ -544,46 +1845,61 @@ impl CommandHandler {
         debug!("Raw message:\n{}", raw_message);
         let user_id = as_str(&mes_json["user"])?;
         let timestamp = as_str(&mes_json["ts"])?;
+        let reply = Reply::Message { channel, timestamp };
         let splitted_messages: Vec<&str> = raw_message.split_whitespace().skip(1).collect();
         match (splitted_messages.first(), splitted_messages.len()) {
             (Some(&"help"), 1) => {
                 if self.pic_of_response {
-                    post_message(
-                        &self.api_token,
-                        channel,
-                        &format!("<@{}> please type `help` at Direct Message to me.", user_id),
-                    )?;
-                    add_reaction(&self.api_token, channel, timestamp, "exclamation")?
-                }
-            }
-            (Some(&"ping"), 1) => self.ping(channel, timestamp)?,
-            (Some(&"create"), 2) => {
-                if self.check_host_name(user_id, channel, timestamp, splitted_messages.last())? {
-                    self.create(user_id, &self.users[user_id], channel, timestamp)?
-                }
-            }
-            (Some(&"update"), 2) => {
-                if self.check_host_name(user_id, channel, timestamp, splitted_messages.last())? {
-                    self.update(user_id, &self.users[user_id], channel, timestamp)?
-                }
-            }
-            (Some(&"join"), 3) => {
-                if self.check_host_name(user_id, channel, timestamp, splitted_messages.last())? {
-                    self.join(
+                    self.reply(
                         user_id,
-                        &self.users[user_id],
-                        channel,
-                        timestamp,
-                        splitted_messages[1],
-                    )?
+                        reply,
+                        "please type `help` at Direct Message to me.",
+                    )?;
+                    self.react(reply, "exclamation")?
                 }
             }
-            _ => self.invalid_command_sequence(user_id, channel, timestamp)?,
+            _ => self.channel_command(user_id, reply, &splitted_messages)?,
         }
         Ok(Some(to_naive_date_time(timestamp)?))
     }
 
-    /// RTMの一部のイベントを処理
+    /// slash command を処理
+    fn slash_command(&self, payload: &serde_json::Value) -> Result<()> {
+        debug!("{}", serde_json::to_string_pretty(payload)?);
+        let user_id = as_str(&payload["user_id"])?;
+        let channel = as_str(&payload["channel_id"])?;
+        let reply = Reply::ResponseUrl {
+            url: as_str(&payload["response_url"])?,
+        };
+        let splitted_messages: Vec<&str> = as_str(&payload["text"])?.split_whitespace().collect();
+        if self.channels.iter().any(|x| x == channel) {
+            match (splitted_messages.first(), splitted_messages.len()) {
+                (Some(&"help"), 1) => self.help(user_id, reply, false)?,
+                _ => self.channel_command(user_id, reply, &splitted_messages)?,
+            }
+        } else if let Ok(ChannelType::DirectMessage) = self.slack.channel_type(channel) {
+            self.dm_command(user_id, reply, as_str(&payload["text"])?, &[])?;
+        } else {
+            let channels_names = self
+                .channels
+                .iter()
+                .map(|x| format!("<#{}>", x))
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.reply(
+                user_id,
+                reply,
+                &format!(
+                    "{} is available on {} or DM.",
+                    as_str(&payload["command"])?,
+                    channels_names
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Slackの一部のイベントを処理
     fn handle_events(&mut self, mes_json: &serde_json::Value) -> Result<bool> {
         Ok(if let Some(mes_type) = mes_json["type"].as_str() {
             match mes_type.trim_matches('"') {
@@ -591,16 +1907,15 @@ impl CommandHandler {
                 "hello" => true,
                 "goodbye" => return Err(Error::CaughtGoodBye),
                 "user_change" => {
-                    if let Some(x) = self.users.get_mut(as_str(&mes_json["user"]["id"])?) {
-                        *x = as_str(&mes_json["user"]["profile"]["display_name_normalized"])?
-                            .to_string();
-                        return Ok(true);
+                    let user = &mes_json["user"];
+                    let user_id = as_str(&user["id"])?;
+                    let user_name =
+                        as_str(&user["profile"]["display_name_normalized"])?.to_string();
+                    self.users.insert(user_id.to_string(), user_name);
+                    // lock や通知の失敗で接続をやり直さないよう、エラーはログに残すだけにする
+                    if let Err(e) = self.update_deactivation(user_id, is_deactivated(user)) {
+                        error!("{}", e);
                     }
-                    self.users.insert(
-                        as_str(&mes_json["user"]["id"])?.to_string(),
-                        as_str(&mes_json["user"]["profile"]["display_name_normalized"])?
-                            .to_string(),
-                    );
                     true
                 }
                 "team_join" => {
@@ -611,6 +1926,12 @@ impl CommandHandler {
                     );
                     true
                 }
+                "reaction_added" => {
+                    if let Err(e) = self.reaction_added(mes_json) {
+                        error!("{}", e);
+                    }
+                    true
+                }
                 "user_typing" => true,
                 "desktop_notification" => true,
                 _ => false,
@@ -620,6 +1941,93 @@ impl CommandHandler {
         })
     }
 
+    /// admin_channel に text を投稿する
+    fn notify_admins(&self, text: &str) -> Result<()> {
+        match &self.admin_channel {
+            Some(channel) => self.slack.post_message(channel, text),
+            None => Ok(()),
+        }
+    }
+
+    /// 無効化されたSlackユーザーに対応付けられたアカウントがあれば lock し、管理者に通知する
+    /// 対応付けが無いアカウントは表示名が一致していても、管理者が map するまで lock しない
+    fn lock_deactivated(&self, user_id: &str) -> Result<()> {
+        if self.deactivation_locks.borrow().get().contains_key(user_id) {
+            return Ok(());
+        }
+        let user_name = match self.bound_login(user_id) {
+            Some(x) => x,
+            None => {
+                info!("{} is deactivated, but isn't bound to any account", user_id);
+                return Ok(());
+            }
+        };
+        match self.backend.query(&user_name)? {
+            // システムのアカウントは表示名が偶然一致しても lock しない
+            Some(account) if !account.locked && account.uid >= MIN_USER_UID => {}
+            _ => return Ok(()),
+        }
+        let text = match self.backend.lock(&user_name) {
+            Ok(()) => {
+                info!("{} locked because {} is deactivated", user_name, user_id);
+                self.deactivation_locks
+                    .borrow_mut()
+                    .update(|x| x.insert(user_id.to_string(), user_name.to_string()))?;
+                format!(
+                    "sacana@{} locked account `{}` because <@{}> is deactivated.",
+                    self.local_host_name, user_name, user_id
+                )
+            }
+            Err(e) => {
+                error!("locking {} failed: {}", user_name, e);
+                format!(
+                    "sacana@{} failed to lock account `{}` of deactivated <@{}>: {}",
+                    self.local_host_name, user_name, user_id, e
+                )
+            }
+        };
+        self.notify_admins(&(text + &planned_actions_text(&self.backend.take_planned_actions())))
+    }
+
+    /// user_id のSlackユーザーが無効化されていればアカウントを lock し、
+    /// 再び有効になっていれば lock したことの記録を削除する(アカウントの unlock は管理者が行う)
+    fn update_deactivation(&self, user_id: &str, deactivated: bool) -> Result<()> {
+        if deactivated {
+            return self.lock_deactivated(user_id);
+        }
+        if self.deactivation_locks.borrow().get().contains_key(user_id) {
+            self.deactivation_locks
+                .borrow_mut()
+                .update(|x| x.remove(user_id))?;
+        }
+        Ok(())
+    }
+
+    /// users.list と照合し、無効化されたユーザーのアカウントを lock する
+    fn reconcile_users(&mut self) -> Result<()> {
+        self.last_reconciled = Some(std::time::Instant::now());
+        // ユーザーグループのメンバーの変更を反映する
+        self.roles.resolve(&self.slack);
+        for user in self.slack.users_list()? {
+            self.update_deactivation(as_str(&user["id"])?, is_deactivated(&user))?;
+        }
+        Ok(())
+    }
+
+    /// 前回から RECONCILE_INTERVAL 以上経過していれば reconcile_users を実行する
+    fn tick(&mut self) {
+        if self
+            .last_reconciled
+            .is_some_and(|x| x.elapsed() < RECONCILE_INTERVAL)
+        {
+            return;
+        }
+        info!("reconcile users");
+        if let Err(e) = self.reconcile_users() {
+            error!("{}", e);
+        }
+    }
+
     fn update_timestamp(&mut self, timestamp: Option<chrono::NaiveDateTime>) -> Result<()> {
         if let Some(x) = timestamp {
             let mut flag = false;
@@ -637,40 +2045,104 @@ impl CommandHandler {
         Ok(())
     }
 
+    /// Slackのイベントを処理
+    fn on_event(&mut self, mes_json: serde_json::Value) -> Result<()> {
+        if let Ok(pretty_string) = serde_json::to_string_pretty(&mes_json) {
+            debug!("{}", pretty_string);
+        }
+        if self.handle_events(&mes_json)? {
+            return Ok(());
+        }
+        if self.last_timestamp.is_none() {
+            if let Some(x) = mes_json["ts"].as_str() {
+                let _ = self.update_timestamp(Some(to_naive_date_time(x)?));
+            }
+        }
+        if !self.first_delivery(&mes_json) {
+            return Ok(());
+        }
+        let result = self.message(mes_json);
+        match result {
+            Ok(res) => self.update_timestamp(res)?,
+            Err(e) => error!("{}", e),
+        }
+        Ok(())
+    }
+
+    /// RTMで受信したテキストを処理
     fn on_text(&mut self, text: String) -> Result<()> {
-        let mes_json_ = text.parse::<serde_json::Value>();
-        match mes_json_ {
-            Ok(mes_json) => {
-                if let Ok(pretty_string) = serde_json::to_string_pretty(&mes_json) {
-                    debug!("{}", pretty_string);
-                }
-                if self.handle_events(&mes_json)? {
-                    return Ok(());
-                }
-                if self.last_timestamp.is_none() {
-                    if let Some(x) = mes_json["ts"].as_str() {
-                        let _ = self.update_timestamp(Some(to_naive_date_time(x)?));
-                    }
+        match text.parse::<serde_json::Value>() {
+            Ok(mes_json) => self.on_event(mes_json)?,
+            Err(e) => error!("{}", e),
+        }
+        Ok(())
+    }
+
+    /// Socket Modeで受信したテキストを処理
+    fn on_envelope(&mut self, client: &mut WebSocket, text: String) -> Result<()> {
+        let envelope = match serde_json::from_str::<Envelope>(&text) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}: {}", e, text);
+                return Ok(());
+            }
+        };
+        // Slack は3秒以内に応答が無いと再送するため、処理の前に応答する
+        // block_suggestion には応答でセレクトメニューの選択肢を返す
+        if let Some(envelope_id) = envelope.envelope_id() {
+            let payload = match &envelope {
+                Envelope::Interactive { payload, .. } if payload["type"] == "block_suggestion" => {
+                    Some(self.suggestion(payload))
                 }
-                let result = self.message(mes_json);
-                match result {
-                    Ok(res) => self.update_timestamp(res)?,
-                    Err(e) => error!("{}", e),
+                _ => None,
+            };
+            client.send(acknowledge(envelope_id, payload))?;
+        }
+        // 処理が遅れている間に再送された envelope やイベントは処理しない
+        let mut first_delivery = true;
+        for id in envelope
+            .envelope_id()
+            .into_iter()
+            .chain(envelope.event_id())
+        {
+            first_delivery &= self.seen_events.insert(id);
+        }
+        if !first_delivery {
+            debug!("ignore retry of envelope: {}", text);
+            return Ok(());
+        }
+        match envelope {
+            Envelope::Hello {} => debug!("Socket Mode hello"),
+            Envelope::Disconnect { reason } => return Err(Error::CaughtDisconnect(reason)),
+            Envelope::EventsApi { payload, .. } => self.on_event(payload["event"].clone())?,
+            Envelope::SlashCommands { payload, .. } => {
+                if let Err(e) = self.slash_command(&payload) {
+                    error!("{}", e)
+                }
+            }
+            Envelope::Interactive { payload, .. } => {
+                if let Err(e) = self.interaction(&payload) {
+                    error!("{}", e)
                 }
             }
-            Err(e) => error!("{}", e),
         }
         Ok(())
     }
 
+    /// メッセージを初めて受け取った場合は true を返す
+    /// 再接続の前に受け取ったメッセージは履歴から再び取得されるため、 client_msg_id で判別する
+    fn first_delivery(&mut self, message: &serde_json::Value) -> bool {
+        message["client_msg_id"]
+            .as_str()
+            .is_none_or(|id| self.seen_events.insert(id))
+    }
+
     fn handle_messages_while_dead(&mut self) -> Result<()> {
         let mut timestamps = Vec::new();
-        for channel in &self.channels {
-            let messages = conversations_history(
-                &self.api_token,
-                channel,
-                &self.last_timestamp.as_ref().map(to_string),
-            )?;
+        for channel in self.channels.clone() {
+            let messages = self
+                .slack
+                .conversations_history(&channel, &self.last_timestamp.as_ref().map(to_string))?;
             let last_timestamp = if let Some(x) = messages.first() {
                 Some(to_naive_date_time(as_str(&x["ts"])?)?)
             } else {
@@ -678,6 +2150,9 @@ impl CommandHandler {
             };
             for mut message in messages.into_iter().rev() {
                 message["channel"] = json!(&channel);
+                if !self.first_delivery(&message) {
+                    continue;
+                }
                 let _ = self.message(message).map_err(|e| error!("{}", e));
             }
             timestamps.push(last_timestamp);
@@ -689,11 +2164,151 @@ impl CommandHandler {
     }
 }
 
+/// client の受信のタイムアウトを設定する
+fn set_read_timeout(client: &WebSocket, timeout: std::time::Duration) -> Result<()> {
+    use tungstenite::stream::MaybeTlsStream;
+    match client.get_ref() {
+        MaybeTlsStream::Plain(s) => s.set_read_timeout(Some(timeout))?,
+        MaybeTlsStream::NativeTls(s) => s.get_ref().set_read_timeout(Some(timeout))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// WebSocketが切断されるまでイベントを受信して処理する
+fn receive_websocket(
+    command_handler: &mut CommandHandler,
+    mode: &WebSocketMode,
+    client: &mut WebSocket,
+) -> Result<()> {
+    // 受信が無い間も定期的な処理を行うため、タイムアウトを設定する
+    set_read_timeout(client, IDLE_TIMEOUT)?;
+    loop {
+        command_handler.tick();
+        let m = match client.read() {
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            message => message?,
+        };
+        trace!("Recv: {:?}", m);
+        use tungstenite::protocol::Message::*;
+        match m {
+            Text(s) => match mode {
+                WebSocketMode::Rtm => command_handler.on_text(s)?,
+                WebSocketMode::SocketMode { .. } => command_handler.on_envelope(client, s)?,
+            },
+            Binary(_) => debug!("get binary"),
+            Close(_) => debug!("get closure"),
+            Ping(ping) => {
+                debug!("Ping");
+                let pong = Pong(ping);
+                debug!("Send {:?}", pong);
+                client.send(pong)?;
+            }
+            Pong(_) => debug!("Pong"),
+            Frame(x) => debug!("Frame({x:?})"),
+        }
+    }
+}
+
+/// WebSocketでイベントを受信して処理する
+fn poll_websocket(
+    command_handler: &mut CommandHandler,
+    mode: &WebSocketMode,
+    channel_names: &[String],
+) -> ! {
+    let (my_id, mut client) = mode.connect(&command_handler.slack).unwrap();
+    command_handler.my_id = my_id;
+    command_handler.report_startup().unwrap();
+
+    info!("poling started");
+    // メッセージのポーリング
+    loop {
+        let _ = receive_websocket(command_handler, mode, &mut client).map_err(|e| info!("{}", e));
+        let (my_id, new_client) = mode.connect(&command_handler.slack).unwrap();
+        command_handler.my_id = my_id;
+        client = new_client;
+        command_handler.channels = check_channels(&command_handler.slack, channel_names).unwrap();
+        command_handler.users = get_users(&command_handler.slack).unwrap();
+        command_handler.handle_messages_while_dead().unwrap();
+        // 切断中に受け取れなかった user_change を補うため、すぐに照合する
+        command_handler.last_reconciled = None;
+        info!("poling restarted");
+    }
+}
+
+/// Events API のHTTPリクエストでイベントを受信して処理する
+fn serve_events_api(
+    command_handler: &mut CommandHandler,
+    listen_address: &str,
+    signing_secret: String,
+) -> ! {
+    command_handler.slack.try_connect_to_slack_com().unwrap();
+    command_handler.my_id = get_my_id(&command_handler.slack).unwrap();
+    let mut receiver = Receiver::bind(listen_address, signing_secret).unwrap();
+    command_handler.report_startup().unwrap();
+
+    info!("listening on {}", listen_address);
+    loop {
+        command_handler.tick();
+        let result = receiver
+            .recv(IDLE_TIMEOUT)
+            .and_then(|callback| match callback {
+                Some(Callback::Event(e)) => command_handler.on_event(e),
+                Some(Callback::SlashCommand(payload)) => command_handler.slash_command(&payload),
+                Some(Callback::Interaction(payload)) => command_handler.interaction(&payload),
+                Some(Callback::Suggestion { payload, request }) => {
+                    respond_json(request, &command_handler.suggestion(&payload))
+                }
+                None => Ok(()),
+            });
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+    }
+}
+
+/// state_directory の name のファイルに保存される状態を開く
+/// dry run ではファイルに書き込まず、状態も変更しない
+fn open_store<T: serde::Serialize + serde::de::DeserializeOwned + Default + Clone>(
+    state_directory: &str,
+    name: &str,
+    dry_run: bool,
+) -> RefCell<Store<T>> {
+    RefCell::new(
+        if dry_run {
+            Store::open_dry_run(state_directory, name)
+        } else {
+            Store::open(state_directory, name)
+        }
+        .unwrap(),
+    )
+}
+
 fn main() {
     let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info");
     env_logger::Builder::from_env(env).init();
     let settings: serde_json::Value = read_settings().unwrap();
-    let api_token: String = settings["SLACK_API_TOKEN"].as_str().unwrap().to_string();
+    let slack = SlackClient::new(
+        settings["SLACK_API_TOKEN"].as_str().unwrap().to_string(),
+        settings["slack_api_url"]
+            .as_str()
+            .unwrap_or(DEFAULT_API_BASE_URL)
+            .trim_end_matches('/')
+            .to_string(),
+        settings["slack_websocket_url"].as_str().map(str::to_string),
+        settings["slack_files_url"]
+            .as_str()
+            .unwrap_or(DEFAULT_FILES_URL)
+            .to_string(),
+    )
+    .with_min_intervals(min_intervals_from_settings(&settings["slack_min_intervals"]).unwrap());
     // 設定ファイルまたは uname の実行結果から local_host_name を取得
     let local_host_name: String = if let Some(x) = settings["hostname"].as_str() {
         x.to_string()
@@ -708,62 +2323,115 @@ fn main() {
         .iter()
         .map(|x| x.as_str().unwrap().to_string())
         .collect::<Vec<_>>();
-    let uri_format = settings["public_key_uri_format"]
-        .as_str()
-        .unwrap()
-        .to_string();
-    // 端末ホスト名のリスト(先頭の端末はホスト名が不正な場合のエラーメッセージ返答をする)
+    let key_sources = KeySources::from_settings(
+        &settings["key_sources"],
+        &settings["key_source_mode"],
+        settings["public_key_uri_format"].as_str(),
+    )
+    .unwrap();
+    // 端末ホスト名のリスト(RTM では先頭の端末がホスト名が不正な場合のエラーメッセージ返答をする)
     let hosts = get_hosts(
         settings["host_list_uri"].as_str().unwrap(),
         settings["certificate_file"].as_str(),
     )
     .unwrap();
     debug!("hosts = {:?}", hosts);
-    let (my_id, mut client) = rtm_setup(&api_token).unwrap();
+    let connection_mode = ConnectionMode::from_settings(&settings).unwrap();
+    let admin_channel = settings["admin_channel"]
+        .as_str()
+        .map(|x| check_channels(&slack, &[x.to_string()]).unwrap().remove(0));
+    // dry run ではアカウントを変更せず、実行する予定の操作をSlackに返答する
+    let dry_run = settings["dry_run"].as_bool().unwrap_or(false)
+        || std::env::args().any(|x| x == "--dry-run");
+    if dry_run {
+        info!("dry run: accounts won't be changed");
+    }
+    let archive_directory = settings["archive_directory"]
+        .as_str()
+        .unwrap_or(DEFAULT_ARCHIVE_DIRECTORY)
+        .to_string();
+    let state_directory = settings["state_directory"]
+        .as_str()
+        .unwrap_or(DEFAULT_STATE_DIRECTORY);
+    // dry run ではディレクトリを作成せず、読めるファイルだけを使う
+    if !dry_run {
+        if let Err(e) = prepare_directory(state_directory) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    // restricted_hosts に一致するホストではアカウントの作成に管理者の承認が必要
+    let create_requires_approval = settings["restricted_hosts"]
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .any(|x| glob_match(x.as_str().unwrap(), &local_host_name))
+        })
+        .unwrap_or(false);
+    let admins = settings["admins"]
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .map(|x| x.as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let default_key_mode = KeyMode::from_settings(&settings["key_mode"]).unwrap();
+    let logins_exist = std::path::Path::new(state_directory)
+        .join(LOGINS_FILE)
+        .exists();
+    let key_owner = KeyOwner::from_settings(&settings["key_owner"], &slack).unwrap();
+    let mut roles = Roles::from_settings(&settings["roles"], admins).unwrap();
+    roles.resolve(&slack);
     let mut command_handler = CommandHandler {
-        pic_of_response: hosts[0] == local_host_name,
-        api_token: api_token.clone(),
+        pic_of_response: connection_mode.pic_of_response(&hosts, &local_host_name),
+        interactive: !matches!(
+            connection_mode,
+            ConnectionMode::WebSocket(WebSocketMode::Rtm)
+        ),
+        channels: check_channels(&slack, &channel_names).unwrap(),
+        users: get_users(&slack).unwrap(),
+        slack,
+        backend: Box::new(if dry_run {
+            LinuxBackend::dry_run(local_host_name.clone(), archive_directory)
+        } else {
+            LinuxBackend::new(local_host_name.clone(), archive_directory)
+        }),
         local_host_name,
         hosts,
-        channels: check_channels(&api_token, &channel_names).unwrap(),
-        users: get_users(&api_token).unwrap(),
-        my_id,
-        uri_format,
+        roles,
+        group_policy: GroupPolicy::from_settings(&settings["group_policy"]).unwrap(),
+        create_requires_approval,
+        logins: open_store(state_directory, LOGINS_FILE, dry_run),
+        default_key_mode,
+        key_modes: open_store(state_directory, KEY_MODES_FILE, dry_run),
+        slack_keys: open_store(state_directory, SLACK_KEYS_FILE, dry_run),
+        pending_requests: open_store(state_directory, PENDING_REQUESTS_FILE, dry_run),
+        deactivation_locks: open_store(state_directory, DEACTIVATION_LOCKS_FILE, dry_run),
+        admin_channel,
+        last_reconciled: None,
+        my_id: String::new(),
+        key_sources,
+        key_owner,
+        key_policy: KeyPolicy::from_settings(&settings["key_policy"]).unwrap(),
+        seen_events: SeenEvents::default(),
         last_timestamp: None,
     };
-    command_handler.report_startup().unwrap();
-
-    info!("poling started");
-    // メッセージのポーリング
-    loop {
-        let _ = || -> Result<()> {
-            loop {
-                let message = client.read();
-                let m = message?;
-                trace!("Recv: {:?}", m);
-                use tungstenite::protocol::Message::*;
-                match m {
-                    Text(s) => command_handler.on_text(s)?,
-                    Binary(_) => debug!("get binary"),
-                    Close(_) => debug!("get closure"),
-                    Ping(ping) => {
-                        debug!("Ping");
-                        let pong = Pong(ping);
-                        debug!("Send {:?}", pong);
-                        client.send(pong)?;
-                    }
-                    Pong(_) => debug!("Pong"),
-                    Frame(x) => debug!("Frame({x:?})"),
-                }
-            }
-        }()
-        .map_err(|e| info!("{}", e));
-        let (my_id, new_client) = rtm_setup(&api_token).unwrap();
-        command_handler.my_id = my_id;
-        client = new_client;
-        command_handler.channels = check_channels(&api_token, &channel_names).unwrap();
-        command_handler.users = get_users(&api_token).unwrap();
-        command_handler.handle_messages_while_dead().unwrap();
-        info!("poling restarted");
+    if !logins_exist {
+        command_handler.seed_logins().unwrap();
+    }
+    match connection_mode {
+        ConnectionMode::WebSocket(mode) => {
+            poll_websocket(&mut command_handler, &mode, &channel_names)
+        }
+        ConnectionMode::EventsApi {
+            listen_address,
+            signing_secret,
+        } => serve_events_api(&mut command_handler, &listen_address, signing_secret),
     }
 }
+
+#[cfg(test)]
+mod mock_slack;
+#[cfg(test)]
+mod tests;
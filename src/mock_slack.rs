@@ -0,0 +1,414 @@
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::slack::SlackClient;
+
+/// モックの bot のID
+pub const BOT_ID: &str = "UBOT";
+/// モックのユーザーのIDと表示名
+pub const USER_ID: &str = "UALICE";
+pub const USER_NAME: &str = "alice";
+/// モックのもう1人のユーザーのIDと表示名
+pub const OTHER_USER_ID: &str = "UBOB";
+pub const OTHER_USER_NAME: &str = "bob";
+/// モックの無効化されたユーザーのIDと表示名
+pub const DEACTIVATED_USER_ID: &str = "UCAROL";
+pub const DEACTIVATED_USER_NAME: &str = "carol";
+/// モックの公開チャンネルのIDと名前
+pub const CHANNEL_ID: &str = "CGENERAL";
+pub const CHANNEL_NAME: &str = "general";
+/// bot が参加していないため、 chat.postMessage が not_in_channel で失敗するチャンネルのID
+pub const NOT_JOINED_CHANNEL_ID: &str = "CRANDOM";
+/// モックのユーザーと bot のDMのID
+pub const DM_ID: &str = "DALICE";
+/// モックが返す公開鍵
+pub const PUBLIC_KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDJ6Qw3W8n2AnJqAxQ1C5bfwQ8dVq0gW2Zt1ZK6oZy1Q alice@mock\n";
+/// PUBLIC_KEY の SHA256 のフィンガープリント
+pub const PUBLIC_KEY_FINGERPRINT: &str = "SHA256:U7CRykqvEhD+qs8KsNz8GW+OcDbFZhsB4Hs6llsXenk";
+/// 公開鍵として読み込めない行と、2048ビットのRSAの鍵とDSAの鍵を含む、
+/// "mixed_keys/<ユーザー名>" で PUBLIC_KEY の前に返す内容
+pub const MIXED_KEYS: &str = "<html>
+ssh-rsa not-a-key
+ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCtRfI9OxoR31h/0oA7q2w5jYg0in7tjRTwbT/vcBlmoMOB6I84wMj9hxK4vAdvN4e50XngbA/U9fgTDEI3cw7fr71n+WGWmc/hmIrZ8GwUSgJbQT+KmgIepkin3QaDnrkFtubjB9S+3FFDEZPmw/M5GiuPH/H9QqKXVdTBOpApMc1EfjW4ttj+RC49Q3IE5S2yIhpYAIoFpsRkcVnDJMmFm4EOdm7J0oZjyoKN1fSzsuSwbOYHQceofOQsghgHLow1v5ktyenGFmEudpamzswbeOUQYXMR2KPCzm9EftTVex4v64lBTDQ8ECfE0cOGu8TNYT4w2PFq35G3WEoiZbH1 alice@old
+ssh-dss AAAAB3NzaC1kc3MAAACBAIH5wfZsDzRZ95sXru+6kfyANGi2thCp9/knD064szOo5URt1FUrgva+PtwKHvKk8EvgPbDcJXS9uUBn7f4XUzChHUWaL5eNhxmZnj+kbWdT7BSMtI5zykfqkKjw1muCnmqKxLoFgFl17S+J2UovIKrzxkr3daiSlMLNeJo4AgipAAAAFQDNqY8dMJn99auZJUrpAeNc1H04DQAAAAEDAAAAAQU= alice@dsa
+";
+
+/// USER_ID のメールアドレス
+pub const USER_EMAIL: &str = "alice.smith@example.com";
+/// プロフィールの GitHub のカスタムフィールドのID
+pub const GITHUB_FIELD_ID: &str = "XfGITHUB";
+/// USER_ID のプロフィールの GitHub のカスタムフィールドの値
+pub const USER_GITHUB_NAME: &str = "alice-gh";
+
+/// USER_ID のみが所属するユーザーグループ
+pub const OPERATORS_USERGROUP_ID: &str = "SOPERATORS";
+
+/// chat.postMessage で投稿したメッセージの ts
+pub const POSTED_TS: &str = "1700000000.000200";
+
+/// 記録した API の呼び出し (method, パラメータ)
+type Calls = Arc<Mutex<Vec<(String, serde_json::Value)>>>;
+/// method ごとの、次の呼び出しから順に返すエラーのHTTPステータス
+type Failures = Arc<Mutex<HashMap<String, VecDeque<u16>>>>;
+
+/// テスト用の Slack のモック
+/// Web API の呼び出しを記録し、 WebSocket で用意したイベントを順に送信する
+pub struct MockSlack {
+    server: Arc<tiny_http::Server>,
+    calls: Calls,
+    failures: Failures,
+    websocket_url: String,
+    websocket: Option<std::thread::JoinHandle<Vec<String>>>,
+}
+
+/// request の本文やクエリ文字列をJSONとして取得
+fn parse_params(request: &mut tiny_http::Request) -> serde_json::Value {
+    let mut body = String::new();
+    let _ = request.as_reader().read_to_string(&mut body);
+    if let Ok(v) = serde_json::from_str(&body) {
+        return v;
+    }
+    let query = request.url().split_once('?').map_or("", |(_, q)| q);
+    let form: HashMap<String, String> = serde_urlencoded::from_str(&body)
+        .ok()
+        .filter(|x: &HashMap<String, String>| !x.is_empty())
+        .or_else(|| serde_urlencoded::from_str(query).ok())
+        .unwrap_or_default();
+    json!(form)
+}
+
+/// method の呼び出しに対する応答
+fn respond(method: &str, params: &serde_json::Value, websocket_url: &str) -> serde_json::Value {
+    match method {
+        "auth.test" => json!({ "ok": true, "user_id": BOT_ID }),
+        "rtm.connect" => json!({ "ok": true, "url": websocket_url, "self": { "id": BOT_ID } }),
+        "apps.connections.open" => json!({ "ok": true, "url": websocket_url }),
+        "users.list" => json!({
+            "ok": true,
+            "members": [
+                { "id": BOT_ID, "profile": { "display_name_normalized": "sacana" } },
+                { "id": USER_ID, "profile": { "display_name_normalized": USER_NAME } },
+                { "id": OTHER_USER_ID, "profile": { "display_name_normalized": OTHER_USER_NAME } },
+                {
+                    "id": DEACTIVATED_USER_ID,
+                    "deleted": true,
+                    "profile": { "display_name_normalized": DEACTIVATED_USER_NAME },
+                },
+            ],
+        }),
+        "users.conversations" => json!({
+            "ok": true,
+            "channels": [{ "id": CHANNEL_ID, "name": CHANNEL_NAME }],
+            "response_metadata": { "next_cursor": "" },
+        }),
+        "conversations.info" => {
+            let is_im = params["channel"]
+                .as_str()
+                .is_some_and(|x| x.starts_with('D'));
+            json!({ "ok": true, "channel": { "is_channel": !is_im, "is_im": is_im } })
+        }
+        "usergroups.users.list" if params["usergroup"] == OPERATORS_USERGROUP_ID => {
+            json!({ "ok": true, "users": [USER_ID] })
+        }
+        "usergroups.users.list" => json!({ "ok": false, "error": "no_such_subteam" }),
+        "users.profile.get" if params["user"] == USER_ID => json!({
+            "ok": true,
+            "profile": {
+                "email": USER_EMAIL,
+                "fields": { GITHUB_FIELD_ID: { "value": USER_GITHUB_NAME, "alt": "" } },
+            },
+        }),
+        "users.profile.get" => json!({ "ok": true, "profile": { "fields": {} } }),
+        "team.profile.get" => json!({
+            "ok": true,
+            "profile": { "fields": [{ "id": GITHUB_FIELD_ID, "label": "GitHub" }] },
+        }),
+        "conversations.history" => json!({ "ok": true, "messages": [] }),
+        "chat.postMessage" if params["channel"] == NOT_JOINED_CHANNEL_ID => {
+            json!({ "ok": false, "error": "not_in_channel" })
+        }
+        "chat.postMessage" => json!({ "ok": true, "ts": POSTED_TS }),
+        _ => json!({ "ok": true }),
+    }
+}
+
+impl MockSlack {
+    /// モックを起動し、 WebSocket の接続に対して events を順に送信する
+    pub fn start(events: Vec<serde_json::Value>) -> Self {
+        Self::start_with(|_| events)
+    }
+
+    /// モックを起動し、 WebSocket の接続に対して events で作成したイベントを順に送信する
+    /// response_url などモックのURLを含むイベントを送る場合に使う
+    pub fn start_with(events: impl FnOnce(&Self) -> Vec<serde_json::Value>) -> Self {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let websocket_url = format!("ws://{}/", listener.local_addr().unwrap());
+        let calls = Calls::default();
+        let failures = Failures::default();
+
+        {
+            let server = server.clone();
+            let calls = calls.clone();
+            let failures = failures.clone();
+            let websocket_url = websocket_url.clone();
+            std::thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let method = request
+                        .url()
+                        .split('?')
+                        .next()
+                        .unwrap()
+                        .trim_start_matches("/api")
+                        .trim_start_matches('/')
+                        .to_string();
+                    let params = parse_params(&mut request);
+                    let failure = failures
+                        .lock()
+                        .unwrap()
+                        .get_mut(&method)
+                        .and_then(VecDeque::pop_front);
+                    let response = if let Some(status) = failure {
+                        let response =
+                            tiny_http::Response::from_string("").with_status_code(status);
+                        if status == 429 {
+                            response
+                                .with_header("Retry-After: 1".parse::<tiny_http::Header>().unwrap())
+                        } else {
+                            response
+                        }
+                    } else if method.starts_with("keys/") || method.starts_with("files/") {
+                        tiny_http::Response::from_string(PUBLIC_KEY)
+                    } else if method.starts_with("mixed_keys/") {
+                        tiny_http::Response::from_string(MIXED_KEYS.to_string() + PUBLIC_KEY)
+                    } else if method.starts_with("html/") {
+                        tiny_http::Response::from_string(
+                            "<html>\n<body>Not Found</body>\n</html>\n",
+                        )
+                    } else {
+                        tiny_http::Response::from_string(
+                            respond(&method, &params, &websocket_url).to_string(),
+                        )
+                        .with_header(
+                            "Content-Type: application/json"
+                                .parse::<tiny_http::Header>()
+                                .unwrap(),
+                        )
+                    };
+                    calls.lock().unwrap().push((method, params));
+                    let _ = request.respond(response);
+                }
+            });
+        }
+
+        let mut mock = MockSlack {
+            server,
+            calls,
+            failures,
+            websocket_url,
+            websocket: None,
+        };
+        let events = events(&mock);
+        mock.websocket = Some(std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            for event in events {
+                ws.send(tungstenite::Message::Text(event.to_string()))
+                    .unwrap();
+            }
+            ws.close(None).unwrap();
+            // 切断されるまでに bot から送られたテキストを返す
+            let mut received = Vec::new();
+            while let Ok(message) = ws.read() {
+                if let tungstenite::Message::Text(text) = message {
+                    received.push(text);
+                }
+            }
+            received
+        }));
+        mock
+    }
+
+    fn base_url(&self) -> String {
+        format!("http://{}", self.server.server_addr().to_ip().unwrap())
+    }
+
+    /// モックに接続する SlackClient
+    /// テストが遅くならないよう、 rate limit のための呼び出しの間隔は空けない
+    pub fn client(&self) -> SlackClient {
+        self.paced_client().with_min_intervals(
+            std::iter::once(("*".to_string(), std::time::Duration::ZERO)).collect(),
+        )
+    }
+
+    /// 実際の Slack と同じ間隔を空けてモックを呼び出す SlackClient
+    pub fn paced_client(&self) -> SlackClient {
+        SlackClient::new(
+            "xoxb-mock".to_string(),
+            self.base_url() + "/api",
+            Some(self.websocket_url.clone()),
+            self.base_url() + "/files/",
+        )
+    }
+
+    /// 公開鍵を PUBLIC_KEY として返す public_key_uri_format
+    /// 公開鍵の取得は "keys/<ユーザー名>" の呼び出しとして記録される
+    pub fn public_key_uri_format(&self) -> String {
+        self.base_url() + "/keys/{}"
+    }
+
+    /// Slackにアップロードされたファイルとして PUBLIC_KEY を返す url_private_download
+    /// ファイルの取得は "files/<name>" の呼び出しとして記録される
+    pub fn file_url(&self, name: &str) -> String {
+        format!("{}/files/{}", self.base_url(), name)
+    }
+
+    /// 公開鍵の前に MIXED_KEYS を返す public_key_uri_format
+    pub fn mixed_keys_uri_format(&self) -> String {
+        self.base_url() + "/mixed_keys/{}"
+    }
+
+    /// 公開鍵の代わりにHTMLのページを返す public_key_uri_format
+    pub fn html_uri_format(&self) -> String {
+        self.base_url() + "/html/{}"
+    }
+
+    /// slash command などの response_url として使うURL
+    /// このURLへの投稿は "response" の呼び出しとして記録される
+    pub fn response_url(&self) -> String {
+        self.base_url() + "/response"
+    }
+
+    /// method の次の呼び出しから順に statuses のHTTPステータスで失敗させる
+    /// 429 の応答には `Retry-After: 1` を付ける
+    pub fn fail(&self, method: &str, statuses: &[u16]) {
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_default()
+            .extend(statuses);
+    }
+
+    /// method の呼び出しのパラメータを呼び出された順に取得
+    pub fn calls(&self, method: &str) -> Vec<serde_json::Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    /// WebSocket の切断を待ち、 bot から送られたテキストを取得
+    pub fn websocket_messages(&mut self) -> Vec<serde_json::Value> {
+        self.websocket
+            .take()
+            .map(|x| x.join().unwrap())
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect()
+    }
+}
+
+impl Drop for MockSlack {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+/// channel でのユーザーの発言のイベント
+pub fn message(channel: &str, text: &str, ts: &str) -> serde_json::Value {
+    json!({
+        "type": "message",
+        "channel": channel,
+        "user": USER_ID,
+        "text": text,
+        "ts": ts,
+    })
+}
+
+/// Socket Mode で event を包んだメッセージ
+pub fn events_api_envelope(envelope_id: &str, event: serde_json::Value) -> serde_json::Value {
+    json!({
+        "type": "events_api",
+        "envelope_id": envelope_id,
+        "payload": { "type": "event_callback", "event": event },
+    })
+}
+
+/// Socket Mode で slash command を包んだメッセージ
+pub fn slash_command_envelope(
+    envelope_id: &str,
+    channel: &str,
+    text: &str,
+    response_url: &str,
+) -> serde_json::Value {
+    json!({
+        "type": "slash_commands",
+        "envelope_id": envelope_id,
+        "payload": {
+            "command": "/sacana",
+            "text": text,
+            "user_id": USER_ID,
+            "channel_id": channel,
+            "response_url": response_url,
+            "trigger_id": "trigger",
+        },
+    })
+}
+
+/// Socket Mode で user が message_ts の投稿の action_id のボタンを押した操作を包んだメッセージ
+pub fn button_envelope(
+    envelope_id: &str,
+    user: &str,
+    action_id: &str,
+    message_ts: &str,
+    response_url: &str,
+) -> serde_json::Value {
+    json!({
+        "type": "interactive",
+        "envelope_id": envelope_id,
+        "payload": {
+            "type": "block_actions",
+            "user": { "id": user },
+            "container": { "type": "message", "message_ts": message_ts },
+            "response_url": response_url,
+            "trigger_id": "trigger",
+            "actions": [{ "action_id": action_id }],
+        },
+    })
+}
+
+/// Socket Mode で user が host へのアカウント申請フォームを送信した操作を包んだメッセージ
+pub fn view_submission_envelope(
+    envelope_id: &str,
+    user: &str,
+    host: &str,
+    login: &str,
+    groups: &[&str],
+) -> serde_json::Value {
+    let groups: Vec<_> = groups.iter().map(|x| json!({ "value": x })).collect();
+    json!({
+        "type": "interactive",
+        "envelope_id": envelope_id,
+        "payload": {
+            "type": "view_submission",
+            "user": { "id": user },
+            "view": {
+                "callback_id": "sacana_request_form",
+                "private_metadata": host,
+                "state": {
+                    "values": {
+                        "sacana_login": { "sacana_login": { "value": login } },
+                        "sacana_groups": { "sacana_groups": { "selected_options": groups } },
+                    },
+                },
+            },
+        },
+    })
+}
@@ -0,0 +1,138 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::account_backend::MIN_USER_GID;
+use crate::runtime_error::Result;
+
+/// グループへの参加の可否
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupAccess {
+    /// 誰でも参加できる
+    Free,
+    /// 管理者の承認が必要
+    Approval,
+    /// 参加できない
+    Forbidden,
+}
+
+/// `*` と `?` を含む pattern が text 全体に一致するかどうか
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // 最後に現れた `*` の位置と、その `*` が一致させ始めた text の位置
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&x| x == b'*')
+}
+
+/// group_policy で指定しない場合に参加できない、root と同等の権限を持つグループ
+const PRIVILEGED_FORBIDDEN_GROUPS: &[&str] = &["root", "shadow", "disk", "kmem"];
+/// group_policy で指定しない場合に管理者の承認が必要な、管理者の権限を得られるグループ
+const PRIVILEGED_APPROVAL_GROUPS: &[&str] = &[
+    "sudo",
+    "wheel",
+    "admin",
+    "adm",
+    "docker",
+    "lxd",
+    "libvirt",
+    "staff",
+    "sys",
+    "systemd-journal",
+    "tty",
+];
+
+/// グループ名のパターンごとの参加の可否
+#[derive(Deserialize, Default, Debug)]
+struct GroupRules {
+    #[serde(default)]
+    free: Vec<String>,
+    #[serde(default)]
+    approval: Vec<String>,
+    #[serde(default)]
+    forbidden: Vec<String>,
+    /// どのパターンにも一致しないグループの参加の可否
+    default: Option<GroupAccess>,
+}
+
+impl GroupRules {
+    /// group に一致するパターンの参加の可否(forbidden, approval, free の順に優先)
+    fn matching(&self, group: &str) -> Option<GroupAccess> {
+        [
+            (&self.forbidden, GroupAccess::Forbidden),
+            (&self.approval, GroupAccess::Approval),
+            (&self.free, GroupAccess::Free),
+        ]
+        .iter()
+        .find(|(patterns, _)| patterns.iter().any(|x| glob_match(x, group)))
+        .map(|(_, access)| *access)
+    }
+}
+
+/// 設定ファイルの group_policy
+/// ホストごとの設定は全体の設定より優先する
+#[derive(Deserialize, Default, Debug)]
+pub struct GroupPolicy {
+    #[serde(flatten)]
+    rules: GroupRules,
+    #[serde(default)]
+    hosts: HashMap<String, GroupRules>,
+}
+
+impl GroupPolicy {
+    /// 設定ファイルの group_policy を読み込む(記述が無い場合は特権的なグループ以外に参加できる)
+    pub fn from_settings(v: &serde_json::Value) -> Result<Self> {
+        if v.is_null() {
+            return Ok(GroupPolicy::default());
+        }
+        Ok(GroupPolicy::deserialize(v)?)
+    }
+
+    /// host で GID が gid の group に参加できるかどうか
+    /// どのパターンにも一致しない特権的なグループは default によらず組み込みの設定に従い、
+    /// システムのグループ(GIDが MIN_USER_GID 未満)は default が free でも承認が必要
+    pub fn access(&self, host: &str, group: &str, gid: Option<u32>) -> GroupAccess {
+        let host_rules = self.hosts.get(host);
+        let matched = host_rules
+            .and_then(|x| x.matching(group))
+            .or_else(|| self.rules.matching(group))
+            .or_else(|| privileged_access(group));
+        if let Some(access) = matched {
+            return access;
+        }
+        let default = host_rules
+            .and_then(|x| x.default)
+            .or(self.rules.default)
+            .unwrap_or(GroupAccess::Free);
+        match gid {
+            Some(x) if x < MIN_USER_GID && default == GroupAccess::Free => GroupAccess::Approval,
+            _ => default,
+        }
+    }
+}
+
+/// 組み込みの設定での特権的なグループの参加の可否
+fn privileged_access(group: &str) -> Option<GroupAccess> {
+    if PRIVILEGED_FORBIDDEN_GROUPS.contains(&group) {
+        Some(GroupAccess::Forbidden)
+    } else if PRIVILEGED_APPROVAL_GROUPS.contains(&group) {
+        Some(GroupAccess::Approval)
+    } else {
+        None
+    }
+}
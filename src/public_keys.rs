@@ -0,0 +1,326 @@
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::key_policy::KeyPolicy;
+use crate::runtime_error::{as_str, Result};
+use crate::slack::SlackClient;
+use crate::ssh_key::KeySet;
+#[derive(thiserror::Error, Debug)]
+pub enum PublicKeyError {
+    #[error("get public key from {0} failed: {1}")]
+    Get(String, String),
+    #[error("there is no profile field named {0}")]
+    NoProfileField(String),
+    #[error("the {1} of <@{0}> to fetch public keys is empty")]
+    EmptyKeyOwner(String, String),
+    #[error("no valid public key is found in {0}{1}")]
+    NoValidKeys(String, String),
+    #[error("`{0}` can't be used to find public keys")]
+    InvalidKeyOwner(String),
+    #[error("file `{0}` is too large for a public key")]
+    FileTooLarge(String),
+    #[error("unknown key_owner `{0}`")]
+    UnknownKeyOwner(String),
+    #[error("public_key_uri_format or key_sources is required")]
+    NoKeySources,
+}
+
+/// 公開鍵の取得元
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// `{}` を持ち主の名前に置換したURI(GitHub, GitLab, Gitea の `/{}.keys` など)
+    Uri(String),
+    /// ディレクトリ内の `<持ち主の名前>.pub`
+    Directory(String),
+    /// LDAPのエントリの属性
+    Ldap(LdapSource),
+}
+
+/// ldapsearch で公開鍵を取得するLDAPのエントリ
+#[derive(Deserialize, Debug, Clone)]
+pub struct LdapSource {
+    uri: String,
+    base: String,
+    /// `{}` を持ち主の名前に置換した検索フィルター
+    #[serde(default = "LdapSource::default_filter")]
+    filter: String,
+    #[serde(default = "LdapSource::default_attribute")]
+    attribute: String,
+    bind_dn: Option<String>,
+    /// bind_dn のパスワードを記述したファイル
+    password_file: Option<String>,
+}
+
+impl LdapSource {
+    fn default_filter() -> String {
+        "(uid={})".to_string()
+    }
+
+    fn default_attribute() -> String {
+        "sshPublicKey".to_string()
+    }
+
+    /// key_owner を検索するフィルター(フィルターの特殊文字はエスケープする)
+    fn filter(&self, key_owner: &str) -> String {
+        let mut escaped = String::new();
+        for c in key_owner.chars() {
+            match c {
+                '*' | '(' | ')' | '\\' | '\0' => escaped += &format!("\\{:02x}", c as u32),
+                c => escaped.push(c),
+            }
+        }
+        self.filter.replace("{}", &escaped)
+    }
+
+    /// ldapsearch の出力(LDIF)から attribute の値を取り出す
+    fn values(&self, ldif: &str) -> Vec<String> {
+        // 折り返された行を元に戻す
+        let mut lines: Vec<String> = Vec::new();
+        for line in ldif.lines() {
+            match (line.strip_prefix(' '), lines.last_mut()) {
+                (Some(rest), Some(last)) => *last += rest,
+                _ => lines.push(line.to_string()),
+            }
+        }
+        lines
+            .iter()
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                if !name.eq_ignore_ascii_case(&self.attribute) {
+                    return None;
+                }
+                match value.strip_prefix(':') {
+                    Some(encoded) => base64::engine::general_purpose::STANDARD
+                        .decode(encoded.trim())
+                        .ok()
+                        .and_then(|x| String::from_utf8(x).ok()),
+                    None => Some(value.trim().to_string()),
+                }
+            })
+            .collect()
+    }
+
+    /// key_owner のエントリの attribute の値を改行区切りで取得
+    fn fetch(&self, key_owner: &str) -> Result<String> {
+        let filter = self.filter(key_owner);
+        let mut args = vec![
+            "-x",
+            "-LLL",
+            "-o",
+            "ldif-wrap=no",
+            "-H",
+            &self.uri,
+            "-b",
+            &self.base,
+        ];
+        if let Some(x) = &self.bind_dn {
+            args.extend(["-D", x.as_str()].iter());
+        }
+        if let Some(x) = &self.password_file {
+            args.extend(["-y", x.as_str()].iter());
+        }
+        args.extend([filter.as_str(), self.attribute.as_str()].iter());
+        let output = std::process::Command::new("ldapsearch")
+            .args(&args)
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(PublicKeyError::Get(self.location(key_owner), stderr).into());
+        }
+        Ok(self
+            .values(&String::from_utf8_lossy(&output.stdout))
+            .join("\n"))
+    }
+
+    /// key_owner のエントリを指すLDAPのURL
+    fn location(&self, key_owner: &str) -> String {
+        format!(
+            "{}/{}?{}?sub?{}",
+            self.uri.trim_end_matches('/'),
+            self.base,
+            self.attribute,
+            self.filter(key_owner)
+        )
+    }
+}
+
+impl KeySource {
+    /// key_owner の公開鍵の取得元を示す文字列
+    pub fn location(&self, key_owner: &str) -> String {
+        match self {
+            KeySource::Uri(x) => x.replace("{}", key_owner),
+            KeySource::Directory(x) => std::path::Path::new(x)
+                .join(format!("{}.pub", key_owner))
+                .display()
+                .to_string(),
+            KeySource::Ldap(x) => x.location(key_owner),
+        }
+    }
+
+    /// key_owner の公開鍵を取得
+    fn fetch(&self, key_owner: &str) -> Result<String> {
+        match self {
+            KeySource::Uri(_) => {
+                let uri = self.location(key_owner);
+                let response = reqwest::blocking::get(&uri)?;
+                if response.status().is_success() {
+                    Ok(response.text()?)
+                } else {
+                    Err(PublicKeyError::Get(uri, response.text()?).into())
+                }
+            }
+            KeySource::Directory(_) => {
+                // 他のファイルを読み込まないよう、パスの区切りや `.` で始まる名前は使わない
+                if key_owner.contains('/') || key_owner.starts_with('.') {
+                    return Err(PublicKeyError::InvalidKeyOwner(key_owner.to_string()).into());
+                }
+                let path = self.location(key_owner);
+                std::fs::read_to_string(&path)
+                    .map_err(|e| PublicKeyError::Get(path, e.to_string()).into())
+            }
+            KeySource::Ldap(x) => x.fetch(key_owner),
+        }
+    }
+}
+
+/// 複数の取得元からの公開鍵の集め方
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceMode {
+    /// 鍵を1つ以上受け付けた最初の取得元の鍵のみを使う
+    FirstMatch,
+    /// 全ての取得元の鍵を使う
+    Union,
+}
+
+/// 優先度の高い順に並べた公開鍵の取得元
+pub struct KeySources {
+    sources: Vec<KeySource>,
+    mode: SourceMode,
+}
+
+impl KeySources {
+    /// uri_format のみを取得元とする
+    pub fn from_uri_format(uri_format: &str) -> Self {
+        KeySources {
+            sources: vec![KeySource::Uri(uri_format.to_string())],
+            mode: SourceMode::FirstMatch,
+        }
+    }
+
+    /// 設定ファイルの key_sources と key_source_mode を読み込む
+    /// key_sources の記述が無い場合は public_key_uri_format を取得元とする
+    pub fn from_settings(
+        sources: &serde_json::Value,
+        mode: &serde_json::Value,
+        uri_format: Option<&str>,
+    ) -> Result<Self> {
+        if sources.is_null() {
+            let uri_format = uri_format.ok_or(PublicKeyError::NoKeySources)?;
+            return Ok(KeySources::from_uri_format(uri_format));
+        }
+        let mode = if mode.is_null() {
+            SourceMode::FirstMatch
+        } else {
+            SourceMode::deserialize(mode)?
+        };
+        Ok(KeySources {
+            sources: Vec::deserialize(sources)?,
+            mode,
+        })
+    }
+
+    /// key_owner の公開鍵の取得元の一覧
+    pub fn describe(&self, key_owner: &str) -> String {
+        self.sources
+            .iter()
+            .map(|x| format!("`{}`", x.location(key_owner)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// 取得元から key_owner の公開鍵を取得
+    /// 公開鍵として読み込めない行と policy に従わない鍵は取り除き、1つも残らない場合はエラーとする
+    pub fn fetch(&self, key_owner: &str, policy: &KeyPolicy) -> Result<KeySet> {
+        let mut keys = KeySet::default();
+        for source in &self.sources {
+            let location = source.location(key_owner);
+            match source.fetch(key_owner) {
+                Ok(text) => keys.append(KeySet::parse(&text, policy, &location)),
+                // 取得元が1つの場合はそのエラーをそのまま返す
+                Err(e) if self.sources.len() == 1 => return Err(e),
+                Err(e) => keys.failed.push(e.to_string()),
+            }
+            if self.mode == SourceMode::FirstMatch && !keys.accepted.is_empty() {
+                break;
+            }
+        }
+        if keys.accepted.is_empty() {
+            let details = keys.rejection_text();
+            let details = if details.is_empty() {
+                details
+            } else {
+                format!("\n```\n{}\n```", details)
+            };
+            return Err(PublicKeyError::NoValidKeys(self.describe(key_owner), details).into());
+        }
+        Ok(keys)
+    }
+}
+
+/// 公開鍵の取得元の `{}` に入れる、Slackユーザーの公開鍵の持ち主としての名前
+pub enum KeyOwner {
+    /// Slackの表示名
+    DisplayName,
+    /// メールアドレスの `@` より前の部分
+    Email,
+    /// プロフィールのカスタムフィールド(id はフィールドのID、 label は表示名)
+    ProfileField { id: String, label: String },
+}
+
+impl KeyOwner {
+    /// 設定ファイルの key_owner を読み込む
+    /// カスタムフィールドは表示名またはIDで指定できる
+    pub fn from_settings(v: &serde_json::Value, slack: &SlackClient) -> Result<Self> {
+        if let Some(field) = v["profile_field"].as_str() {
+            for x in slack.team_profile_get()? {
+                let (id, label) = (as_str(&x["id"])?, as_str(&x["label"])?);
+                if field == id || field == label {
+                    return Ok(KeyOwner::ProfileField {
+                        id: id.to_string(),
+                        label: label.to_string(),
+                    });
+                }
+            }
+            return Err(PublicKeyError::NoProfileField(field.to_string()).into());
+        }
+        match v.as_str() {
+            None | Some("display_name") => Ok(KeyOwner::DisplayName),
+            Some("email") => Ok(KeyOwner::Email),
+            Some(x) => Err(PublicKeyError::UnknownKeyOwner(x.to_string()).into()),
+        }
+    }
+
+    /// user_id のユーザーの公開鍵の持ち主としての名前
+    pub fn name(&self, slack: &SlackClient, user_id: &str, display_name: &str) -> Result<String> {
+        let (name, source) = match self {
+            KeyOwner::DisplayName => (display_name.to_string(), "display name".to_string()),
+            KeyOwner::Email => {
+                let profile = slack.users_profile_get(user_id)?;
+                let email = profile["email"].as_str().unwrap_or_default();
+                let name = email.split('@').next().unwrap_or_default();
+                (name.to_string(), "email".to_string())
+            }
+            KeyOwner::ProfileField { id, label } => {
+                let profile = slack.users_profile_get(user_id)?;
+                let value = profile["fields"][id]["value"].as_str().unwrap_or_default();
+                (value.trim().to_string(), format!("{} profile field", label))
+            }
+        };
+        if name.is_empty() {
+            return Err(PublicKeyError::EmptyKeyOwner(user_id.to_string(), source).into());
+        }
+        Ok(name)
+    }
+}
@@ -0,0 +1,118 @@
+use log::error;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::runtime_error::Result;
+use crate::slack::SlackClient;
+
+/// コマンドを実行できる権限(後のものほど強い)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// 自分のアカウントを管理できる
+    User,
+    /// 他のユーザーのアカウントを lock / unlock できる
+    Operator,
+    /// 他のユーザーのアカウントの削除や申請の承認ができる
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::User => "user",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// ロールを持つユーザーのIDと、Slackのユーザーグループ(`S` で始まるID)
+#[derive(Deserialize, Default, Debug)]
+struct Members {
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    usergroups: Vec<String>,
+}
+
+/// 設定ファイルの roles
+#[derive(Deserialize, Default, Debug)]
+struct RoleSettings {
+    #[serde(default)]
+    admin: Members,
+    #[serde(default)]
+    operator: Members,
+    /// 記述が無い場合は全てのユーザーが user のロールを持つ
+    user: Option<Members>,
+}
+
+/// ユーザーごとのロール
+#[derive(Default)]
+pub struct Roles {
+    settings: RoleSettings,
+    /// resolve で解決したユーザーのIDごとのロール
+    resolved: HashMap<String, Role>,
+}
+
+impl Roles {
+    /// 設定ファイルの roles を読み込む
+    /// admins は admin のロールを持つユーザーのIDとして扱う
+    pub fn from_settings(v: &serde_json::Value, admins: Vec<String>) -> Result<Self> {
+        let mut settings = if v.is_null() {
+            RoleSettings::default()
+        } else {
+            RoleSettings::deserialize(v)?
+        };
+        settings.admin.users.extend(admins);
+        Ok(Roles {
+            settings,
+            resolved: HashMap::new(),
+        })
+    }
+
+    /// ユーザーグループのメンバーを取得し、ユーザーごとのロールを解決する
+    /// メンバーを取得できなかったユーザーグループは記録して無視し、 users の記述のみを反映する
+    pub fn resolve(&mut self, slack: &SlackClient) {
+        let mut resolved = HashMap::new();
+        let roles = [
+            (Role::User, self.settings.user.as_ref()),
+            (Role::Operator, Some(&self.settings.operator)),
+            (Role::Admin, Some(&self.settings.admin)),
+        ];
+        let roles = roles
+            .iter()
+            .filter_map(|(role, members)| members.map(|x| (*role, x)))
+            .collect::<Vec<_>>();
+        // 複数のロールを持つユーザーは強い方のロールになる
+        let mut grant = |user: String, role: Role| {
+            let x = resolved.entry(user).or_insert(role);
+            *x = (*x).max(role);
+        };
+        for (role, members) in &roles {
+            for user in &members.users {
+                grant(user.clone(), *role);
+            }
+        }
+        for (role, members) in &roles {
+            for usergroup in &members.usergroups {
+                match slack.usergroups_users_list(usergroup) {
+                    Ok(users) => users.into_iter().for_each(|x| grant(x, *role)),
+                    Err(e) => error!(
+                        "failed to get members of usergroup {} for the {} role: {}",
+                        usergroup, role, e
+                    ),
+                }
+            }
+        }
+        self.resolved = resolved;
+    }
+
+    /// user_id のロール(どのロールも持たない場合は None)
+    pub fn role(&self, user_id: &str) -> Option<Role> {
+        match self.resolved.get(user_id) {
+            Some(x) => Some(*x),
+            None if self.settings.user.is_none() => Some(Role::User),
+            None => None,
+        }
+    }
+}
@@ -12,11 +12,35 @@ pub enum Error {
     #[error(transparent)]
     Slack(#[from] crate::slack::SlackError),
     #[error(transparent)]
+    EventsApi(#[from] crate::events_api::EventsApiError),
+    #[error(transparent)]
     Linux(#[from] crate::linux_user_manage::LinuxError),
+    #[error(transparent)]
+    PublicKey(#[from] crate::public_keys::PublicKeyError),
+    #[error(transparent)]
+    UserName(#[from] crate::user_name::UserNameError),
     #[error("Internal error: path -> str conversion failed")]
     PathToStr,
     #[error("there is no channel named {0}")]
     NoChannel(String),
+    #[error("approval by an admin is required, but admin_channel isn't configured")]
+    NoAdminChannel,
+    #[error("login name `{0}` is bound to another Slack user")]
+    LoginBound(String),
+    #[error("your Slack account is bound to login name `{0}`")]
+    LoginMismatch(String),
+    #[error("account `{0}` already exists, but isn't bound to your Slack account. ask an admin to `map` it")]
+    LoginUnbound(String),
+    #[error("account `{0}` is locked on {1}. ask an operator to unlock it")]
+    AccountLocked(String, String),
+    #[error("unknown key_mode `{0}`")]
+    UnknownKeyMode(String),
+    #[error("unknown connection_mode `{0}`")]
+    UnknownConnectionMode(String),
+    #[error("{0} is required for connection_mode `{1}`")]
+    MissingSetting(&'static str, &'static str),
+    #[error("state_directory `{0}` can't be written ({1}). run sacana as root, or set state_directory to a directory this user can write")]
+    StateDirectory(String, std::io::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error("Serde error: {0}")]
@@ -26,12 +50,19 @@ pub enum Error {
     #[error("chrono parse error: {0}")]
     ChronoParse(#[from] chrono::ParseError),
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tungstenite::Error),
+    WebSocket(Box<tungstenite::Error>),
     #[error("goodbye event was caught. try to reconnect...")]
     CaughtGoodBye,
+    #[error("disconnect message was caught ({0}). try to reconnect...")]
+    CaughtDisconnect(String),
     #[error("receive non-event object on RTM")]
     NonEvent,
 }
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
 pub type Result<T> = std::result::Result<T, Error>;
 /// JSON値を文字列として取得
 pub fn as_str(v: &serde_json::Value) -> Result<&str> {
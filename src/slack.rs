@@ -1,113 +1,165 @@
 use log::{debug, warn};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::runtime_error::{as_array, Result};
+use crate::runtime_error::{Error, Result};
 #[derive(thiserror::Error, Debug)]
 pub enum SlackError {
     #[error("connecting to slack.com failed")]
     ConnectFailed,
     #[error("invalid conversation object")]
     InvalidConversation,
-    #[error("API error: users.conversations failed \"{0}\"")]
-    UsersConversations(String),
+    #[error("API error: {method} failed \"{error}\"")]
+    Api { method: &'static str, error: String },
+    #[error("HTTP error: {method} returned {status}")]
+    Http { method: &'static str, status: u16 },
+    #[error("file URL {0} isn't on Slack")]
+    UntrustedFileUrl(String),
+    #[error("file {0} is larger than {1} bytes")]
+    FileTooLarge(String, u64),
+    #[error("slack_min_intervals of `{0}` isn't milliseconds")]
+    InvalidMinInterval(String),
 }
 
-fn post(api_token: &str, body: HashMap<&str, &str>, uri: &str) -> Result<()> {
-    debug!("{:?}", body);
-    let client = reqwest::blocking::Client::new()
-        .post(uri)
-        .header(
-            reqwest::header::CONTENT_TYPE,
-            "application/json; charset=utf-8",
-        )
-        .header(
-            reqwest::header::AUTHORIZATION,
-            "Bearer ".to_owned() + api_token,
-        )
-        .body(serde_json::to_vec(&body)?)
-        .send()?;
-    let text = client.text()?;
-    let v: serde_json::Value = text.parse()?;
-    debug!("{}", serde_json::to_string_pretty(&v)?);
-    Ok(())
+/// 一時的なエラーの場合に API を呼び出す最大の回数
+const MAX_ATTEMPTS: u32 = 5;
+/// 再送までの待ち時間の初期値
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// 再送までの待ち時間の最大値
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Slack Web API のURIの既定値
+pub const DEFAULT_API_BASE_URL: &str = "https://slack.com/api";
+/// Slackにアップロードされたファイルの url_private の接頭辞の既定値
+pub const DEFAULT_FILES_URL: &str = "https://files.slack.com/";
+
+/// method の rate limit の段階に合わせた呼び出しの最小間隔
+/// https://api.slack.com/docs/rate-limits
+fn min_interval(method: &str) -> Duration {
+    match method {
+        // Tier 1 (1回/分以上)
+        "rtm.connect" | "apps.connections.open" => Duration::from_secs(60),
+        // Tier 2 (20回/分以上)
+        "users.list" | "usergroups.users.list" => Duration::from_secs(3),
+        // Tier 3 (50回/分以上)
+        "conversations.info"
+        | "conversations.history"
+        | "users.conversations"
+        | "reactions.add"
+        | "chat.update"
+        | "team.profile.get" => Duration::from_millis(1200),
+        // Special (チャンネルごとに1回/秒程度、 per_channel で区別する)
+        "chat.postMessage" => Duration::from_secs(1),
+        // Tier 4 (100回/分以上)
+        _ => Duration::from_millis(600),
+    }
 }
 
-/// channel に text を投稿する
-pub fn post_message(api_token: &str, channel: &str, text: &str) -> Result<()> {
-    let mut body = HashMap::new();
-    body.insert("channel", channel);
-    body.insert("text", text);
-    body.insert("as_user", "true");
-    post(api_token, body, "https://slack.com/api/chat.postMessage")
+/// 設定の slack_min_intervals から、 method ごとの呼び出しの最小間隔(ミリ秒)の上書きを取得
+pub fn min_intervals_from_settings(v: &serde_json::Value) -> Result<HashMap<String, Duration>> {
+    let intervals = match v.as_object() {
+        Some(x) => x,
+        None => return Ok(HashMap::new()),
+    };
+    intervals
+        .iter()
+        .map(|(method, x)| {
+            let millis = x
+                .as_u64()
+                .ok_or_else(|| SlackError::InvalidMinInterval(method.clone()))?;
+            Ok((method.clone(), Duration::from_millis(millis)))
+        })
+        .collect()
 }
 
-/// channel の ts のスレッドに text を投稿する
-pub fn post_message_to_thread(api_token: &str, channel: &str, ts: &str, text: &str) -> Result<()> {
-    let mut body = HashMap::new();
-    body.insert("channel", channel);
-    body.insert("text", text);
-    body.insert("as_user", "true");
-    body.insert("thread_ts", ts);
-    post(api_token, body, "https://slack.com/api/chat.postMessage")
+/// 呼び出しの間隔をチャンネルごとに数える method
+fn per_channel(method: &str) -> bool {
+    method == "chat.postMessage"
 }
 
-/// channel に user のみに見える attachments を投稿する
-pub fn post_ephemeral_attachments(
-    api_token: &str,
-    channel: &str,
-    user: &str,
-    attachments: serde_json::Value,
-) -> Result<()> {
-    let attachments_str = attachments.to_string();
-    let mut body = HashMap::new();
-    body.insert("channel", channel);
-    body.insert("attachments", &attachments_str);
-    body.insert("user", user);
-    body.insert("as_user", "true");
-    post(api_token, body, "https://slack.com/api/chat.postEphemeral")
+/// 処理されたかどうか分からない場合に再送すると、投稿などが重複してしまう method
+/// これらは処理されていないことが確実な接続の失敗と rate limit の場合のみ再送する
+fn is_non_idempotent(method: &str) -> bool {
+    matches!(
+        method,
+        "chat.postMessage" | "chat.postEphemeral" | "reactions.add" | "views.open"
+    )
 }
 
-/// channnel の ts の投稿に reaction を付加する
-pub fn add_reaction(api_token: &str, channel: &str, ts: &str, reaction: &str) -> Result<()> {
-    let mut body = HashMap::new();
-    body.insert("name", reaction);
-    body.insert("channel", channel);
-    body.insert("timestamp", ts);
-    post(api_token, body, "https://slack.com/api/reactions.add")
+/// 一時的なエラーとして再送してよい API のエラー
+fn is_transient(error: &str) -> bool {
+    matches!(
+        error,
+        "ratelimited"
+            | "internal_error"
+            | "fatal_error"
+            | "service_unavailable"
+            | "request_timeout"
+    )
 }
 
-/// slack.com のAPIを叩いて MAX_RETRY 回返って来なかった場合 Err を返す
-pub fn try_connect_to_slack_com() -> Result<()> {
-    const MAX_TRY_TIMES: usize = 5;
-    const SLEEP_TIME: std::time::Duration = std::time::Duration::from_secs(10);
-    for i in 0..MAX_TRY_TIMES {
-        if post(
-            "invalid token",
-            HashMap::new(),
-            "https://slack.com/api/auth.test",
-        )
-        .is_ok()
-        {
-            return Ok(());
-        } else {
-            warn!("connecting to slack.com failed");
-        }
-        if i + 1 != MAX_TRY_TIMES {
-            std::thread::sleep(SLEEP_TIME);
+/// attempt 回目の再送までの待ち時間(指数関数的に増やし、揺らぎを加える)
+fn backoff(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    let jitter = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+        % (delay.as_millis() as u64 / 2 + 1);
+    delay + Duration::from_millis(jitter)
+}
+
+/// 429 の応答の Retry-After ヘッダーが示す待ち時間
+fn retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// method の応答 text を T として取得
+/// 応答の ok が true でない場合は SlackError::Api を返す
+fn parse_response<T: DeserializeOwned>(method: &'static str, text: &str) -> Result<T> {
+    let v: serde_json::Value = text.parse()?;
+    debug!("{}", serde_json::to_string_pretty(&v)?);
+    if let Some(w) = v["warning"].as_str() {
+        warn!("API warning: {} \"{}\"", method, w);
+    }
+    if v["ok"].as_bool() != Some(true) {
+        return Err(SlackError::Api {
+            method,
+            error: v["error"].as_str().unwrap_or("unknown_error").to_string(),
         }
+        .into());
     }
-    Err(SlackError::ConnectFailed.into())
+    Ok(serde_json::from_value(v)?)
 }
 
-/// Real Time Messaging session を開始する
-pub fn rtm_connect(api_token: &str) -> Result<serde_json::Value> {
-    Ok(reqwest::blocking::Client::new()
-        .post("https://slack.com/api/rtm.connect")
-        .form(&[("token", api_token)])
-        .send()?
-        .text()?
-        .parse()?)
+/// 1回の API の呼び出しの結果
+enum Attempt<T> {
+    Done(Result<T>),
+    /// 一時的なエラーのため再送する(待ち時間の指定が無い場合は backoff で待つ)
+    Retry(Error, Option<Duration>),
+}
+
+/// 応答の本文を必要としない場合に使う
+#[derive(Deserialize, Debug)]
+struct Empty {}
+
+#[derive(Deserialize, Debug)]
+struct PostMessageResponse {
+    ts: String,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -127,116 +179,61 @@ fn get_channel_type_name_for_slack_api(x: ChannelType) -> &'static str {
     }
 }
 
-/// channel がpublicチャンネル/privateチャンネル/DMのどれかを確認する
-pub fn channel_type(api_token: &str, channel: &str) -> Result<ChannelType> {
-    let response = &reqwest::blocking::Client::new()
-        .post("https://slack.com/api/conversations.info")
-        .header(
-            reqwest::header::CONTENT_TYPE,
-            "application/x-www-form-urlencoded; charset=utf-8",
-        )
-        .form(&[("token", api_token), ("channel", channel)])
-        .send()?
-        .text()?
-        .parse::<serde_json::Value>()?["channel"];
-    debug!("{:?}", response);
-    Ok(if let Some(true) = response["is_mpim"].as_bool() {
-        ChannelType::GroupDirectMessage
-    } else if let Some(true) = response["is_channel"].as_bool() {
-        ChannelType::PublicChannel
-    } else if let Some(true) = response["is_group"].as_bool() {
-        ChannelType::PrivateChannel
-    } else if let Some(true) = response["is_im"].as_bool() {
-        ChannelType::DirectMessage
-    } else {
-        return Err(SlackError::InvalidConversation.into());
-    })
+#[derive(Deserialize, Debug)]
+struct Conversation {
+    #[serde(default)]
+    is_channel: bool,
+    #[serde(default)]
+    is_group: bool,
+    #[serde(default)]
+    is_im: bool,
+    #[serde(default)]
+    is_mpim: bool,
 }
-
-fn get_users_list(
-    api_token: &str,
-    cursor: Option<String>,
-) -> Result<(Vec<serde_json::Value>, Option<String>)> {
-    let form_params = {
-        let mut params = HashMap::new();
-        params.insert("token", api_token);
-        if let Some(ref c) = cursor {
-            params.insert("cursor", c);
-        }
-        params.insert("limit", "200");
-        params
-    };
-    let response = reqwest::blocking::Client::new()
-        .post("https://slack.com/api/users.list")
-        .header(
-            reqwest::header::CONTENT_TYPE,
-            "application/x-www-form-urlencoded; charset=utf-8",
-        )
-        .form(&form_params)
-        .send()?
-        .text()?
-        .parse::<serde_json::Value>()?;
-    debug!("{}", serde_json::to_string_pretty(&response)?);
-    Ok((
-        as_array(&response["members"])?.clone(),
-        response["response_metadata"]["next_cursor"]
-            .as_str()
-            .and_then(|s| if s.is_empty() { None } else { Some(s) })
-            .map(std::string::ToString::to_string),
-    ))
+#[derive(Deserialize, Debug)]
+struct ConversationsInfoResponse {
+    channel: Conversation,
 }
 
-/// 全ユーザー情報を取得
-pub fn users_list(api_token: &str) -> Result<Vec<serde_json::Value>> {
-    let mut list = Vec::new();
-    let mut cursor = None;
-    loop {
-        let (mut ret, next_cursor) = get_users_list(api_token, cursor)?;
-        list.append(&mut ret);
-        if next_cursor.is_some() {
-            cursor = next_cursor;
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Cursor {
+    next_cursor: String,
+}
+impl Cursor {
+    /// 次のページが存在する場合そのカーソルを返す
+    fn next(self) -> Option<String> {
+        if self.next_cursor.is_empty() {
+            None
         } else {
-            break;
+            Some(self.next_cursor)
         }
     }
-    Ok(list)
 }
 
-/// channel の last_timestamp から最新までの履歴200件を取得
-pub fn conversations_history(
-    api_token: &str,
-    channel: &str,
-    last_timestamp: &Option<String>,
-) -> Result<Vec<serde_json::Value>> {
-    let mut param = HashMap::new();
-    param.insert("token", api_token);
-    param.insert("channel", channel);
-    param.insert("limit", "200");
-    if let Some(x) = last_timestamp {
-        param.insert("oldest", x);
-    }
-    Ok(as_array(
-        &reqwest::blocking::Client::new()
-            .post("https://slack.com/api/conversations.history")
-            .header(
-                reqwest::header::CONTENT_TYPE,
-                "application/x-www-form-urlencoded; charset=utf-8",
-            )
-            .form(&param)
-            .send()?
-            .text()?
-            .parse::<serde_json::Value>()?["messages"],
-    )?
-    .clone())
+#[derive(Deserialize, Debug)]
+struct UsersListResponse {
+    members: Vec<serde_json::Value>,
+    #[serde(default)]
+    response_metadata: Cursor,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Cursor {
-    next_cursor: String,
+#[derive(Deserialize, Debug)]
+struct UsergroupsUsersListResponse {
+    users: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileResponse {
+    profile: serde_json::Value,
 }
+
+#[derive(Deserialize, Debug)]
+struct ConversationsHistoryResponse {
+    messages: Vec<serde_json::Value>,
+}
+
 #[derive(Serialize, Debug)]
 struct UsersConversationsParams<'a> {
-    token: &'a str,
     cursor: Option<&'a str>,
     exclude_archive: Option<bool>,
     limit: Option<u16>,
@@ -245,9 +242,8 @@ struct UsersConversationsParams<'a> {
     user: Option<&'a str>,
 }
 impl<'a> UsersConversationsParams<'a> {
-    fn new(token: &'a str) -> Self {
+    fn new() -> Self {
         UsersConversationsParams {
-            token,
             cursor: None,
             exclude_archive: None,
             limit: None,
@@ -263,92 +259,551 @@ pub struct Channel {
     pub name: String,
 }
 #[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum UsersConversationsResponse {
-    Ok {
-        ok: bool,
-        channels: Vec<Channel>,
-        response_metadata: Cursor,
-    },
-    Err {
-        ok: bool,
-        error: String,
-    },
+struct UsersConversationsResponse {
+    channels: Vec<Channel>,
+    response_metadata: Cursor,
 }
 
-fn get_users_conversations(
-    api_token: &str,
-    cursor: Option<String>,
-    exclude_archive: Option<bool>,
-    types: Option<&[ChannelType]>,
-    user: Option<&str>,
-) -> Result<(Vec<Channel>, Option<String>)> {
-    let s;
-    let form_params = {
-        let mut p = UsersConversationsParams::new(api_token);
-        p.cursor = cursor.as_deref();
-        p.exclude_archive = exclude_archive;
-        p.limit = Some(200);
-        if let Some(t) = types {
-            let v = t
-                .iter()
-                .copied()
-                .map(get_channel_type_name_for_slack_api)
-                .collect::<Vec<_>>();
-            s = v.join(",");
-            p.types = Some(&s);
+/// Slack Web API のクライアント
+/// HTTPの接続を使い回し、 rate limit を守って API を呼び出す
+pub struct SlackClient {
+    http: reqwest::blocking::Client,
+    api_token: String,
+    api_base_url: String,
+    /// RTM や Socket Mode で Slack が返す URL の代わりに接続する WebSocket の URL
+    websocket_url: Option<String>,
+    /// api_token を付けてダウンロードしてよいファイルのURLの接頭辞
+    files_url: String,
+    /// method (per_channel の method はチャンネルも)ごとの次に呼び出してよい時刻
+    next_call: Mutex<HashMap<(&'static str, String), Instant>>,
+    /// method (`*` は上書きの無い全ての method)ごとの min_interval の上書き
+    min_intervals: HashMap<String, Duration>,
+}
+
+impl SlackClient {
+    /// api_base_url には末尾の `/` を除いた Web API のURIを指定する
+    pub fn new(
+        api_token: String,
+        api_base_url: String,
+        websocket_url: Option<String>,
+        files_url: String,
+    ) -> Self {
+        SlackClient {
+            http: reqwest::blocking::Client::new(),
+            api_token,
+            api_base_url,
+            websocket_url,
+            files_url,
+            next_call: Mutex::new(HashMap::new()),
+            min_intervals: HashMap::new(),
         }
-        p.user = user;
-        p
-    };
-    let response: UsersConversationsResponse = reqwest::blocking::Client::new()
-        .get("https://slack.com/api/users.conversations")
-        .header(
-            reqwest::header::CONTENT_TYPE,
-            "application/x-www-form-urlencoded; charset=utf-8",
+    }
+
+    /// method ごとの呼び出しの最小間隔を min_intervals で上書きする
+    /// `*` の間隔は上書きの無い全ての method に使う
+    pub fn with_min_intervals(mut self, min_intervals: HashMap<String, Duration>) -> Self {
+        self.min_intervals = min_intervals;
+        self
+    }
+
+    /// method の呼び出しの最小間隔
+    fn min_interval(&self, method: &str) -> Duration {
+        self.min_intervals
+            .get(method)
+            .or_else(|| self.min_intervals.get("*"))
+            .copied()
+            .unwrap_or_else(|| min_interval(method))
+    }
+
+    /// Slack Web API の method のURI
+    fn api_uri(&self, method: &str) -> String {
+        format!("{}/{}", self.api_base_url, method)
+    }
+
+    /// Slack から取得した WebSocket の url の代わりに接続する URL
+    pub fn websocket_url<'a>(&'a self, url: &'a str) -> &'a str {
+        self.websocket_url.as_deref().unwrap_or(url)
+    }
+
+    /// method の呼び出しの間隔が min_interval 以上になるまで待つ
+    /// per_channel の method は channel ごとに間隔を数える
+    fn wait_for_budget(&self, method: &'static str, channel: Option<&str>) {
+        let now = Instant::now();
+        let key = match channel {
+            Some(x) if per_channel(method) => (method, x.to_string()),
+            _ => (method, String::new()),
+        };
+        let at = {
+            let mut next_call = self.next_call.lock().unwrap();
+            let at = next_call.get(&key).map_or(now, |&x| x.max(now));
+            next_call.insert(key, at + self.min_interval(method));
+            at
+        };
+        if at > now {
+            debug!("wait {:?} for {}", at - now, method);
+            std::thread::sleep(at - now);
+        }
+    }
+
+    /// request を1回送信する
+    /// is_non_idempotent の method は処理された可能性のあるタイムアウトやサーバーのエラーでは再送しない
+    fn attempt<T: DeserializeOwned>(
+        &self,
+        method: &'static str,
+        request: &reqwest::blocking::RequestBuilder,
+    ) -> Attempt<T> {
+        let idempotent = !is_non_idempotent(method);
+        let response = match request
+            .try_clone()
+            .expect("request body must not be a stream")
+            .send()
+        {
+            Ok(x) => x,
+            Err(e) if e.is_connect() || (idempotent && e.is_timeout()) => {
+                return Attempt::Retry(e.into(), None)
+            }
+            Err(e) => return Attempt::Done(Err(e.into())),
+        };
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let wait = retry_after(&response);
+            let error = SlackError::Api {
+                method,
+                error: "ratelimited".to_string(),
+            };
+            return Attempt::Retry(error.into(), wait);
+        }
+        if status.is_server_error() {
+            let error = SlackError::Http {
+                method,
+                status: status.as_u16(),
+            };
+            return if idempotent {
+                Attempt::Retry(error.into(), None)
+            } else {
+                Attempt::Done(Err(error.into()))
+            };
+        }
+        let text = match response.text() {
+            Ok(x) => x,
+            Err(e) => return Attempt::Done(Err(e.into())),
+        };
+        match parse_response(method, &text) {
+            Err(Error::Slack(SlackError::Api { method, error }))
+                if error == "ratelimited" || (idempotent && is_transient(&error)) =>
+            {
+                Attempt::Retry(SlackError::Api { method, error }.into(), None)
+            }
+            result => Attempt::Done(result),
+        }
+    }
+
+    /// method を呼び出す request を api_token で送信し、応答を T として取得
+    fn send<T: DeserializeOwned>(
+        &self,
+        method: &'static str,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<T> {
+        self.send_with(method, None, &self.api_token, request)
+    }
+
+    /// channel に対して method を呼び出す request を token で送信し、応答を T として取得
+    /// rate limit やサーバーの一時的なエラーの場合は待ってから再送する
+    fn send_with<T: DeserializeOwned>(
+        &self,
+        method: &'static str,
+        channel: Option<&str>,
+        token: &str,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<T> {
+        let request = request.header(reqwest::header::AUTHORIZATION, "Bearer ".to_owned() + token);
+        let mut attempt = 0;
+        loop {
+            self.wait_for_budget(method, channel);
+            match self.attempt(method, &request) {
+                Attempt::Done(result) => return result,
+                Attempt::Retry(e, _) if attempt + 1 >= MAX_ATTEMPTS => return Err(e),
+                Attempt::Retry(e, wait) => {
+                    let wait = wait.unwrap_or_else(|| backoff(attempt));
+                    warn!("{}. retry after {:?}", e, wait);
+                    std::thread::sleep(wait);
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// method に body をJSONとしてPOSTする
+    fn post<T: DeserializeOwned>(
+        &self,
+        method: &'static str,
+        body: &HashMap<&str, &str>,
+    ) -> Result<T> {
+        debug!("{:?}", body);
+        self.send_with(
+            method,
+            body.get("channel").copied(),
+            &self.api_token,
+            self.http
+                .post(self.api_uri(method))
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    "application/json; charset=utf-8",
+                )
+                .body(serde_json::to_vec(body)?),
         )
-        .query(&form_params)
-        .send()?
-        .json()?;
-    match response {
-        UsersConversationsResponse::Ok {
-            ok,
-            channels,
-            response_metadata,
-        } => {
-            debug_assert!(ok);
-            let next_cursor = response_metadata.next_cursor;
-            if next_cursor.is_empty() {
-                Ok((channels, None))
+    }
+
+    /// method に params をフォームとしてPOSTする
+    fn post_form<T: DeserializeOwned, P: Serialize + ?Sized>(
+        &self,
+        method: &'static str,
+        params: &P,
+    ) -> Result<T> {
+        self.send(
+            method,
+            self.http
+                .post(self.api_uri(method))
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    "application/x-www-form-urlencoded; charset=utf-8",
+                )
+                .form(params),
+        )
+    }
+
+    /// channel に text を投稿する
+    pub fn post_message(&self, channel: &str, text: &str) -> Result<()> {
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("text", text);
+        body.insert("as_user", "true");
+        self.post::<Empty>("chat.postMessage", &body)?;
+        Ok(())
+    }
+
+    /// channel に text を投稿し、その投稿の ts を返す
+    pub fn post_message_for_thread(&self, channel: &str, text: &str) -> Result<String> {
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("text", text);
+        body.insert("as_user", "true");
+        let response: PostMessageResponse = self.post("chat.postMessage", &body)?;
+        Ok(response.ts)
+    }
+
+    /// channel に blocks を投稿し、その投稿の ts を返す
+    /// text は通知などで blocks の代わりに表示される
+    pub fn post_blocks_for_thread(
+        &self,
+        channel: &str,
+        text: &str,
+        blocks: serde_json::Value,
+    ) -> Result<String> {
+        let blocks_str = blocks.to_string();
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("text", text);
+        body.insert("blocks", &blocks_str);
+        body.insert("as_user", "true");
+        let response: PostMessageResponse = self.post("chat.postMessage", &body)?;
+        Ok(response.ts)
+    }
+
+    /// channel の ts の投稿を text のみの投稿に書き換える(ボタンなどの blocks は削除される)
+    pub fn update_message(&self, channel: &str, ts: &str, text: &str) -> Result<()> {
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("ts", ts);
+        body.insert("text", text);
+        body.insert("blocks", "[]");
+        self.post::<Empty>("chat.update", &body)?;
+        Ok(())
+    }
+
+    /// channel の ts のスレッドに text を投稿する
+    pub fn post_message_to_thread(&self, channel: &str, ts: &str, text: &str) -> Result<()> {
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("text", text);
+        body.insert("as_user", "true");
+        body.insert("thread_ts", ts);
+        self.post::<Empty>("chat.postMessage", &body)?;
+        Ok(())
+    }
+
+    /// channel に user のみに見える blocks を投稿する
+    /// text は通知などで blocks の代わりに表示される
+    pub fn post_ephemeral_blocks(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        blocks: serde_json::Value,
+    ) -> Result<()> {
+        let blocks_str = blocks.to_string();
+        let mut body = HashMap::new();
+        body.insert("channel", channel);
+        body.insert("text", text);
+        body.insert("blocks", &blocks_str);
+        body.insert("user", user);
+        body.insert("as_user", "true");
+        self.post::<Empty>("chat.postEphemeral", &body)?;
+        Ok(())
+    }
+
+    /// slash command などの response_url に body を投稿する
+    pub fn post_to_response_url(&self, response_url: &str, body: serde_json::Value) -> Result<()> {
+        debug!("{}", body);
+        let text = self.http.post(response_url).json(&body).send()?.text()?;
+        debug!("{}", text);
+        // 成功時は JSON ではなく "ok" のみが返ってくる場合がある
+        if text.trim() != "ok" {
+            parse_response::<Empty>("response_url", &text)?;
+        }
+        Ok(())
+    }
+
+    /// trigger_id の操作に対して view のモーダルを開く
+    pub fn views_open(&self, trigger_id: &str, view: serde_json::Value) -> Result<()> {
+        let view_str = view.to_string();
+        let mut body = HashMap::new();
+        body.insert("trigger_id", trigger_id);
+        body.insert("view", &view_str);
+        self.post::<Empty>("views.open", &body)?;
+        Ok(())
+    }
+
+    /// channnel の ts の投稿に reaction を付加する
+    pub fn add_reaction(&self, channel: &str, ts: &str, reaction: &str) -> Result<()> {
+        let mut body = HashMap::new();
+        body.insert("name", reaction);
+        body.insert("channel", channel);
+        body.insert("timestamp", ts);
+        self.post::<Empty>("reactions.add", &body)?;
+        Ok(())
+    }
+
+    /// slack.com のAPIを叩いて MAX_RETRY 回返って来なかった場合 Err を返す
+    pub fn try_connect_to_slack_com(&self) -> Result<()> {
+        const MAX_TRY_TIMES: usize = 5;
+        const SLEEP_TIME: std::time::Duration = std::time::Duration::from_secs(10);
+        for i in 0..MAX_TRY_TIMES {
+            // トークン無しなので API としては失敗するが、応答が返ってくれば接続できている
+            let response = self
+                .http
+                .post(self.api_uri("auth.test"))
+                .send()
+                .and_then(reqwest::blocking::Response::text);
+            if response.is_ok() {
+                return Ok(());
             } else {
-                Ok((channels, Some(next_cursor)))
+                warn!("connecting to slack.com failed");
+            }
+            if i + 1 != MAX_TRY_TIMES {
+                std::thread::sleep(SLEEP_TIME);
             }
         }
-        UsersConversationsResponse::Err { ok, error } => {
-            debug_assert!(!ok);
-            Err(SlackError::UsersConversations(error).into())
+        Err(SlackError::ConnectFailed.into())
+    }
+
+    /// Real Time Messaging session を開始する
+    pub fn rtm_connect(&self) -> Result<serde_json::Value> {
+        self.post_form("rtm.connect", &HashMap::<&str, &str>::new())
+    }
+
+    /// Socket Mode の WebSocket URL を取得する
+    /// app_token にはアプリレベルトークン(xapp-)を指定する
+    pub fn apps_connections_open(&self, app_token: &str) -> Result<serde_json::Value> {
+        let method = "apps.connections.open";
+        self.send_with(
+            method,
+            None,
+            app_token,
+            self.http.post(self.api_uri(method)),
+        )
+    }
+
+    /// api_token の持ち主の情報を取得する
+    pub fn auth_test(&self) -> Result<serde_json::Value> {
+        self.post_form("auth.test", &HashMap::<&str, &str>::new())
+    }
+
+    /// channel がpublicチャンネル/privateチャンネル/DMのどれかを確認する
+    pub fn channel_type(&self, channel: &str) -> Result<ChannelType> {
+        let response: ConversationsInfoResponse =
+            self.post_form("conversations.info", &[("channel", channel)])?;
+        let conversation = response.channel;
+        Ok(if conversation.is_mpim {
+            ChannelType::GroupDirectMessage
+        } else if conversation.is_channel {
+            ChannelType::PublicChannel
+        } else if conversation.is_group {
+            ChannelType::PrivateChannel
+        } else if conversation.is_im {
+            ChannelType::DirectMessage
+        } else {
+            return Err(SlackError::InvalidConversation.into());
+        })
+    }
+
+    fn get_users_list(
+        &self,
+        cursor: Option<String>,
+    ) -> Result<(Vec<serde_json::Value>, Option<String>)> {
+        let form_params = {
+            let mut params = HashMap::new();
+            if let Some(ref c) = cursor {
+                params.insert("cursor", c.as_str());
+            }
+            params.insert("limit", "200");
+            params
+        };
+        let response: UsersListResponse = self.post_form("users.list", &form_params)?;
+        Ok((response.members, response.response_metadata.next()))
+    }
+
+    /// 全ユーザー情報を取得
+    pub fn users_list(&self) -> Result<Vec<serde_json::Value>> {
+        let mut list = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (mut ret, next_cursor) = self.get_users_list(cursor)?;
+            list.append(&mut ret);
+            if next_cursor.is_some() {
+                cursor = next_cursor;
+            } else {
+                break;
+            }
         }
+        Ok(list)
     }
-}
 
-/// ユーザーが属している全てのpublic channelを取得
-pub fn users_public_channel_list(api_token: &str, user: Option<&str>) -> Result<Vec<Channel>> {
-    let mut list = Vec::new();
-    let mut cursor = None;
-    loop {
-        let (mut ret, next_cursor) = get_users_conversations(
-            api_token,
-            cursor,
-            Some(true),
-            Some(&[ChannelType::PublicChannel, ChannelType::PrivateChannel]),
-            user,
+    /// usergroup に所属するユーザーのIDを取得
+    pub fn usergroups_users_list(&self, usergroup: &str) -> Result<Vec<String>> {
+        let response: UsergroupsUsersListResponse =
+            self.post_form("usergroups.users.list", &[("usergroup", usergroup)])?;
+        Ok(response.users)
+    }
+
+    /// user のプロフィール(カスタムフィールドを含む)を取得
+    pub fn users_profile_get(&self, user: &str) -> Result<serde_json::Value> {
+        let response: ProfileResponse = self.post_form("users.profile.get", &[("user", user)])?;
+        Ok(response.profile)
+    }
+
+    /// Slackにアップロードされたファイルの url_private の内容を取得
+    /// api_token を送るため files_url 以外のURLは取得せず、 max_size バイトより大きいファイルは途中で読むのをやめる
+    pub fn download_file(&self, url: &str, max_size: u64) -> Result<String> {
+        if !url.starts_with(&self.files_url) {
+            return Err(SlackError::UntrustedFileUrl(url.to_string()).into());
+        }
+        let response = self
+            .http
+            .get(url)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                "Bearer ".to_owned() + &self.api_token,
+            )
+            .send()?;
+        if !response.status().is_success() {
+            return Err(SlackError::Http {
+                method: "files",
+                status: response.status().as_u16(),
+            }
+            .into());
+        }
+        let mut body = Vec::new();
+        response.take(max_size + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > max_size {
+            return Err(SlackError::FileTooLarge(url.to_string(), max_size).into());
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// ワークスペースのプロフィールのカスタムフィールドの定義を取得
+    pub fn team_profile_get(&self) -> Result<Vec<serde_json::Value>> {
+        let response: ProfileResponse =
+            self.post_form("team.profile.get", &HashMap::<&str, &str>::new())?;
+        Ok(response.profile["fields"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// channel の last_timestamp から最新までの履歴200件を取得
+    pub fn conversations_history(
+        &self,
+        channel: &str,
+        last_timestamp: &Option<String>,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut param = HashMap::new();
+        param.insert("channel", channel);
+        param.insert("limit", "200");
+        if let Some(x) = last_timestamp {
+            param.insert("oldest", x);
+        }
+        let response: ConversationsHistoryResponse =
+            self.post_form("conversations.history", &param)?;
+        Ok(response.messages)
+    }
+
+    fn get_users_conversations(
+        &self,
+        cursor: Option<String>,
+        exclude_archive: Option<bool>,
+        types: Option<&[ChannelType]>,
+        user: Option<&str>,
+    ) -> Result<(Vec<Channel>, Option<String>)> {
+        let s;
+        let form_params = {
+            let mut p = UsersConversationsParams::new();
+            p.cursor = cursor.as_deref();
+            p.exclude_archive = exclude_archive;
+            p.limit = Some(200);
+            if let Some(t) = types {
+                let v = t
+                    .iter()
+                    .copied()
+                    .map(get_channel_type_name_for_slack_api)
+                    .collect::<Vec<_>>();
+                s = v.join(",");
+                p.types = Some(&s);
+            }
+            p.user = user;
+            p
+        };
+        let response: UsersConversationsResponse = self.send(
+            "users.conversations",
+            self.http
+                .get(self.api_uri("users.conversations"))
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    "application/x-www-form-urlencoded; charset=utf-8",
+                )
+                .query(&form_params),
         )?;
-        list.append(&mut ret);
-        if next_cursor.is_some() {
-            cursor = next_cursor;
-        } else {
-            break;
+        Ok((response.channels, response.response_metadata.next()))
+    }
+
+    /// ユーザーが属している全てのpublic channelを取得
+    pub fn users_public_channel_list(&self, user: Option<&str>) -> Result<Vec<Channel>> {
+        let mut list = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (mut ret, next_cursor) = self.get_users_conversations(
+                cursor,
+                Some(true),
+                Some(&[ChannelType::PublicChannel, ChannelType::PrivateChannel]),
+                user,
+            )?;
+            list.append(&mut ret);
+            if next_cursor.is_some() {
+                cursor = next_cursor;
+            } else {
+                break;
+            }
         }
+        Ok(list)
     }
-    Ok(list)
 }
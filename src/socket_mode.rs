@@ -0,0 +1,55 @@
+use serde::Deserialize;
+use serde_json::json;
+
+/// Socket Mode の WebSocket 上で受信するメッセージ
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    Hello {},
+    Disconnect {
+        #[serde(default)]
+        reason: String,
+    },
+    EventsApi {
+        envelope_id: String,
+        payload: serde_json::Value,
+    },
+    SlashCommands {
+        envelope_id: String,
+        payload: serde_json::Value,
+    },
+    Interactive {
+        envelope_id: String,
+        payload: serde_json::Value,
+    },
+}
+
+impl Envelope {
+    /// 応答が必要なメッセージの場合 envelope_id を返す
+    pub fn envelope_id(&self) -> Option<&str> {
+        match self {
+            Envelope::EventsApi { envelope_id, .. }
+            | Envelope::SlashCommands { envelope_id, .. }
+            | Envelope::Interactive { envelope_id, .. } => Some(envelope_id),
+            Envelope::Hello {} | Envelope::Disconnect { .. } => None,
+        }
+    }
+
+    /// Events API のイベントを包んだメッセージの場合、イベントの event_id を返す
+    pub fn event_id(&self) -> Option<&str> {
+        match self {
+            Envelope::EventsApi { payload, .. } => payload["event_id"].as_str(),
+            _ => None,
+        }
+    }
+}
+
+/// envelope_id のメッセージを受け取ったことを Slack に伝える応答を作成する
+/// block_suggestion などの応答が必要なメッセージには payload を含める
+pub fn acknowledge(envelope_id: &str, payload: Option<serde_json::Value>) -> tungstenite::Message {
+    let mut message = json!({ "envelope_id": envelope_id });
+    if let Some(payload) = payload {
+        message["payload"] = payload;
+    }
+    tungstenite::Message::Text(message.to_string())
+}
@@ -0,0 +1,305 @@
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::key_policy::KeyPolicy;
+
+/// 公開鍵の種類として受け付けるもの
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+/// 返答に含める不正な行の数の上限
+const MAX_REPORTED_REJECTIONS: usize = 5;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SshKeyError {
+    #[error("missing key type")]
+    MissingType,
+    #[error("unknown key type `{0}`")]
+    UnknownType(String),
+    #[error("missing key data")]
+    MissingData,
+    #[error("key data is not valid base64")]
+    InvalidBase64,
+    #[error("key data is not a `{0}` key")]
+    TypeMismatch(String),
+    #[error("DSA keys are not allowed")]
+    Dsa,
+    #[error("`{0}` keys are not allowed")]
+    DisallowedType(String),
+    #[error("RSA keys must be at least {1} bits, but this key is {0} bits")]
+    WeakRsa(usize, usize),
+    #[error("this key is known to be compromised")]
+    Blocked,
+}
+
+/// authorized_keys の1行の OpenSSH 形式の公開鍵
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    /// `from="..."` などのオプション
+    pub options: Option<String>,
+    pub key_type: String,
+    /// base64 をデコードした鍵のデータ
+    pub blob: Vec<u8>,
+    pub comment: Option<String>,
+    /// 鍵の取得元
+    pub source: String,
+    /// authorized_keys に書き込む行
+    line: String,
+}
+
+/// text の先頭から、引用符の外の最初の空白までを切り出す
+fn split_token(text: &str) -> (&str, &str) {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return (&text[..i], text[i..].trim_start()),
+            _ => {}
+        }
+    }
+    (text, "")
+}
+
+/// SSHの形式で長さが前置された文字列を data の先頭から読み込む
+fn read_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (length, rest) = data.split_at(4);
+    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    if rest.len() < length {
+        return None;
+    }
+    Some(rest.split_at(length))
+}
+
+impl PublicKey {
+    /// authorized_keys の1行を公開鍵として読み込む
+    pub fn parse(line: &str) -> Result<Self, SshKeyError> {
+        let line = line.trim();
+        let (first, rest) = split_token(line);
+        if first.is_empty() {
+            return Err(SshKeyError::MissingType);
+        }
+        let (options, key_type, rest) = if KEY_TYPES.contains(&first) {
+            (None, first, rest)
+        } else {
+            let (key_type, rest) = split_token(rest);
+            if !KEY_TYPES.contains(&key_type) {
+                return Err(SshKeyError::UnknownType(first.to_string()));
+            }
+            (Some(first.to_string()), key_type, rest)
+        };
+        let (data, comment) = split_token(rest);
+        if data.is_empty() {
+            return Err(SshKeyError::MissingData);
+        }
+        let blob = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|_| SshKeyError::InvalidBase64)?;
+        let key = PublicKey {
+            options,
+            key_type: key_type.to_string(),
+            blob,
+            comment: Some(comment.to_string()).filter(|x| !x.is_empty()),
+            source: String::new(),
+            line: line.to_string(),
+        };
+        match read_string(&key.blob) {
+            Some((x, _)) if x == key_type.as_bytes() => {}
+            _ => return Err(SshKeyError::TypeMismatch(key.key_type)),
+        }
+        if key.key_type == "ssh-rsa" && key.rsa_bits().is_none() {
+            return Err(SshKeyError::TypeMismatch(key.key_type));
+        }
+        Ok(key)
+    }
+
+    /// RSAの鍵の法のビット数(RSAの鍵でない場合は None)
+    pub fn rsa_bits(&self) -> Option<usize> {
+        if self.key_type != "ssh-rsa" {
+            return None;
+        }
+        let (_, rest) = read_string(&self.blob)?;
+        let (_exponent, rest) = read_string(rest)?;
+        let (modulus, _) = read_string(rest)?;
+        let modulus = match modulus.iter().position(|&x| x != 0) {
+            Some(i) => &modulus[i..],
+            None => return Some(0),
+        };
+        Some(modulus.len() * 8 - modulus[0].leading_zeros() as usize)
+    }
+
+    /// 返答に使う、鍵の種類とフィンガープリントとコメント
+    pub fn summary(&self) -> String {
+        match &self.comment {
+            Some(comment) => format!("{} {} {}", self.key_type, self.fingerprint(), comment),
+            None => format!("{} {}", self.key_type, self.fingerprint()),
+        }
+    }
+
+    /// `ssh-keygen -l` と同じ形式の SHA256 のフィンガープリント
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(&self.blob);
+        format!(
+            "SHA256:{}",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+        )
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.line)
+    }
+}
+
+/// 書き込まなかった行
+#[derive(Debug)]
+pub struct Rejection {
+    /// 取得元
+    pub source: String,
+    /// 行番号(1始まり)
+    pub line: usize,
+    /// 公開鍵として読み込めた場合はその鍵
+    pub key: Option<PublicKey>,
+    pub reason: SshKeyError,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.key {
+            Some(key) => write!(
+                f,
+                "line {} of {}: {}: {}",
+                self.line,
+                self.source,
+                key.summary(),
+                self.reason
+            ),
+            None => write!(f, "line {} of {}: {}", self.line, self.source, self.reason),
+        }
+    }
+}
+
+/// 取得した authorized_keys を読み込んだ結果
+#[derive(Debug, Default)]
+pub struct KeySet {
+    pub accepted: Vec<PublicKey>,
+    pub rejected: Vec<Rejection>,
+    /// 取得できなかった取得元のエラー
+    pub failed: Vec<String>,
+}
+
+impl KeySet {
+    /// source から取得した text の、空行と `#` で始まる行を除いた各行を公開鍵として読み込み、
+    /// policy に従うものだけを受け付ける
+    pub fn parse(text: &str, policy: &KeyPolicy, source: &str) -> Self {
+        let mut keys = KeySet::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, reason) = match PublicKey::parse(line) {
+                Ok(mut x) => match policy.check(&x) {
+                    Ok(()) => {
+                        x.source = source.to_string();
+                        keys.accepted.push(x);
+                        continue;
+                    }
+                    Err(e) => (Some(x), e),
+                },
+                Err(e) => (None, e),
+            };
+            keys.rejected.push(Rejection {
+                source: source.to_string(),
+                line: i + 1,
+                key,
+                reason,
+            });
+        }
+        keys
+    }
+
+    /// other の結果を加える(既に受け付けた鍵と同じ鍵は加えない)
+    pub fn append(&mut self, other: KeySet) {
+        for x in other.accepted {
+            if self.accepted.iter().all(|y| y.blob != x.blob) {
+                self.accepted.push(x);
+            }
+        }
+        self.rejected.extend(other.rejected);
+        self.failed.extend(other.failed);
+    }
+
+    /// authorized_keys に書き込む内容
+    /// 各鍵の前に取得元をコメントとして記録する
+    pub fn authorized_keys(&self) -> String {
+        let mut text = String::new();
+        let mut source = None;
+        for x in &self.accepted {
+            if source != Some(&x.source) {
+                text += &format!("# from {}\n", x.source);
+                source = Some(&x.source);
+            }
+            text += &format!("{}\n", x);
+        }
+        text
+    }
+
+    /// 書き込まなかった行とその理由
+    fn rejected_lines(&self) -> Vec<String> {
+        let mut lines = self
+            .rejected
+            .iter()
+            .take(MAX_REPORTED_REJECTIONS)
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        if self.rejected.len() > MAX_REPORTED_REJECTIONS {
+            lines.push(format!(
+                "and {} more line(s)",
+                self.rejected.len() - MAX_REPORTED_REJECTIONS
+            ));
+        }
+        lines
+    }
+
+    /// 取得できなかった取得元と、書き込まなかった行とその理由
+    pub fn rejection_text(&self) -> String {
+        let mut lines = self.failed.clone();
+        lines.extend(self.rejected_lines());
+        lines.join("\n")
+    }
+
+    /// 受け付けた鍵のフィンガープリントと、取得できなかった取得元と書き込まなかった行を Slack に返答する文
+    pub fn report(&self) -> String {
+        let mut text = format!("\nAccepted {} key(s):\n```\n", self.accepted.len());
+        for x in &self.accepted {
+            text += &format!("{} (from {})\n", x.summary(), x.source);
+        }
+        text += "```";
+        if !self.failed.is_empty() {
+            text += &format!(
+                "\nFailed to fetch keys from {} source(s):\n```\n{}\n```",
+                self.failed.len(),
+                self.failed.join("\n")
+            );
+        }
+        if !self.rejected.is_empty() {
+            text += &format!(
+                "\nRejected {} line(s):\n```\n{}\n```",
+                self.rejected.len(),
+                self.rejected_lines().join("\n")
+            );
+        }
+        text
+    }
+}
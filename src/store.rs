@@ -0,0 +1,111 @@
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::runtime_error::{Error, Result};
+
+/// 承認待ちの申請などの状態を保存するディレクトリの既定値
+pub const DEFAULT_STATE_DIRECTORY: &str = "/var/lib/sacana";
+
+/// 状態を保存する directory を作成し、ファイルを書き込めるかどうかを確かめる
+/// 書き込めない場合は、最初の申請などを保存するときではなく起動時に失敗させる
+pub fn prepare_directory(directory: &str) -> Result<()> {
+    let check = || -> std::io::Result<()> {
+        std::fs::create_dir_all(directory)?;
+        let path = std::path::Path::new(directory).join(".write-check");
+        std::fs::File::create(&path)?;
+        std::fs::remove_file(&path)
+    };
+    check().map_err(|e| Error::StateDirectory(directory.to_string(), e))
+}
+
+/// 状態を保存するディレクトリのJSONファイルに永続化される値
+pub struct Store<T> {
+    path: PathBuf,
+    value: T,
+    /// true の場合はファイルに書き込まず、 update で値も変更しない
+    dry_run: bool,
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Clone> Store<T> {
+    /// directory の name のファイルから読み込む(存在しない場合は既定値)
+    pub fn open(directory: &str, name: &str) -> Result<Self> {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| Error::StateDirectory(directory.to_string(), e))?;
+        let path = std::path::Path::new(directory).join(name);
+        let value = match std::fs::File::open(&path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Store {
+            path,
+            value,
+            dry_run: false,
+        })
+    }
+
+    /// dry run 用に directory の name のファイルから読み込む
+    /// root 以外でも試せるよう、ディレクトリを作成せず、ファイルが無いか読めない場合は既定値を使う
+    pub fn open_dry_run(directory: &str, name: &str) -> Result<Self> {
+        let path = std::path::Path::new(directory).join(name);
+        let value = match std::fs::File::open(&path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    info!("dry run: {} can't be read ({})", path.display(), e);
+                }
+                T::default()
+            }
+        };
+        Ok(Store {
+            path,
+            value,
+            dry_run: true,
+        })
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// f で値を変更し、ファイルに書き込む
+    /// 書き込みに失敗した場合は値を変更しない
+    /// dry run では f の結果だけを返し、値もファイルも変更しない
+    pub fn update<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+        let mut value = self.value.clone();
+        let result = f(&mut value);
+        if self.dry_run {
+            info!("dry run: {} isn't updated", self.path.display());
+            return Ok(result);
+        }
+        self.write(&value)?;
+        self.value = value;
+        Ok(result)
+    }
+
+    /// ファイルが無かった場合の初期値を f で設定する
+    /// 初期値は既存の状態から求めたものなので、 dry run でも値は変更する(ファイルには書き込まない)
+    pub fn seed(&mut self, f: impl FnOnce(&mut T)) -> Result<()> {
+        let mut value = self.value.clone();
+        f(&mut value);
+        if !self.dry_run {
+            self.write(&value)?;
+        }
+        self.value = value;
+        Ok(())
+    }
+
+    /// value をファイルに書き込む
+    /// 書き込みの途中で終了しても壊れないよう、一時ファイルに書いてから置き換える
+    fn write(&self, value: &T) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut writer, value)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
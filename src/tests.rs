@@ -0,0 +1,2216 @@
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::account_backend::{merge_authorized_keys, Account};
+use crate::linux_user_manage::{read_user_file, write_user_file, LinuxError};
+use crate::mock_slack::{
+    button_envelope, events_api_envelope, message, slash_command_envelope,
+    view_submission_envelope, MockSlack, BOT_ID, CHANNEL_ID, CHANNEL_NAME, DEACTIVATED_USER_ID,
+    DEACTIVATED_USER_NAME, DM_ID, NOT_JOINED_CHANNEL_ID, OPERATORS_USERGROUP_ID, OTHER_USER_ID,
+    OTHER_USER_NAME, POSTED_TS, PUBLIC_KEY, PUBLIC_KEY_FINGERPRINT, USER_GITHUB_NAME, USER_ID,
+    USER_NAME,
+};
+use crate::slack::SlackError;
+use crate::*;
+
+const LOCAL_HOST_NAME: &str = "host1";
+const OTHER_HOST_NAME: &str = "host2";
+
+/// mock の public_key_uri_format から取得した PUBLIC_KEY の取得元
+fn public_key_source(mock: &MockSlack) -> String {
+    mock.public_key_uri_format().replace("{}", USER_NAME)
+}
+
+/// source から取得した PUBLIC_KEY を書き込んだ authorized_keys の内容
+fn installed_public_key(source: &str) -> String {
+    format!("# from {}\n{}", source, PUBLIC_KEY)
+}
+
+/// source から PUBLIC_KEY を取得したときに返答に含まれるフィンガープリント
+fn public_key_report(source: &str) -> String {
+    format!(
+        "\nAccepted 1 key(s):\n```\nssh-ed25519 {} alice@mock (from {})\n```",
+        PUBLIC_KEY_FINGERPRINT, source
+    )
+}
+
+/// MemoryBackend のグループのGID(video はシステムのグループ、 lab は一般のグループ)
+const GROUP_IDS: &[(&str, u32)] = &[("sudo", 27), ("video", 44), ("docker", 999), ("lab", 1001)];
+
+/// メモリ上でアカウントを管理するテスト用のバックエンド
+#[derive(Clone, Default)]
+struct MemoryBackend {
+    /// ユーザー名ごとのアカウントと authorized_keys
+    accounts: Rc<RefCell<HashMap<String, (Account, String)>>>,
+}
+
+impl MemoryBackend {
+    fn not_found() -> Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "Your account doesn't exist").into()
+    }
+
+    /// user_name のアカウントを変更する
+    fn modify(&self, user_name: &str, f: impl FnOnce(&mut (Account, String))) -> Result<()> {
+        let mut accounts = self.accounts.borrow_mut();
+        f(accounts.get_mut(user_name).ok_or_else(Self::not_found)?);
+        Ok(())
+    }
+}
+
+impl AccountBackend for MemoryBackend {
+    fn create(&self, user_name: &str, shell: &str, keys: &str) -> Result<()> {
+        let mut accounts = self.accounts.borrow_mut();
+        if accounts.contains_key(user_name) {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+        let account = Account {
+            name: user_name.to_string(),
+            uid: 1000 + accounts.len() as u32,
+            home: format!("/home/{}", user_name),
+            shell: shell.to_string(),
+            groups: Vec::new(),
+            locked: false,
+        };
+        accounts.insert(user_name.to_string(), (account, keys.to_string()));
+        Ok(())
+    }
+
+    fn update_keys(&self, user_name: &str, keys: &str, mode: KeyMode) -> Result<()> {
+        self.modify(user_name, |x| {
+            x.1 = match mode {
+                KeyMode::Merge => merge_authorized_keys(&x.1, keys),
+                KeyMode::Replace => merge_authorized_keys("", keys),
+            }
+        })
+    }
+
+    fn join_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        self.modify(user_name, |x| x.0.groups.push(group_name.to_string()))
+    }
+
+    fn leave_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        self.modify(user_name, |x| x.0.groups.retain(|g| g != group_name))
+    }
+
+    fn archive_home(&self, user_name: &str) -> Result<String> {
+        self.query(user_name)?.ok_or_else(Self::not_found)?;
+        Ok(format!("/archive/{}.tar.gz", user_name))
+    }
+
+    fn delete(&self, user_name: &str) -> Result<()> {
+        self.accounts
+            .borrow_mut()
+            .remove(user_name)
+            .map(|_| ())
+            .ok_or_else(Self::not_found)
+    }
+
+    fn lock(&self, user_name: &str) -> Result<()> {
+        self.modify(user_name, |x| x.0.locked = true)
+    }
+
+    fn unlock(&self, user_name: &str) -> Result<()> {
+        self.modify(user_name, |x| x.0.locked = false)
+    }
+
+    fn query(&self, user_name: &str) -> Result<Option<Account>> {
+        Ok(self.accounts.borrow().get(user_name).map(|x| x.0.clone()))
+    }
+
+    fn group_names(&self) -> Result<Vec<String>> {
+        Ok(vec!["docker".to_string(), "sudo".to_string()])
+    }
+
+    fn group_id(&self, group_name: &str) -> Result<Option<u32>> {
+        Ok(GROUP_IDS.iter().find(|x| x.0 == group_name).map(|x| x.1))
+    }
+
+    fn login_shells(&self) -> Result<Vec<String>> {
+        Ok(vec![DEFAULT_SHELL.to_string()])
+    }
+}
+
+/// テストごとに異なる、状態を保存するディレクトリ
+fn state_directory() -> String {
+    static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let directory = std::env::temp_dir().join(format!(
+        "sacana-test-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&directory);
+    directory.to_str().unwrap().to_string()
+}
+
+/// mock に接続する CommandHandler
+/// 初回の起動と同様に、 backend に既にあるアカウントを表示名が一致するユーザーに対応付ける
+fn command_handler(
+    mock: &MockSlack,
+    mode: &WebSocketMode,
+    backend: Box<dyn AccountBackend>,
+) -> CommandHandler {
+    let slack = mock.client();
+    let directory = state_directory();
+    let command_handler = CommandHandler {
+        pic_of_response: true,
+        interactive: !matches!(mode, WebSocketMode::Rtm),
+        channels: check_channels(&slack, &[CHANNEL_NAME.to_string()]).unwrap(),
+        users: get_users(&slack).unwrap(),
+        slack,
+        backend,
+        local_host_name: LOCAL_HOST_NAME.to_string(),
+        hosts: vec![LOCAL_HOST_NAME.to_string(), OTHER_HOST_NAME.to_string()],
+        roles: Roles::default(),
+        group_policy: GroupPolicy::default(),
+        create_requires_approval: false,
+        logins: RefCell::new(Store::open(&directory, LOGINS_FILE).unwrap()),
+        default_key_mode: KeyMode::Replace,
+        key_modes: RefCell::new(Store::open(&directory, KEY_MODES_FILE).unwrap()),
+        slack_keys: RefCell::new(Store::open(&directory, SLACK_KEYS_FILE).unwrap()),
+        pending_requests: RefCell::new(Store::open(&directory, PENDING_REQUESTS_FILE).unwrap()),
+        deactivation_locks: RefCell::new(Store::open(&directory, DEACTIVATION_LOCKS_FILE).unwrap()),
+        admin_channel: None,
+        // users.list との照合は必要なテストでのみ行う
+        last_reconciled: Some(std::time::Instant::now()),
+        my_id: String::new(),
+        key_sources: KeySources::from_uri_format(&mock.public_key_uri_format()),
+        key_owner: KeyOwner::DisplayName,
+        key_policy: KeyPolicy::default(),
+        seen_events: SeenEvents::default(),
+        last_timestamp: None,
+    };
+    command_handler.seed_logins().unwrap();
+    command_handler
+}
+
+/// 設定ファイルの roles を解決して command_handler に設定する
+fn set_roles(command_handler: &mut CommandHandler, settings: serde_json::Value) {
+    command_handler.roles = Roles::from_settings(&settings, Vec::new()).unwrap();
+    command_handler.roles.resolve(&command_handler.slack);
+}
+
+/// mode で接続し、 WebSocket が切断されるまでイベントを処理する
+fn receive(mode: WebSocketMode, mut command_handler: CommandHandler) -> CommandHandler {
+    let (my_id, mut client) = mode.connect(&command_handler.slack).unwrap();
+    command_handler.my_id = my_id;
+    command_handler.report_startup().unwrap();
+    assert!(receive_websocket(&mut command_handler, &mode, &mut client).is_err());
+    command_handler
+}
+
+/// mode で mock に接続し、 WebSocket が切断されるまでイベントを処理する
+fn run(mock: &MockSlack, mode: WebSocketMode, backend: Box<dyn AccountBackend>) -> CommandHandler {
+    let command_handler = command_handler(mock, &mode, backend);
+    receive(mode, command_handler)
+}
+
+/// channel へのメンション
+fn mention(text: &str) -> serde_json::Value {
+    message(
+        CHANNEL_ID,
+        &format!("<@{}> {}", BOT_ID, text),
+        "1700000000.000100",
+    )
+}
+
+/// user から channel へのメンション
+fn mention_from(user: &str, text: &str) -> serde_json::Value {
+    let mut event = mention(text);
+    event["user"] = json!(user);
+    event
+}
+
+fn texts(calls: &[serde_json::Value]) -> Vec<&str> {
+    calls.iter().map(|x| x["text"].as_str().unwrap()).collect()
+}
+
+#[test]
+fn rtm_ping_replies_in_thread() {
+    let mock = MockSlack::start(vec![json!({ "type": "hello" }), mention("ping")]);
+    let command_handler = run(&mock, WebSocketMode::Rtm, Box::<MemoryBackend>::default());
+    assert_eq!(command_handler.my_id, BOT_ID);
+    let posts = mock.calls("chat.postMessage");
+    assert_eq!(
+        texts(&posts),
+        ["Hello, this is sacana@host1.", "pong@host1"]
+    );
+    assert_eq!(posts[1]["channel"], CHANNEL_ID);
+    assert_eq!(posts[1]["thread_ts"], "1700000000.000100");
+}
+
+#[test]
+fn rtm_create_with_invalid_host_is_rejected() {
+    let mock = MockSlack::start(vec![mention("create nohost")]);
+    run(&mock, WebSocketMode::Rtm, Box::<MemoryBackend>::default());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!("<@{}> Invalid hostname 'nohost'.", USER_ID)]
+    );
+    let reactions = mock.calls("reactions.add");
+    assert_eq!(reactions.len(), 1);
+    assert_eq!(reactions[0]["name"], "x");
+    assert_eq!(reactions[0]["timestamp"], "1700000000.000100");
+    assert_eq!(mock.calls("chat.postEphemeral")[0]["user"], USER_ID);
+}
+
+#[test]
+fn rtm_create_for_other_host_is_left_to_it() {
+    let mock = MockSlack::start(vec![mention(&format!("create {}", OTHER_HOST_NAME))]);
+    run(&mock, WebSocketMode::Rtm, Box::<MemoryBackend>::default());
+    assert_eq!(mock.calls("chat.postMessage").len(), 1);
+    assert!(mock.calls("reactions.add").is_empty());
+}
+
+#[test]
+fn rtm_help_at_dm_posts_blocks() {
+    let mock = MockSlack::start(vec![message(DM_ID, "help", "1700000000.000100")]);
+    run(&mock, WebSocketMode::Rtm, Box::<MemoryBackend>::default());
+    let ephemerals = mock.calls("chat.postEphemeral");
+    assert_eq!(ephemerals.len(), 1);
+    assert_eq!(ephemerals[0]["channel"], DM_ID);
+    let blocks: serde_json::Value = ephemerals[0]["blocks"].as_str().unwrap().parse().unwrap();
+    assert!(blocks.is_array());
+    assert_eq!(
+        mock.calls("reactions.add")[0]["name"],
+        "ballot_box_with_check"
+    );
+}
+
+#[test]
+fn socket_mode_acknowledges_envelopes() {
+    let mut mock = MockSlack::start(vec![
+        json!({ "type": "hello" }),
+        events_api_envelope("envelope-1", mention("ping")),
+    ]);
+    run(
+        &mock,
+        WebSocketMode::SocketMode {
+            app_token: "xapp-mock".to_string(),
+        },
+        Box::<MemoryBackend>::default(),
+    );
+    assert_eq!(
+        mock.websocket_messages(),
+        [json!({ "envelope_id": "envelope-1" })]
+    );
+    assert_eq!(texts(&mock.calls("chat.postMessage"))[1..], ["pong@host1"]);
+}
+
+#[test]
+fn socket_mode_ignores_redelivered_envelopes_and_events() {
+    let with_event_id = |envelope_id: &str, event_id: &str, client_msg_id: &str| {
+        let mut event = mention("ping");
+        event["client_msg_id"] = json!(client_msg_id);
+        let mut envelope = events_api_envelope(envelope_id, event);
+        envelope["payload"]["event_id"] = json!(event_id);
+        envelope
+    };
+    let mut mock = MockSlack::start(vec![
+        with_event_id("envelope-1", "Ev1", "message-1"),
+        // 同じ envelope の再送
+        with_event_id("envelope-1", "Ev1", "message-1"),
+        // 同じイベントを新しい envelope で再送
+        with_event_id("envelope-2", "Ev1", "message-1"),
+        // 同じメッセージの別のイベント
+        with_event_id("envelope-3", "Ev2", "message-1"),
+        with_event_id("envelope-4", "Ev3", "message-2"),
+    ]);
+    run(
+        &mock,
+        WebSocketMode::SocketMode {
+            app_token: "xapp-mock".to_string(),
+        },
+        Box::<MemoryBackend>::default(),
+    );
+    // 再送された envelope にも応答する
+    assert_eq!(mock.websocket_messages().len(), 5);
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        ["pong@host1", "pong@host1"]
+    );
+}
+
+#[test]
+fn socket_mode_slash_command_for_other_host() {
+    let mock = MockSlack::start_with(|mock| {
+        vec![slash_command_envelope(
+            "envelope-1",
+            CHANNEL_ID,
+            &format!("create {}", OTHER_HOST_NAME),
+            &mock.response_url(),
+        )]
+    });
+    run(
+        &mock,
+        WebSocketMode::SocketMode {
+            app_token: "xapp-mock".to_string(),
+        },
+        Box::<MemoryBackend>::default(),
+    );
+    assert_eq!(
+        texts(&mock.calls("response")),
+        ["sacana@host1 can't handle commands for 'host2'."]
+    );
+}
+
+#[test]
+fn slack_client_returns_api_error_when_ok_is_false() {
+    let mock = MockSlack::start(Vec::new());
+    match mock.client().post_message(NOT_JOINED_CHANNEL_ID, "hello") {
+        Err(Error::Slack(SlackError::Api { method, error })) => {
+            assert_eq!(method, "chat.postMessage");
+            assert_eq!(error, "not_in_channel");
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("not_in_channel is ignored"),
+    }
+    // 一時的でないエラーは再送しない
+    assert_eq!(mock.calls("chat.postMessage").len(), 1);
+}
+
+#[test]
+fn slack_client_retries_after_rate_limit_and_server_errors() {
+    let mock = MockSlack::start(Vec::new());
+    let slack = mock.client();
+    // 429 は Retry-After の秒数だけ待って再送する
+    mock.fail("auth.test", &[429]);
+    let start = std::time::Instant::now();
+    slack.auth_test().unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+    assert_eq!(mock.calls("auth.test").len(), 2);
+    // サーバーのエラーは指数関数的に待ち時間を増やして再送する
+    mock.fail("conversations.history", &[500, 503]);
+    let start = std::time::Instant::now();
+    slack.conversations_history(CHANNEL_ID, &None).unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(500 + 1000));
+    assert_eq!(mock.calls("conversations.history").len(), 3);
+    // 再送の回数には上限がある
+    mock.fail("users.profile.get", &[500; 5]);
+    assert_eq!(
+        slack.users_profile_get(USER_ID).unwrap_err().to_string(),
+        "HTTP error: users.profile.get returned 500"
+    );
+    assert_eq!(mock.calls("users.profile.get").len(), 5);
+}
+
+#[test]
+fn slack_client_does_not_repeat_posts_after_server_errors() {
+    let mock = MockSlack::start(Vec::new());
+    let slack = mock.client();
+    // 投稿されたかどうか分からないサーバーのエラーでは再送しない
+    mock.fail("chat.postMessage", &[500]);
+    assert_eq!(
+        slack
+            .post_message(CHANNEL_ID, "hello")
+            .unwrap_err()
+            .to_string(),
+        "HTTP error: chat.postMessage returned 500"
+    );
+    assert_eq!(mock.calls("chat.postMessage").len(), 1);
+    // rate limit の場合は投稿されていないため再送する
+    mock.fail("chat.postMessage", &[429]);
+    slack.post_message(CHANNEL_ID, "hello").unwrap();
+    assert_eq!(mock.calls("chat.postMessage").len(), 3);
+    // 投稿の間隔はチャンネルごとに数える
+    let slack = mock.paced_client();
+    slack.post_message(CHANNEL_ID, "hello").unwrap();
+    let start = std::time::Instant::now();
+    slack.post_message(DM_ID, "hello").unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    slack.post_message(DM_ID, "hello").unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn slack_min_intervals_are_read_from_settings() {
+    let intervals =
+        slack::min_intervals_from_settings(&json!({ "users.list": 5000, "*": 0 })).unwrap();
+    assert_eq!(
+        intervals["users.list"],
+        std::time::Duration::from_millis(5000)
+    );
+    assert_eq!(intervals["*"], std::time::Duration::ZERO);
+    assert!(slack::min_intervals_from_settings(&json!(null))
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        slack::min_intervals_from_settings(&json!({ "users.list": "5s" }))
+            .unwrap_err()
+            .to_string(),
+        "slack_min_intervals of `users.list` isn't milliseconds"
+    );
+}
+
+const SIGNING_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+
+/// SIGNING_SECRET による X-Slack-Signature
+fn slack_signature(timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_SECRET.as_bytes()).unwrap();
+    mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+    let digest = mac.finalize().into_bytes();
+    format!(
+        "v0={}",
+        digest
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>()
+    )
+}
+
+#[test]
+fn events_api_signature_is_verified() {
+    let body = r#"{"type":"event_callback"}"#;
+    let now = chrono::Utc::now().timestamp().to_string();
+    let verify = |timestamp: &str, signature: &str| {
+        events_api::verify_signature(SIGNING_SECRET, timestamp, body.as_bytes(), signature)
+            .map_err(|e| e.to_string())
+    };
+    assert_eq!(verify(&now, &slack_signature(&now, body)), Ok(()));
+    // 本文を書き換えた場合や別の secret で署名した場合は一致しない
+    assert_eq!(
+        verify(&now, &slack_signature(&now, r#"{"type":"other"}"#)),
+        Err("request signature mismatch".to_string())
+    );
+    assert_eq!(
+        verify(&now, "v0=not-hex"),
+        Err("request signature mismatch".to_string())
+    );
+    // 署名が正しくても古いリクエストは受け付けない
+    let stale = (chrono::Utc::now().timestamp() - 60 * 10).to_string();
+    assert_eq!(
+        verify(&stale, &slack_signature(&stale, body)),
+        Err(format!("request timestamp {} is too old", stale))
+    );
+}
+
+#[test]
+fn events_api_rejects_large_or_stale_requests_before_verifying() {
+    let address = format!(
+        "127.0.0.1:{}",
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    );
+    let mut receiver = events_api::Receiver::bind(&address, SIGNING_SECRET.to_string()).unwrap();
+    let url = format!("http://{}/", address);
+    let now = chrono::Utc::now().timestamp();
+    let large = format!(
+        r#"{{"type":"event_callback","text":"{}"}}"#,
+        "a".repeat(1024 * 1024)
+    );
+    let requests = vec![
+        (now.to_string(), large),
+        (
+            (now - 60 * 10).to_string(),
+            r#"{"type":"event_callback"}"#.to_string(),
+        ),
+    ];
+    let count = requests.len();
+    let client = std::thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        requests
+            .into_iter()
+            .map(|(timestamp, body)| {
+                client
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .header("X-Slack-Signature", slack_signature(&timestamp, &body))
+                    .header("X-Slack-Request-Timestamp", timestamp)
+                    .body(body)
+                    .send()
+                    .unwrap()
+                    .status()
+                    .as_u16()
+            })
+            .collect::<Vec<_>>()
+    });
+    for _ in 0..count {
+        assert!(receiver
+            .recv(std::time::Duration::from_secs(10))
+            .unwrap()
+            .is_none());
+    }
+    assert_eq!(client.join().unwrap(), [413, 401]);
+}
+
+#[test]
+fn events_api_answers_challenge_and_ignores_redelivered_events() {
+    let address = format!(
+        "127.0.0.1:{}",
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    );
+    let mut receiver = events_api::Receiver::bind(&address, SIGNING_SECRET.to_string()).unwrap();
+    let event = |event_id: &str| {
+        json!({
+            "type": "event_callback",
+            "event_id": event_id,
+            "event": { "type": "app_mention", "text": event_id },
+        })
+        .to_string()
+    };
+    let requests = vec![
+        (
+            json!({ "type": "url_verification", "challenge": "challenge-1" }).to_string(),
+            None,
+            true,
+        ),
+        (event("Ev1"), None, true),
+        // 受け取り済みのイベントの再送は無視し、受け取れなかったイベントの再送は処理する
+        (event("Ev1"), Some("1"), true),
+        (event("Ev2"), Some("1"), true),
+        (event("Ev3"), None, false),
+    ];
+    let count = requests.len();
+    let url = format!("http://{}/", address);
+    let client = std::thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        requests
+            .into_iter()
+            .map(|(body, retry_num, signed)| {
+                let timestamp = chrono::Utc::now().timestamp().to_string();
+                let signature = if signed {
+                    slack_signature(&timestamp, &body)
+                } else {
+                    slack_signature(&timestamp, "")
+                };
+                let mut request = client
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .header("X-Slack-Request-Timestamp", timestamp)
+                    .header("X-Slack-Signature", signature);
+                if let Some(n) = retry_num {
+                    request = request.header("X-Slack-Retry-Num", n);
+                }
+                let response = request.body(body).send().unwrap();
+                (response.status().as_u16(), response.text().unwrap())
+            })
+            .collect::<Vec<_>>()
+    });
+    let events = (0..count)
+        .map(
+            |_| match receiver.recv(std::time::Duration::from_secs(10)).unwrap() {
+                Some(events_api::Callback::Event(e)) => {
+                    Some(e["text"].as_str().unwrap().to_string())
+                }
+                _ => None,
+            },
+        )
+        .collect::<Vec<_>>();
+    assert_eq!(
+        client.join().unwrap(),
+        [
+            (200, "challenge-1".to_string()),
+            (200, String::new()),
+            (200, String::new()),
+            (200, String::new()),
+            (401, String::new()),
+        ]
+    );
+    assert_eq!(
+        events,
+        [
+            None,
+            Some("Ev1".to_string()),
+            None,
+            Some("Ev2".to_string()),
+            None
+        ]
+    );
+}
+
+#[test]
+fn rtm_create_at_local_host_creates_account() {
+    let mock = MockSlack::start(vec![mention(&format!("create {}", LOCAL_HOST_NAME))]);
+    let backend = MemoryBackend::default();
+    run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert_eq!(mock.calls(&format!("keys/{}", USER_NAME)).len(), 1);
+    let (account, keys) = backend.accounts.borrow()[USER_NAME].clone();
+    assert_eq!(account.shell, DEFAULT_SHELL);
+    assert_eq!(keys, installed_public_key(&public_key_source(&mock)));
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{}> creating account is succeeded.{}",
+            USER_ID,
+            public_key_report(&public_key_source(&mock))
+        )]
+    );
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "o");
+}
+
+#[test]
+fn rtm_update_rejects_invalid_and_weak_keys() {
+    let backend = MemoryBackend::default();
+    backend.create(USER_NAME, DEFAULT_SHELL, "").unwrap();
+    let mock = MockSlack::start(vec![mention(&format!("update {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_uri_format(&mock.mixed_keys_uri_format());
+    receive(WebSocketMode::Rtm, command_handler);
+    let source = mock.mixed_keys_uri_format().replace("{}", USER_NAME);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        merge_authorized_keys("", &installed_public_key(&source))
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{0}> updating key is succeeded.{1}\nRejected 4 line(s):\n```\n\
+             line 1 of {2}: unknown key type `<html>`\n\
+             line 2 of {2}: key data is not valid base64\n\
+             line 3 of {2}: ssh-rsa SHA256:HcpRkXhFkldqqW+BlIcMcC5T2ub900pVhroRp+MwBeA alice@old: \
+             RSA keys must be at least 3072 bits, but this key is 2048 bits\n\
+             line 4 of {2}: ssh-dss SHA256:g9hbwLpCrZ6etRhpgFWsdVs5FDDC0LApze9ayOEDE6M alice@dsa: \
+             DSA keys are not allowed\n```",
+            USER_ID,
+            public_key_report(&source),
+            source
+        )]
+    );
+}
+
+#[test]
+fn rtm_create_refuses_page_without_keys() {
+    let mock = MockSlack::start(vec![mention(&format!("create {}", LOCAL_HOST_NAME))]);
+    let backend = MemoryBackend::default();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_uri_format(&mock.html_uri_format());
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.accounts.borrow().is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{0}> no valid public key is found in `{1}`\n```\n\
+             line 1 of {1}: unknown key type `<html>`\n\
+             line 2 of {1}: unknown key type `<body>Not`\n\
+             line 3 of {1}: unknown key type `</html>`\n```",
+            USER_ID,
+            mock.html_uri_format().replace("{}", USER_NAME)
+        )]
+    );
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "x");
+}
+
+/// PUBLIC_KEY とは別の公開鍵とその SHA256 のフィンガープリント
+const LAPTOP_PUBLIC_KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFLyJmWmDBLSiRhdlQ7ogTYJFm9rET0XjWwP05Af8jmh alice@laptop";
+const LAPTOP_PUBLIC_KEY_FINGERPRINT: &str = "SHA256:zNVryZZNUBieceZfNOqb6lKe4QDE+wnvoC3G83B0HVA";
+
+/// `<USER_NAME>.pub` に LAPTOP_PUBLIC_KEY を書き込んだディレクトリ
+fn key_directory() -> String {
+    let directory = state_directory();
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(
+        format!("{}/{}.pub", directory, USER_NAME),
+        format!("{}\n", LAPTOP_PUBLIC_KEY),
+    )
+    .unwrap();
+    directory
+}
+
+#[test]
+fn rtm_update_collects_keys_from_all_sources_in_union_mode() {
+    let directory = key_directory();
+    let backend = MemoryBackend::default();
+    backend.create(USER_NAME, DEFAULT_SHELL, "").unwrap();
+    let mock = MockSlack::start(vec![mention(&format!("update {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_settings(
+        &json!([
+            { "directory": directory },
+            { "uri": mock.html_uri_format() },
+            { "uri": mock.public_key_uri_format() },
+        ]),
+        &json!("union"),
+        None,
+    )
+    .unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    let file = format!("{}/{}.pub", directory, USER_NAME);
+    let html = mock.html_uri_format().replace("{}", USER_NAME);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        merge_authorized_keys(
+            "",
+            &format!(
+                "# from {}\n{}\n{}",
+                file,
+                LAPTOP_PUBLIC_KEY,
+                installed_public_key(&public_key_source(&mock))
+            )
+        )
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{0}> updating key is succeeded.\nAccepted 2 key(s):\n```\n\
+             ssh-ed25519 {1} alice@laptop (from {2})\n\
+             ssh-ed25519 {3} alice@mock (from {4})\n```\n\
+             Rejected 3 line(s):\n```\n\
+             line 1 of {5}: unknown key type `<html>`\n\
+             line 2 of {5}: unknown key type `<body>Not`\n\
+             line 3 of {5}: unknown key type `</html>`\n```",
+            USER_ID,
+            LAPTOP_PUBLIC_KEY_FINGERPRINT,
+            file,
+            PUBLIC_KEY_FINGERPRINT,
+            public_key_source(&mock),
+            html
+        )]
+    );
+}
+
+#[test]
+fn rtm_update_uses_first_source_with_keys_in_first_match_mode() {
+    let empty_directory = state_directory();
+    let backend = MemoryBackend::default();
+    backend.create(USER_NAME, DEFAULT_SHELL, "").unwrap();
+    let mock = MockSlack::start(vec![mention(&format!("update {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_settings(
+        &json!([
+            { "directory": empty_directory },
+            { "uri": mock.public_key_uri_format() },
+            { "directory": key_directory() },
+        ]),
+        &json!("first_match"),
+        None,
+    )
+    .unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        merge_authorized_keys("", &installed_public_key(&public_key_source(&mock)))
+    );
+    let posts = mock.calls("chat.postMessage");
+    let text = texts(&posts)[1];
+    assert!(text.contains(&public_key_report(&public_key_source(&mock))));
+    assert!(text.contains(&format!(
+        "get public key from {}/{}.pub failed",
+        empty_directory, USER_NAME
+    )));
+    match KeySources::from_settings(&json!(null), &json!(null), None) {
+        Err(e) => assert_eq!(
+            e.to_string(),
+            "public_key_uri_format or key_sources is required"
+        ),
+        Ok(_) => panic!("settings without key sources are accepted"),
+    }
+}
+
+#[test]
+fn rtm_key_added_by_dm_is_installed_without_other_sources() {
+    let (key, comment) = LAPTOP_PUBLIC_KEY.rsplit_once(' ').unwrap();
+    let mock = MockSlack::start(vec![
+        // Slackはメールアドレスのようなコメントをリンクに整形する
+        message(
+            DM_ID,
+            &format!(
+                "addkey {} {} <mailto:{2}|{2}>",
+                LOCAL_HOST_NAME, key, comment
+            ),
+            "1700000000.000100",
+        ),
+        mention(&format!("create {}", LOCAL_HOST_NAME)),
+    ]);
+    let backend = MemoryBackend::default();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_uri_format(&mock.html_uri_format());
+    let command_handler = receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        command_handler.slack_keys.borrow().get()[USER_ID],
+        [LAPTOP_PUBLIC_KEY]
+    );
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        format!("# from Slack\n{}\n", LAPTOP_PUBLIC_KEY)
+    );
+    let posts = mock.calls("chat.postMessage");
+    let texts = texts(&posts);
+    assert_eq!(
+        texts[1],
+        format!(
+            "<@{}> adding key is succeeded.\nAccepted 1 key(s):\n```\n\
+             ssh-ed25519 {} alice@laptop (from Slack)\n```\n\
+             The key(s) will be installed when you `create` your account on {}.",
+            USER_ID, LAPTOP_PUBLIC_KEY_FINGERPRINT, LOCAL_HOST_NAME
+        )
+    );
+    assert_eq!(
+        texts[2],
+        format!(
+            "<@{}> creating account is succeeded.\nAccepted 1 key(s):\n```\n\
+             ssh-ed25519 {} alice@laptop (from Slack)\n```\n\
+             Failed to fetch keys from 1 source(s):\n```\n\
+             no valid public key is found in `{}`\n```",
+            USER_ID,
+            LAPTOP_PUBLIC_KEY_FINGERPRINT,
+            mock.html_uri_format().replace("{}", USER_NAME)
+        )
+    );
+}
+
+#[test]
+fn rtm_keys_added_by_dm_are_used_only_for_the_sender() {
+    let mock = MockSlack::start(vec![
+        mention(&format!("create {}", LOCAL_HOST_NAME)),
+        mention(&format!("unmap <@{}> {}", OTHER_USER_ID, LOCAL_HOST_NAME)),
+    ]);
+    let backend = MemoryBackend::default();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    set_roles(
+        &mut command_handler,
+        json!({ "admin": { "users": [USER_ID] } }),
+    );
+    // 他のユーザーが登録した鍵は、アカウント名によらず使わない
+    command_handler
+        .slack_keys
+        .borrow_mut()
+        .update(|x| {
+            x.insert(
+                OTHER_USER_ID.to_string(),
+                vec![LAPTOP_PUBLIC_KEY.to_string()],
+            )
+        })
+        .unwrap();
+    let command_handler = receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        installed_public_key(&public_key_source(&mock))
+    );
+    // unmap してもそのユーザーが登録した鍵は削除しない
+    assert_eq!(
+        command_handler.slack_keys.borrow().get()[OTHER_USER_ID],
+        [LAPTOP_PUBLIC_KEY]
+    );
+}
+
+#[test]
+fn rtm_key_file_sent_by_dm_updates_existing_account() {
+    let backend = MemoryBackend::default();
+    backend.create(USER_NAME, DEFAULT_SHELL, "").unwrap();
+    let mock = MockSlack::start_with(|mock| {
+        let mut event = message(
+            DM_ID,
+            &format!("addkey {}", LOCAL_HOST_NAME),
+            "1700000000.000100",
+        );
+        event["subtype"] = json!("file_share");
+        event["files"] = json!([{
+            "name": "id_ed25519.pub",
+            "size": PUBLIC_KEY.len(),
+            "url_private_download": mock.file_url("id_ed25519.pub"),
+        }]);
+        vec![event]
+    });
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_sources = KeySources::from_uri_format(&mock.html_uri_format());
+    receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(mock.calls("files/id_ed25519.pub").len(), 1);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        merge_authorized_keys("", &installed_public_key(SLACK_KEY_SOURCE))
+    );
+    let posts = mock.calls("chat.postMessage");
+    assert!(texts(&posts)[1].contains(&format!(
+        "\nupdating key is succeeded.{}",
+        public_key_report(SLACK_KEY_SOURCE)
+    )));
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "o");
+}
+
+#[test]
+fn slack_files_are_downloaded_only_from_slack_within_size() {
+    let mock = MockSlack::start(Vec::new());
+    let slack = mock.client();
+    assert_eq!(
+        slack
+            .download_file(&mock.file_url("id_ed25519.pub"), 16 * 1024)
+            .unwrap(),
+        PUBLIC_KEY
+    );
+    // 申告されたサイズによらず、上限を超えた時点で読むのをやめる
+    assert_eq!(
+        slack
+            .download_file(&mock.file_url("id_ed25519.pub"), 16)
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "file {} is larger than 16 bytes",
+            mock.file_url("id_ed25519.pub")
+        )
+    );
+    // Slack以外のURLにはトークンを送らない
+    let url = mock.public_key_uri_format().replace("{}", USER_NAME);
+    assert_eq!(
+        slack
+            .download_file(&url, 16 * 1024)
+            .unwrap_err()
+            .to_string(),
+        format!("file URL {} isn't on Slack", url)
+    );
+    assert!(mock.calls(&format!("keys/{}", USER_NAME)).is_empty());
+}
+
+#[test]
+fn rtm_create_refuses_blocked_key() {
+    let mock = MockSlack::start(vec![mention(&format!("create {}", LOCAL_HOST_NAME))]);
+    let backend = MemoryBackend::default();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_policy =
+        KeyPolicy::from_settings(&json!({ "blocked_fingerprints": [PUBLIC_KEY_FINGERPRINT] }))
+            .unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.accounts.borrow().is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{0}> no valid public key is found in `{1}`\n```\n\
+             line 1 of {1}: ssh-ed25519 {2} alice@mock: this key is known to be compromised\n```",
+            USER_ID,
+            public_key_source(&mock),
+            PUBLIC_KEY_FINGERPRINT
+        )]
+    );
+}
+
+#[test]
+fn rtm_join_without_account_fails() {
+    let mock = MockSlack::start(vec![mention(&format!("join docker {}", LOCAL_HOST_NAME))]);
+    let backend = MemoryBackend::default();
+    run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert!(backend.accounts.borrow().is_empty());
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "x");
+}
+
+#[test]
+fn rtm_create_in_dry_run_replies_planned_actions() {
+    let mock = MockSlack::start(vec![mention(&format!("create {}", LOCAL_HOST_NAME))]);
+    run(
+        &mock,
+        WebSocketMode::Rtm,
+        Box::new(LinuxBackend::dry_run(
+            LOCAL_HOST_NAME.to_string(),
+            DEFAULT_ARCHIVE_DIRECTORY.to_string(),
+        )),
+    );
+    assert_eq!(mock.calls(&format!("keys/{}", USER_NAME)).len(), 1);
+    let posts = mock.calls("chat.postMessage");
+    let text = texts(&posts)[1];
+    assert!(text.starts_with(&format!("<@{}> creating account is succeeded.", USER_ID)));
+    assert!(text.contains("useradd -m -s /bin/bash -p '' alice"));
+    assert!(text.contains("write 2 line(s) to /home/alice/.ssh/authorized_keys"));
+    assert!(text.contains("chown -R alice:alice /home/alice/.ssh"));
+}
+
+#[test]
+fn authorized_keys_are_not_read_or_written_through_symlinks() {
+    use std::os::unix::fs::MetadataExt;
+    let ssh_dir = state_directory();
+    std::fs::create_dir_all(&ssh_dir).unwrap();
+    let metadata = std::fs::metadata(&ssh_dir).unwrap();
+    let path = format!("{}/authorized_keys", ssh_dir);
+    let target = format!("{}/secret", ssh_dir);
+    std::fs::write(&target, "secret\n").unwrap();
+    let not_regular = |e| matches!(e, Error::Linux(LinuxError::NotRegularFile(x)) if x == path);
+
+    assert_eq!(read_user_file(&path).unwrap(), "");
+    std::os::unix::fs::symlink(&target, &path).unwrap();
+    assert!(not_regular(read_user_file(&path).unwrap_err()));
+    // 置き換えるのはシンボリックリンク自体で、リンク先は変更しない
+    write_user_file(&ssh_dir, &path, PUBLIC_KEY, metadata.uid(), metadata.gid()).unwrap();
+    assert!(std::fs::symlink_metadata(&path).unwrap().is_file());
+    assert_eq!(read_user_file(&path).unwrap(), PUBLIC_KEY);
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "secret\n");
+    // 一時ファイルの位置に置かれたシンボリックリンクもたどらない
+    std::os::unix::fs::symlink(&target, format!("{}.sacana-tmp", path)).unwrap();
+    write_user_file(&ssh_dir, &path, "", metadata.uid(), metadata.gid()).unwrap();
+    assert_eq!(read_user_file(&path).unwrap(), "");
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "secret\n");
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::create_dir(&path).unwrap();
+    assert!(not_regular(read_user_file(&path).unwrap_err()));
+}
+
+#[test]
+fn unwritable_state_directory_is_reported_and_ignored_in_dry_run() {
+    let file = state_directory();
+    std::fs::write(&file, "").unwrap();
+    let directory = format!("{}/state", file);
+    let e = prepare_directory(&directory).unwrap_err();
+    assert!(matches!(e, Error::StateDirectory(..)));
+    assert!(e
+        .to_string()
+        .starts_with(&format!("state_directory `{}` can't be written", directory)));
+    assert!(matches!(
+        Store::<HashMap<String, String>>::open(&directory, LOGINS_FILE),
+        Err(Error::StateDirectory(..))
+    ));
+    let mut store =
+        Store::<HashMap<String, String>>::open_dry_run(&directory, LOGINS_FILE).unwrap();
+    assert!(store.get().is_empty());
+    store
+        .update(|x| x.insert(USER_ID.to_string(), USER_NAME.to_string()))
+        .unwrap();
+    assert!(store.get().is_empty());
+}
+
+#[test]
+fn dry_run_does_not_change_stored_state() {
+    let directory = state_directory();
+    Store::open(&directory, LOGINS_FILE)
+        .unwrap()
+        .update(|x: &mut HashMap<String, String>| {
+            x.insert(USER_ID.to_string(), USER_NAME.to_string())
+        })
+        .unwrap();
+    Store::open(&directory, SLACK_KEYS_FILE)
+        .unwrap()
+        .update(|x: &mut HashMap<String, Vec<String>>| {
+            x.insert(USER_ID.to_string(), vec![PUBLIC_KEY.trim().to_string()])
+        })
+        .unwrap();
+    let files = [
+        LOGINS_FILE,
+        KEY_MODES_FILE,
+        SLACK_KEYS_FILE,
+        PENDING_REQUESTS_FILE,
+        DEACTIVATION_LOCKS_FILE,
+    ];
+    let read_files = || {
+        files
+            .iter()
+            .map(|x| std::fs::read_to_string(std::path::Path::new(&directory).join(x)).ok())
+            .collect::<Vec<_>>()
+    };
+    let before = read_files();
+
+    let mock = MockSlack::start(vec![
+        message(
+            DM_ID,
+            &format!("addkey {} {}", LOCAL_HOST_NAME, LAPTOP_PUBLIC_KEY),
+            "1700000000.000100",
+        ),
+        mention_from(OTHER_USER_ID, &format!("create {}", LOCAL_HOST_NAME)),
+        mention(&format!("delete {}", LOCAL_HOST_NAME)),
+    ]);
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.logins = RefCell::new(Store::open_dry_run(&directory, LOGINS_FILE).unwrap());
+    command_handler.key_modes =
+        RefCell::new(Store::open_dry_run(&directory, KEY_MODES_FILE).unwrap());
+    command_handler.slack_keys =
+        RefCell::new(Store::open_dry_run(&directory, SLACK_KEYS_FILE).unwrap());
+    command_handler.pending_requests =
+        RefCell::new(Store::open_dry_run(&directory, PENDING_REQUESTS_FILE).unwrap());
+    command_handler.deactivation_locks =
+        RefCell::new(Store::open_dry_run(&directory, DEACTIVATION_LOCKS_FILE).unwrap());
+    let command_handler = receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        mock.calls("reactions.add")
+            .iter()
+            .map(|x| x["name"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        ["o", "o", "o"]
+    );
+    assert!(backend.query(OTHER_USER_NAME).unwrap().is_some());
+    assert!(backend.query(USER_NAME).unwrap().is_none());
+    // 作成したアカウントを対応付けず、削除したアカウントのSlackから登録した公開鍵も残す
+    assert_eq!(read_files(), before);
+    assert_eq!(
+        *command_handler.logins.borrow().get(),
+        [(USER_ID.to_string(), USER_NAME.to_string())]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>()
+    );
+    assert_eq!(
+        command_handler.slack_keys.borrow().get()[USER_ID],
+        [PUBLIC_KEY.trim()]
+    );
+    assert!(command_handler.key_modes.borrow().get().is_empty());
+    assert!(command_handler.pending_requests.borrow().get().is_empty());
+    assert!(command_handler.deactivation_locks.borrow().get().is_empty());
+}
+
+#[test]
+fn rtm_delete_archives_and_deletes_own_account() {
+    let mock = MockSlack::start(vec![mention(&format!("delete {}", LOCAL_HOST_NAME))]);
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let command_handler = command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler
+        .slack_keys
+        .borrow_mut()
+        .update(|x| x.insert(USER_ID.to_string(), vec![LAPTOP_PUBLIC_KEY.to_string()]))
+        .unwrap();
+    let command_handler = receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.accounts.borrow().is_empty());
+    // Slackから登録した公開鍵も削除する
+    assert!(command_handler.slack_keys.borrow().get().is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{}> deleting account `alice` is succeeded. the home directory is archived to `/archive/alice.tar.gz`.",
+            USER_ID
+        )]
+    );
+}
+
+#[test]
+fn rtm_renamed_user_cannot_operate_unbound_account() {
+    let mock = MockSlack::start(vec![
+        mention(&format!("update {}", LOCAL_HOST_NAME)),
+        mention(&format!("delete {}", LOCAL_HOST_NAME)),
+    ]);
+    let backend = MemoryBackend::default();
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    // 初回の起動より後に作成され、対応付けられていないアカウントと同じ表示名に変更した
+    backend
+        .create(DEACTIVATED_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    command_handler
+        .users
+        .insert(USER_ID.to_string(), DEACTIVATED_USER_NAME.to_string());
+    let command_handler = receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(DEACTIVATED_USER_NAME).unwrap().is_some());
+    assert!(command_handler.logins.borrow().get().is_empty());
+    let message = format!(
+        "<@{}> account `carol` already exists, but isn't bound to your Slack account. ask an admin to `map` it",
+        USER_ID
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [message.clone(), message]
+    );
+    let reactions = mock.calls("reactions.add");
+    assert!(reactions.iter().all(|x| x["name"] == "x"));
+}
+
+#[test]
+fn logins_are_seeded_with_existing_accounts() {
+    let mock = MockSlack::start(vec![]);
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let command_handler = command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend));
+    // アカウントが存在するユーザーだけを対応付ける
+    assert_eq!(
+        *command_handler.logins.borrow().get(),
+        [(OTHER_USER_ID.to_string(), OTHER_USER_NAME.to_string())]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>()
+    );
+}
+
+#[test]
+fn rtm_delete_other_user_requires_admin() {
+    let events = vec![mention(&format!(
+        "delete <@{}> {}",
+        OTHER_USER_ID, LOCAL_HOST_NAME
+    ))];
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+
+    let mock = MockSlack::start(events.clone());
+    run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert!(backend.query(OTHER_USER_NAME).unwrap().is_some());
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "x");
+
+    let mock = MockSlack::start(events);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    set_roles(
+        &mut command_handler,
+        json!({ "admin": { "users": [USER_ID] } }),
+    );
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(OTHER_USER_NAME).unwrap().is_none());
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "o");
+}
+
+#[test]
+fn rtm_lock_and_unlock_by_admin() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    for (command, locked) in [("lock", true), ("unlock", false)] {
+        let mock = MockSlack::start(vec![mention(&format!(
+            "{} <@{}> {}",
+            command, OTHER_USER_ID, LOCAL_HOST_NAME
+        ))]);
+        let mut command_handler =
+            command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+        set_roles(
+            &mut command_handler,
+            json!({ "admin": { "users": [USER_ID] } }),
+        );
+        receive(WebSocketMode::Rtm, command_handler);
+        assert_eq!(
+            backend.query(OTHER_USER_NAME).unwrap().unwrap().locked,
+            locked
+        );
+        assert_eq!(mock.calls("reactions.add")[0]["name"], "o");
+    }
+}
+
+#[test]
+fn rtm_locked_account_cannot_be_deleted_or_changed_by_its_user() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    backend.join_group(USER_NAME, "lab").unwrap();
+    backend.lock(USER_NAME).unwrap();
+    let mock = MockSlack::start(vec![
+        mention(&format!("delete {}", LOCAL_HOST_NAME)),
+        mention(&format!("join docker {}", LOCAL_HOST_NAME)),
+        mention(&format!("leave lab {}", LOCAL_HOST_NAME)),
+    ]);
+    run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    // 調査のためにデータを残す
+    let account = backend.query(USER_NAME).unwrap().unwrap();
+    assert!(account.locked);
+    assert_eq!(account.groups, ["lab"]);
+    let message = format!(
+        "<@{}> account `alice` is locked on host1. ask an operator to unlock it",
+        USER_ID
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [message.clone(), message.clone(), message]
+    );
+}
+
+#[test]
+fn lock_state_is_read_from_shadow() {
+    let today = 20000;
+    // usermod -L -e 1 でロックしたアカウント
+    assert!(linux_user_manage::shadow_locked(
+        "alice:!:19000:0:99999:7::1:",
+        today
+    ));
+    // パスワードのロックのみ、または有効期限のみの場合はロックとみなさない
+    assert!(!linux_user_manage::shadow_locked(
+        "alice:!:19000:0:99999:7:::",
+        today
+    ));
+    assert!(!linux_user_manage::shadow_locked(
+        "alice::19000:0:99999:7::1:",
+        today
+    ));
+    assert!(!linux_user_manage::shadow_locked(
+        "alice:!:19000:0:99999:7::20001:",
+        today
+    ));
+}
+
+#[test]
+fn user_change_to_deactivated_locks_account() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let user_change = |id: &str, name: &str| {
+        json!({
+            "type": "user_change",
+            "user": {
+                "id": id,
+                "deleted": true,
+                "profile": { "display_name_normalized": name },
+            },
+        })
+    };
+    let mock = MockSlack::start(vec![
+        user_change(OTHER_USER_ID, OTHER_USER_NAME),
+        user_change(DEACTIVATED_USER_ID, DEACTIVATED_USER_NAME),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+    // 初回の起動より後に作成され、対応付けられていないアカウント
+    backend
+        .create(DEACTIVATED_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(OTHER_USER_NAME).unwrap().unwrap().locked);
+    // 対応付けが無いアカウントは表示名が一致していても lock しない
+    assert!(
+        !backend
+            .query(DEACTIVATED_USER_NAME)
+            .unwrap()
+            .unwrap()
+            .locked
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "sacana@host1 locked account `bob` because <@{}> is deactivated.",
+            OTHER_USER_ID
+        )]
+    );
+}
+
+#[test]
+fn user_change_keeps_connection_when_notification_fails() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(vec![
+        json!({
+            "type": "user_change",
+            "user": {
+                "id": OTHER_USER_ID,
+                "deleted": true,
+                "profile": { "display_name_normalized": OTHER_USER_NAME },
+            },
+        }),
+        mention("ping"),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    // bot が参加していないため通知の投稿は失敗する
+    command_handler.admin_channel = Some(NOT_JOINED_CHANNEL_ID.to_string());
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(OTHER_USER_NAME).unwrap().unwrap().locked);
+    assert_eq!(texts(&mock.calls("chat.postMessage"))[2..], ["pong@host1"]);
+}
+
+#[test]
+fn reconcile_users_locks_deactivated_accounts() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    backend
+        .create(DEACTIVATED_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(Vec::new());
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+    command_handler.reconcile_users().unwrap();
+    assert!(!backend.query(USER_NAME).unwrap().unwrap().locked);
+    assert!(
+        backend
+            .query(DEACTIVATED_USER_NAME)
+            .unwrap()
+            .unwrap()
+            .locked
+    );
+    assert_eq!(
+        command_handler.users[DEACTIVATED_USER_ID],
+        DEACTIVATED_USER_NAME
+    );
+    // 管理者が unlock したアカウントは、次の照合で再び lock も通知もしない
+    backend.unlock(DEACTIVATED_USER_NAME).unwrap();
+    command_handler.reconcile_users().unwrap();
+    assert!(
+        !backend
+            .query(DEACTIVATED_USER_NAME)
+            .unwrap()
+            .unwrap()
+            .locked
+    );
+    assert_eq!(mock.calls("chat.postMessage").len(), 1);
+}
+
+#[test]
+fn rtm_join_and_leave_group() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    for (command, groups) in [("join", vec!["lab".to_string()]), ("leave", vec![])] {
+        let mock = MockSlack::start(vec![mention(&format!(
+            "{} lab {}",
+            command, LOCAL_HOST_NAME
+        ))]);
+        run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+        assert_eq!(backend.query(USER_NAME).unwrap().unwrap().groups, groups);
+        assert_eq!(mock.calls("reactions.add")[0]["name"], "o");
+    }
+}
+
+#[test]
+fn group_policy_matches_globs_and_host_overrides() {
+    let policy = GroupPolicy::from_settings(&json!({
+        "free": ["dev-*"],
+        "approval": ["docker", "sudo"],
+        "forbidden": ["root", "wheel", "dev-secret?"],
+        "default": "approval",
+        "hosts": {
+            "host2": { "free": ["docker"], "default": "forbidden" },
+        },
+    }))
+    .unwrap();
+    let access = |host, group| policy.access(host, group, Some(1001));
+    assert_eq!(access("host1", "dev-web"), GroupAccess::Free);
+    assert_eq!(access("host1", "dev-secret1"), GroupAccess::Forbidden);
+    assert_eq!(access("host1", "docker"), GroupAccess::Approval);
+    assert_eq!(access("host1", "video"), GroupAccess::Approval);
+    assert_eq!(access("host2", "docker"), GroupAccess::Free);
+    assert_eq!(access("host2", "sudo"), GroupAccess::Approval);
+    assert_eq!(access("host2", "video"), GroupAccess::Forbidden);
+    // 特権的なグループはパターンに一致しなければ default によらず組み込みの設定に従う
+    let policy =
+        GroupPolicy::from_settings(&json!({ "free": ["docker"], "default": "free" })).unwrap();
+    assert_eq!(
+        policy.access("host1", "docker", Some(999)),
+        GroupAccess::Free
+    );
+    assert_eq!(
+        policy.access("host1", "adm", Some(4)),
+        GroupAccess::Approval
+    );
+    assert_eq!(policy.access("host1", "staff", None), GroupAccess::Approval);
+    assert_eq!(
+        policy.access("host1", "shadow", Some(42)),
+        GroupAccess::Forbidden
+    );
+    let policy = GroupPolicy::from_settings(&serde_json::Value::Null).unwrap();
+    assert_eq!(
+        policy.access("host1", "sudo", Some(27)),
+        GroupAccess::Approval
+    );
+    assert_eq!(
+        policy.access("host1", "root", Some(0)),
+        GroupAccess::Forbidden
+    );
+    assert_eq!(policy.access("host1", "lab", Some(1001)), GroupAccess::Free);
+    // パターンに一致しないシステムのグループは、 default が free でも承認が必要
+    assert_eq!(
+        policy.access("host1", "video", Some(44)),
+        GroupAccess::Approval
+    );
+    let policy = GroupPolicy::from_settings(&json!({
+        "free": ["video"],
+        "default": "forbidden",
+    }))
+    .unwrap();
+    assert_eq!(policy.access("host1", "video", Some(44)), GroupAccess::Free);
+    assert_eq!(
+        policy.access("host1", "audio", Some(29)),
+        GroupAccess::Forbidden
+    );
+}
+
+#[test]
+fn rtm_join_unlisted_system_group_requires_approval() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(vec![mention(&format!("join video {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(USER_NAME).unwrap().unwrap().groups.is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1],
+        format!(
+            "<@{}> requests to join `video` group on host1 as `alice`.",
+            USER_ID
+        )
+    );
+    assert_eq!(
+        mock.calls("reactions.add")[0]["name"],
+        "hourglass_flowing_sand"
+    );
+}
+
+#[test]
+fn rtm_join_forbidden_group_is_rejected() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(vec![mention(&format!("join sudo {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.group_policy =
+        GroupPolicy::from_settings(&json!({ "forbidden": ["sudo"] })).unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(USER_NAME).unwrap().unwrap().groups.is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{}> `sudo` group can't be joined on host1.",
+            USER_ID
+        )]
+    );
+}
+
+/// user が admin_channel の ts の投稿に reaction を付けたイベント
+fn reaction_added(user: &str, reaction: &str, ts: &str) -> serde_json::Value {
+    json!({
+        "type": "reaction_added",
+        "user": user,
+        "reaction": reaction,
+        "item": { "type": "message", "channel": CHANNEL_ID, "ts": ts },
+    })
+}
+
+#[test]
+fn rtm_join_approval_group_is_executed_after_admin_reaction() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let directory = state_directory();
+    let approval_handler = |mock: &MockSlack| {
+        let mut command_handler =
+            command_handler(mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+        set_roles(
+            &mut command_handler,
+            json!({ "admin": { "users": [OTHER_USER_ID] } }),
+        );
+        command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+        command_handler.group_policy =
+            GroupPolicy::from_settings(&json!({ "approval": ["sudo"] })).unwrap();
+        command_handler.pending_requests =
+            RefCell::new(Store::open(&directory, PENDING_REQUESTS_FILE).unwrap());
+        command_handler
+    };
+
+    let mock = MockSlack::start(vec![mention(&format!("join sudo {}", LOCAL_HOST_NAME))]);
+    receive(WebSocketMode::Rtm, approval_handler(&mock));
+    assert!(backend.query(USER_NAME).unwrap().unwrap().groups.is_empty());
+    let posts = mock.calls("chat.postMessage");
+    assert_eq!(
+        texts(&posts)[1..],
+        [
+            format!(
+                "<@{}> requests to join `sudo` group on host1 as `alice`.",
+                USER_ID
+            ),
+            format!(
+                "<@{}> join `sudo` group on host1 as `alice` requires approval by an admin. the request has been sent to the admins.",
+                USER_ID
+            ),
+        ]
+    );
+    // RTM ではボタンを使えない
+    let blocks: serde_json::Value = posts[1]["blocks"].as_str().unwrap().parse().unwrap();
+    assert_eq!(blocks.as_array().unwrap().len(), 2);
+    assert_eq!(
+        mock.calls("reactions.add")[0]["name"],
+        "hourglass_flowing_sand"
+    );
+
+    // 再起動後も申請は保存されており、管理者以外のリアクションは無視される
+    let mock = MockSlack::start(vec![
+        reaction_added(USER_ID, APPROVE_REACTION, POSTED_TS),
+        reaction_added(OTHER_USER_ID, APPROVE_REACTION, POSTED_TS),
+    ]);
+    let command_handler = receive(WebSocketMode::Rtm, approval_handler(&mock));
+    assert_eq!(backend.query(USER_NAME).unwrap().unwrap().groups, ["sudo"]);
+    assert!(command_handler.pending_requests.borrow().get().is_empty());
+    let posts = mock.calls("chat.postMessage");
+    assert_eq!(
+        texts(&posts)[1..],
+        [format!(
+            "<@{}> your request to join `sudo` group on host1 as `alice` was approved by <@{}>.\n:o: joined sudo group.",
+            USER_ID, OTHER_USER_ID
+        )]
+    );
+    assert_eq!(posts[1]["thread_ts"], "1700000000.000100");
+    assert_eq!(mock.calls("chat.update")[0]["ts"], POSTED_TS);
+}
+
+#[test]
+fn socket_mode_help_buttons_run_commands_on_local_host() {
+    let mock = MockSlack::start_with(|mock| {
+        vec![
+            button_envelope(
+                "envelope-1",
+                USER_ID,
+                CREATE_ACTION_ID,
+                POSTED_TS,
+                &mock.response_url(),
+            ),
+            button_envelope(
+                "envelope-2",
+                USER_ID,
+                JOIN_ACTION_ID,
+                POSTED_TS,
+                &mock.response_url(),
+            ),
+        ]
+    });
+    let backend = MemoryBackend::default();
+    let mode = WebSocketMode::SocketMode {
+        app_token: "xapp-mock".to_string(),
+    };
+    let command_handler = command_handler(&mock, &mode, Box::new(backend.clone()));
+    let blocks = make_help_message(
+        BOT_ID,
+        &command_handler.channels,
+        "",
+        &command_handler.hosts,
+        LOCAL_HOST_NAME,
+        true,
+        true,
+    );
+    // ホストは選ばせず、グループだけを選ばせる
+    let selects = blocks
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["block_id"] == SELECT_BLOCK_ID)
+        .unwrap();
+    assert_eq!(selects["elements"].as_array().unwrap().len(), 1);
+    assert_eq!(selects["elements"][0]["action_id"], GROUP_ACTION_ID);
+    assert_eq!(selects["elements"][0]["type"], "external_select");
+    receive(mode, command_handler);
+    assert!(backend.query(USER_NAME).unwrap().is_some());
+    let responses = mock.calls("response");
+    let responses = texts(&responses);
+    assert!(responses[0].starts_with("creating account is succeeded."));
+    assert_eq!(responses[1], "Please select _GROUPNAME_.");
+}
+
+#[test]
+fn socket_mode_group_picker_suggests_groups_matching_query() {
+    let suggestion = |envelope_id: &str, action_id: &str, value: &str| {
+        json!({
+            "type": "interactive",
+            "envelope_id": envelope_id,
+            "payload": {
+                "type": "block_suggestion",
+                "user": { "id": USER_ID },
+                "action_id": action_id,
+                "value": value,
+            },
+        })
+    };
+    let mut mock = MockSlack::start(vec![
+        suggestion("envelope-1", GROUP_ACTION_ID, "doc"),
+        suggestion("envelope-2", REQUEST_GROUPS_ID, ""),
+    ]);
+    run(
+        &mock,
+        WebSocketMode::SocketMode {
+            app_token: "xapp-mock".to_string(),
+        },
+        Box::<MemoryBackend>::default(),
+    );
+    assert_eq!(
+        mock.websocket_messages(),
+        [
+            json!({ "envelope_id": "envelope-1", "payload": { "options": [option("docker")] } }),
+            json!({
+                "envelope_id": "envelope-2",
+                "payload": { "options": [option("docker"), option("sudo")] },
+            }),
+        ]
+    );
+    // 選択肢が多い場合も、入力された文字列で絞り込めば全て選べる
+    let groups: Vec<_> = (0..150).map(|i| format!("group{}", i)).collect();
+    assert_eq!(
+        suggested_options(&groups, "")["options"]
+            .as_array()
+            .unwrap()
+            .len(),
+        block_kit::MAX_OPTIONS
+    );
+    assert_eq!(
+        suggested_options(&groups, "group149")["options"],
+        json!([option("group149")])
+    );
+}
+
+#[test]
+fn socket_mode_request_form_creates_account_on_local_host() {
+    let mock = MockSlack::start(vec![
+        view_submission_envelope(
+            "envelope-1",
+            USER_ID,
+            LOCAL_HOST_NAME,
+            USER_NAME,
+            &["lab", "sudo"],
+        ),
+        // 作成に失敗した場合は、承認が必要なグループへの参加を申請しない
+        view_submission_envelope("envelope-2", USER_ID, LOCAL_HOST_NAME, USER_NAME, &["sudo"]),
+        view_submission_envelope("envelope-3", USER_ID, OTHER_HOST_NAME, USER_NAME, &[]),
+    ]);
+    let backend = MemoryBackend::default();
+    let mode = WebSocketMode::SocketMode {
+        app_token: "xapp-mock".to_string(),
+    };
+    let mut command_handler = command_handler(&mock, &mode, Box::new(backend.clone()));
+    command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+    let command_handler = receive(mode, command_handler);
+    assert_eq!(backend.query(USER_NAME).unwrap().unwrap().groups, ["lab"]);
+    let pending_requests = command_handler.pending_requests.borrow();
+    assert_eq!(pending_requests.get().len(), 1);
+    assert!(pending_requests
+        .get()
+        .values()
+        .all(|x| matches!(&x.action, RequestedAction::Join { group } if group == "sudo")));
+    let posts = mock.calls("chat.postMessage");
+    let results: Vec<_> = texts(&posts)
+        .into_iter()
+        .filter(|x| x.starts_with('*'))
+        .collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[0].starts_with("*host1*\n:o: creating account `alice` is succeeded."));
+    assert!(results[0].contains(":hourglass_flowing_sand: "));
+    assert!(results[1].starts_with("*host1*\n:x: "));
+    assert!(!results[1].contains(":hourglass_flowing_sand: "));
+    assert_eq!(
+        results[2],
+        "*host2*\n:x: sacana@host1 can't handle requests for 'host2'."
+    );
+}
+
+#[test]
+fn socket_mode_create_on_restricted_host_is_denied_by_button() {
+    let mock = MockSlack::start_with(|mock| {
+        vec![
+            slash_command_envelope(
+                "envelope-1",
+                CHANNEL_ID,
+                &format!("create {}", LOCAL_HOST_NAME),
+                &mock.response_url(),
+            ),
+            button_envelope(
+                "envelope-2",
+                USER_ID,
+                APPROVE_ACTION_ID,
+                POSTED_TS,
+                &mock.response_url(),
+            ),
+            button_envelope(
+                "envelope-3",
+                OTHER_USER_ID,
+                DENY_ACTION_ID,
+                POSTED_TS,
+                &mock.response_url(),
+            ),
+        ]
+    });
+    let backend = MemoryBackend::default();
+    let mode = WebSocketMode::SocketMode {
+        app_token: "xapp-mock".to_string(),
+    };
+    let mut command_handler = command_handler(&mock, &mode, Box::new(backend.clone()));
+    set_roles(
+        &mut command_handler,
+        json!({ "admin": { "users": [OTHER_USER_ID] } }),
+    );
+    command_handler.admin_channel = Some(CHANNEL_ID.to_string());
+    command_handler.create_requires_approval = true;
+    receive(mode, command_handler);
+    assert!(backend.accounts.borrow().is_empty());
+    assert_eq!(
+        texts(&mock.calls("response")),
+        [
+            "create an account `alice` on host1 requires approval by an admin. the request has been sent to the admins.",
+            "Only admins can approve or deny requests.",
+        ]
+    );
+    let posts = mock.calls("chat.postMessage");
+    // slash command の申請の結果は申請者へのDMで返答する
+    assert_eq!(posts[2]["channel"], USER_ID);
+    assert_eq!(
+        posts[2]["text"],
+        format!(
+            "<@{}> your request to create an account `alice` on host1 was denied by <@{}>.",
+            USER_ID, OTHER_USER_ID
+        )
+    );
+}
+
+#[test]
+fn rtm_commands_are_gated_by_roles() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(vec![
+        mention(&format!("lock <@{}> {}", OTHER_USER_ID, LOCAL_HOST_NAME)),
+        mention(&format!("delete <@{}> {}", OTHER_USER_ID, LOCAL_HOST_NAME)),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    // 取得できないユーザーグループは無視し、他のユーザーグループと users の記述は反映する
+    set_roles(
+        &mut command_handler,
+        json!({
+            "admin": { "users": ["UADMIN"], "usergroups": ["SMISSING"] },
+            "operator": { "usergroups": [OPERATORS_USERGROUP_ID] },
+        }),
+    );
+    assert_eq!(command_handler.roles.role("UADMIN"), Some(Role::Admin));
+    assert_eq!(command_handler.roles.role(USER_ID), Some(Role::Operator));
+    assert_eq!(command_handler.roles.role(OTHER_USER_ID), Some(Role::User));
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(OTHER_USER_NAME).unwrap().unwrap().locked);
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [
+            format!("<@{}> locking account `bob` is succeeded.", USER_ID),
+            format!("<@{}> This command requires the admin role.", USER_ID),
+        ]
+    );
+}
+
+#[test]
+fn rtm_users_without_user_role_are_rejected() {
+    // user のロールが設定されている場合、記述されていないユーザーはコマンドを実行できない
+    let backend = MemoryBackend::default();
+    let mock = MockSlack::start(vec![mention(&format!("create {}", LOCAL_HOST_NAME))]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    set_roles(
+        &mut command_handler,
+        json!({ "user": { "users": [OTHER_USER_ID] } }),
+    );
+    receive(WebSocketMode::Rtm, command_handler);
+    assert!(backend.query(USER_NAME).unwrap().is_none());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{}> This command requires the user role.",
+            USER_ID
+        )]
+    );
+}
+
+#[test]
+fn rtm_renamed_user_cannot_use_bound_login() {
+    let backend = MemoryBackend::default();
+    let mock = MockSlack::start(vec![
+        mention(&format!("create {}", LOCAL_HOST_NAME)),
+        json!({
+            "type": "user_change",
+            "user": { "id": OTHER_USER_ID, "profile": { "display_name_normalized": USER_NAME } },
+        }),
+        mention_from(OTHER_USER_ID, &format!("update {}", LOCAL_HOST_NAME)),
+    ]);
+    let command_handler = run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert_eq!(
+        command_handler.bound_login(USER_ID).as_deref(),
+        Some(USER_NAME)
+    );
+    assert_eq!(mock.calls(&format!("keys/{}", USER_NAME)).len(), 1);
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [
+            format!(
+                "<@{}> creating account is succeeded.{}",
+                USER_ID,
+                public_key_report(&public_key_source(&mock))
+            ),
+            format!(
+                "<@{}> login name `alice` is bound to another Slack user",
+                OTHER_USER_ID
+            ),
+        ]
+    );
+}
+
+#[test]
+fn rtm_admin_maps_and_shows_logins() {
+    let backend = MemoryBackend::default();
+    backend
+        .create(OTHER_USER_NAME, DEFAULT_SHELL, PUBLIC_KEY)
+        .unwrap();
+    let mock = MockSlack::start(vec![
+        mention(&format!(
+            "map <@{}> bobby {}",
+            OTHER_USER_ID, LOCAL_HOST_NAME
+        )),
+        mention(&format!("map <@{}> bobby {}", USER_ID, LOCAL_HOST_NAME)),
+        mention(&format!("mapping {}", LOCAL_HOST_NAME)),
+        mention(&format!("unmap <@{}> {}", OTHER_USER_ID, LOCAL_HOST_NAME)),
+        mention(&format!("mapping <@{}> {}", OTHER_USER_ID, LOCAL_HOST_NAME)),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    set_roles(
+        &mut command_handler,
+        json!({ "admin": { "users": [USER_ID] } }),
+    );
+    receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [
+            format!("<@{0}> <@{1}> is bound to `bobby`.", USER_ID, OTHER_USER_ID),
+            format!(
+                "<@{}> login name `bobby` is bound to another Slack user",
+                USER_ID
+            ),
+            format!(
+                "<@{0}> mappings on host1:\n<@{1}> → `bobby`",
+                USER_ID, OTHER_USER_ID
+            ),
+            format!("<@{0}> <@{1}> is unbound.", USER_ID, OTHER_USER_ID),
+            format!("<@{}> no mappings on host1.", USER_ID),
+        ]
+    );
+}
+
+#[test]
+fn user_names_are_validated() {
+    assert!(user_name::validate("alice").is_ok());
+    assert!(user_name::validate("a-1_b").is_ok());
+    for (name, text) in [
+        (
+            "Alice Smith",
+            "login name `Alice Smith` must start with a lowercase letter and consist of lowercase letters, digits, `-` and `_` (e.g. `alice_smith`)",
+        ),
+        (
+            "-rf",
+            "login name `-rf` must start with a lowercase letter and consist of lowercase letters, digits, `-` and `_` (e.g. `rf`)",
+        ),
+        (
+            "太郎",
+            "login name `太郎` must start with a lowercase letter and consist of lowercase letters, digits, `-` and `_`",
+        ),
+        ("root", "login name `root` is reserved for the system"),
+        (
+            "abcdefghijklmnopqrstuvwxyz0123456",
+            "login name `abcdefghijklmnopqrstuvwxyz0123456` is longer than 32 characters",
+        ),
+    ] {
+        assert_eq!(user_name::validate(name).unwrap_err().to_string(), text);
+    }
+}
+
+#[test]
+fn rtm_create_with_invalid_display_name_is_rejected() {
+    let backend = MemoryBackend::default();
+    let mock = MockSlack::start(vec![
+        json!({
+            "type": "user_change",
+            "user": { "id": USER_ID, "profile": { "display_name_normalized": "Alice" } },
+        }),
+        mention(&format!("create {}", LOCAL_HOST_NAME)),
+    ]);
+    run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert!(backend.accounts.borrow().is_empty());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [format!(
+            "<@{}> login name `Alice` must start with a lowercase letter and consist of lowercase letters, digits, `-` and `_` (e.g. `alice`)",
+            USER_ID
+        )]
+    );
+    assert_eq!(mock.calls("reactions.add")[0]["name"], "x");
+}
+
+#[test]
+fn rtm_keys_are_fetched_with_key_owner_from_profile() {
+    let backend = MemoryBackend::default();
+    let mock = MockSlack::start(vec![
+        mention(&format!("create {}", LOCAL_HOST_NAME)),
+        mention_from(OTHER_USER_ID, &format!("create {}", LOCAL_HOST_NAME)),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.key_owner = KeyOwner::from_settings(
+        &json!({ "profile_field": "GitHub" }),
+        &command_handler.slack,
+    )
+    .unwrap();
+    receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(mock.calls(&format!("keys/{}", USER_GITHUB_NAME)).len(), 1);
+    assert!(mock.calls(&format!("keys/{}", USER_NAME)).is_empty());
+    // アカウント名は表示名のまま
+    assert!(backend.query(USER_NAME).unwrap().is_some());
+    assert!(backend.query(OTHER_USER_NAME).unwrap().is_none());
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1..],
+        [
+            format!(
+                "<@{}> creating account is succeeded.{}",
+                USER_ID,
+                public_key_report(&mock.public_key_uri_format().replace("{}", USER_GITHUB_NAME))
+            ),
+            format!(
+                "<@{0}> the GitHub profile field of <@{0}> to fetch public keys is empty",
+                OTHER_USER_ID
+            ),
+        ]
+    );
+
+    let mock = MockSlack::start(Vec::new());
+    let key_owner = KeyOwner::from_settings(&json!("email"), &mock.client()).unwrap();
+    assert_eq!(
+        key_owner.name(&mock.client(), USER_ID, USER_NAME).unwrap(),
+        "alice.smith"
+    );
+    match KeyOwner::from_settings(&json!("nickname"), &mock.client()) {
+        Err(e) => assert_eq!(e.to_string(), "unknown key_owner `nickname`"),
+        Ok(_) => panic!("unknown key_owner is accepted"),
+    }
+}
+
+#[test]
+fn store_keeps_value_when_write_fails() {
+    let directory = state_directory();
+    let mut store: Store<HashMap<String, String>> = Store::open(&directory, LOGINS_FILE).unwrap();
+    store
+        .update(|x| x.insert(USER_ID.to_string(), USER_NAME.to_string()))
+        .unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(store
+        .update(|x| x.insert(OTHER_USER_ID.to_string(), OTHER_USER_NAME.to_string()))
+        .is_err());
+    assert_eq!(store.get().len(), 1);
+    assert_eq!(store.get()[USER_ID], USER_NAME);
+}
+
+#[test]
+fn key_mode_is_read_from_settings() {
+    assert_eq!(
+        KeyMode::from_settings(&json!(null)).unwrap(),
+        KeyMode::Replace
+    );
+    assert_eq!(
+        KeyMode::from_settings(&json!("merge")).unwrap(),
+        KeyMode::Merge
+    );
+    assert_eq!(
+        KeyMode::from_settings(&json!("append"))
+            .unwrap_err()
+            .to_string(),
+        "unknown key_mode `append`"
+    );
+}
+
+#[test]
+fn connection_mode_is_read_from_settings() {
+    assert!(matches!(
+        ConnectionMode::from_settings(&json!({})),
+        Ok(ConnectionMode::WebSocket(WebSocketMode::Rtm))
+    ));
+    assert!(matches!(
+        ConnectionMode::from_settings(&json!({
+            "connection_mode": "socket_mode",
+            "SLACK_APP_TOKEN": "xapp-token",
+        })),
+        Ok(ConnectionMode::WebSocket(WebSocketMode::SocketMode { app_token })) if app_token == "xapp-token"
+    ));
+    for (settings, message) in [
+        (
+            json!({ "connection_mode": "socket_mode" }),
+            "SLACK_APP_TOKEN is required for connection_mode `socket_mode`",
+        ),
+        (
+            json!({ "connection_mode": "events_api" }),
+            "SLACK_SIGNING_SECRET is required for connection_mode `events_api`",
+        ),
+        (
+            json!({ "connection_mode": "webhook" }),
+            "unknown connection_mode `webhook`",
+        ),
+    ]
+    .iter()
+    {
+        match ConnectionMode::from_settings(settings) {
+            Err(e) => assert_eq!(e.to_string(), *message),
+            Ok(_) => panic!("invalid connection_mode is accepted"),
+        }
+    }
+}
+
+#[test]
+fn only_first_host_responds_on_shared_rtm_bot() {
+    let hosts = [LOCAL_HOST_NAME.to_string(), OTHER_HOST_NAME.to_string()];
+    let rtm = ConnectionMode::WebSocket(WebSocketMode::Rtm);
+    assert!(rtm.pic_of_response(&hosts, LOCAL_HOST_NAME));
+    assert!(!rtm.pic_of_response(&hosts, OTHER_HOST_NAME));
+    // ホストごとのSlackアプリには、そのbotに宛てたメッセージだけが届く
+    let socket_mode = ConnectionMode::WebSocket(WebSocketMode::SocketMode {
+        app_token: "xapp-mock".to_string(),
+    });
+    assert!(socket_mode.pic_of_response(&hosts, OTHER_HOST_NAME));
+    let events_api = ConnectionMode::EventsApi {
+        listen_address: "127.0.0.1:0".to_string(),
+        signing_secret: SIGNING_SECRET.to_string(),
+    };
+    assert!(events_api.pic_of_response(&hosts, OTHER_HOST_NAME));
+}
+
+#[test]
+fn rtm_update_in_merge_mode_keeps_user_added_keys() {
+    const LAPTOP_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaptopKey alice@laptop";
+    let backend = MemoryBackend::default();
+    backend
+        .create(USER_NAME, DEFAULT_SHELL, &format!("{}\n", LAPTOP_KEY))
+        .unwrap();
+    let mock = MockSlack::start(vec![
+        mention(&format!("keymode merge {}", LOCAL_HOST_NAME)),
+        mention(&format!("update {}", LOCAL_HOST_NAME)),
+        mention(&format!("update {}", LOCAL_HOST_NAME)),
+    ]);
+    let handler = run(&mock, WebSocketMode::Rtm, Box::new(backend.clone()));
+    assert_eq!(handler.key_modes.borrow().get()[USER_NAME], KeyMode::Merge);
+    // 2回更新しても管理する範囲は1つのまま
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        format!(
+            "{}\n# BEGIN sacana managed keys\n{}# END sacana managed keys\n",
+            LAPTOP_KEY,
+            installed_public_key(&public_key_source(&mock))
+        )
+    );
+    assert_eq!(
+        texts(&mock.calls("chat.postMessage"))[1],
+        format!(
+            "<@{}> `update` will merge your authorized_keys from now on.",
+            USER_ID
+        )
+    );
+
+    // replace に戻すとユーザーが追加した行は削除される
+    let mock = MockSlack::start(vec![
+        mention(&format!("keymode replace {}", LOCAL_HOST_NAME)),
+        mention(&format!("update {}", LOCAL_HOST_NAME)),
+    ]);
+    let mut command_handler =
+        command_handler(&mock, &WebSocketMode::Rtm, Box::new(backend.clone()));
+    command_handler.default_key_mode = KeyMode::Merge;
+    receive(WebSocketMode::Rtm, command_handler);
+    assert_eq!(
+        backend.accounts.borrow()[USER_NAME].1,
+        format!(
+            "# BEGIN sacana managed keys\n{}# END sacana managed keys\n",
+            installed_public_key(&public_key_source(&mock))
+        )
+    );
+}
@@ -0,0 +1,98 @@
+use crate::runtime_error::Result;
+
+#[derive(thiserror::Error, Debug)]
+pub enum UserNameError {
+    #[error("login name `{0}` is longer than {MAX_LENGTH} characters")]
+    TooLong(String),
+    #[error("login name `{0}` must start with a lowercase letter and consist of lowercase letters, digits, `-` and `_`{}", hint(.1))]
+    Invalid(String, Option<String>),
+    #[error("login name `{0}` is reserved for the system")]
+    Reserved(String),
+}
+
+/// useradd が受け付けるアカウント名の最大の長さ
+const MAX_LENGTH: usize = 32;
+
+/// 一般ユーザーのアカウント名として使えない名前
+/// (/etc/passwd に無い場合も、後からパッケージが作成しうるものや管理者を連想させるもの)
+const RESERVED_NAMES: &[&str] = &[
+    "root",
+    "daemon",
+    "bin",
+    "sys",
+    "sync",
+    "games",
+    "man",
+    "lp",
+    "mail",
+    "news",
+    "uucp",
+    "proxy",
+    "www-data",
+    "backup",
+    "list",
+    "irc",
+    "gnats",
+    "nobody",
+    "nogroup",
+    "sshd",
+    "messagebus",
+    "syslog",
+    "adm",
+    "admin",
+    "sudo",
+    "wheel",
+    "staff",
+    "operator",
+    "halt",
+    "shutdown",
+];
+
+/// name がアカウント名の規則(Debian の既定の NAME_REGEX 相当)を満たすかどうか
+fn is_valid(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|x| x.is_ascii_lowercase())
+        && chars.all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-' || x == '_')
+}
+
+/// name を規則を満たすように変換した候補(候補が作れない場合は None)
+fn sanitize(name: &str) -> Option<String> {
+    let mut sanitized = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|x| {
+            if x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-' {
+                x
+            } else {
+                '_'
+            }
+        })
+        .skip_while(|x| !x.is_ascii_lowercase())
+        .take(MAX_LENGTH)
+        .collect::<String>();
+    while sanitized.ends_with('_') {
+        sanitized.pop();
+    }
+    Some(sanitized).filter(|x| is_valid(x) && !RESERVED_NAMES.contains(&x.as_str()))
+}
+
+/// エラーメッセージに付け加える候補
+fn hint(suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(x) => format!(" (e.g. `{}`)", x),
+        None => String::new(),
+    }
+}
+
+/// name が一般ユーザーのアカウント名として使えるか確認する
+pub fn validate(name: &str) -> Result<()> {
+    if name.chars().count() > MAX_LENGTH {
+        Err(UserNameError::TooLong(name.to_string()).into())
+    } else if !is_valid(name) {
+        Err(UserNameError::Invalid(name.to_string(), sanitize(name)).into())
+    } else if RESERVED_NAMES.contains(&name) {
+        Err(UserNameError::Reserved(name.to_string()).into())
+    } else {
+        Ok(())
+    }
+}